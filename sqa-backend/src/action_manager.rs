@@ -1,5 +1,5 @@
 //! Managing actions.
-use actions::{Action};
+use actions::{Action, ActionMetadata};
 use std::collections::{HashMap, HashSet};
 use uuid::Uuid;
 use state::{Context, CD};
@@ -29,7 +29,23 @@ pub struct ActionManager {
     async_actions: HashSet<Uuid>,
     changed: HashSet<Uuid>,
     order: Vec<Uuid>,
-    order_changed: bool
+    order_changed: bool,
+    /// The action that is "standing by", i.e. will be executed on the next GO.
+    playhead: Option<Uuid>,
+    playhead_changed: bool,
+    /// Which action has which cue number.
+    numbers: HashMap<String, Uuid>
+}
+/// The largest number an `f64` can count up to exactly (2^53).
+const MAX_EXACT_NUMBER: f64 = 9007199254740992.0;
+/// Tidy up a cue number: surrounding whitespace is removed, and an empty number means no number.
+fn normalise_number(num: Option<String>) -> Option<String> {
+    num.map(|n| n.trim().to_string()).and_then(|n| if n == "" { None } else { Some(n) })
+}
+/// Format a cue number produced by renumbering, without any trailing zeroes.
+fn format_number(num: f64) -> String {
+    let ret = format!("{:.3}", num);
+    ret.trim_end_matches('0').trim_end_matches('.').to_string()
 }
 impl ActionManager {
     pub fn new() -> Self {
@@ -55,28 +71,132 @@ impl ActionManager {
             }
         }
         self.order = order;
+        let first = self.order.get(0).cloned();
+        self.set_playhead_unchecked(first);
         debug!("order restored");
         Ok(())
     }
     pub fn action_list(&self) -> Vec<Uuid> {
         self.actions.iter().map(|(x, _)| x.clone()).collect::<Vec<_>>()
     }
+    /// Find the action with the given cue number.
+    pub fn by_number(&self, number: &str) -> Option<Uuid> {
+        self.numbers.get(number.trim()).cloned()
+    }
+    /// Tidy up the cue number in `meta`, and check that the action `uuid` can have it without
+    /// clashing with any other action.
+    pub fn check_meta(&self, uuid: Uuid, meta: &mut ActionMetadata) -> BackendResult<()> {
+        meta.number = normalise_number(meta.number.take());
+        if let Some(ref num) = meta.number {
+            match self.numbers.get(num) {
+                Some(&uu) if uu != uuid => bail!("Cue number {} is already used by action {}", num, uu),
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+    /// Change an action's metadata, keeping the cue number index up to date.
+    pub fn set_meta(&mut self, uuid: Uuid, mut meta: ActionMetadata) -> BackendResult<()> {
+        self.check_meta(uuid, &mut meta)?;
+        let act = self.actions.get_mut(&uuid).ok_or("No action found")?;
+        if let Some(ref old) = act.meta().number {
+            self.numbers.remove(old);
+        }
+        if let Some(ref new) = meta.number {
+            self.numbers.insert(new.clone(), uuid);
+        }
+        act.set_meta(meta);
+        self.changed.insert(uuid);
+        Ok(())
+    }
+    /// Work out what the cue number index would look like after `set_numbers(numbers)`,
+    /// failing if any of the actions don't exist or the new numbers would clash.
+    fn numbers_after(&self, numbers: &[(Uuid, Option<String>)]) -> BackendResult<HashMap<String, Uuid>> {
+        let mut new = self.numbers.iter()
+            .map(|(num, &uu)| (uu, num.clone()))
+            .collect::<HashMap<_, _>>();
+        for &(uu, ref num) in numbers {
+            if self.actions.get(&uu).is_none() {
+                bail!("No action found with UUID {}", uu);
+            }
+            match normalise_number(num.clone()) {
+                Some(num) => new.insert(uu, num),
+                None => new.remove(&uu)
+            };
+        }
+        let mut index = HashMap::new();
+        for (uu, num) in new {
+            if let Some(other) = index.insert(num.clone(), uu) {
+                bail!("Cue number {} would be used by both action {} and action {}", num, other, uu);
+            }
+        }
+        Ok(index)
+    }
+    /// Check that `set_numbers(numbers)` would succeed, without changing anything.
+    pub fn check_numbers(&self, numbers: &[(Uuid, Option<String>)]) -> BackendResult<()> {
+        self.numbers_after(numbers).map(|_| ())
+    }
+    /// Change the cue numbers of several actions at once.
+    ///
+    /// If any of the actions don't exist, or the new numbers would clash, nothing is changed.
+    pub fn set_numbers(&mut self, numbers: Vec<(Uuid, Option<String>)>) -> BackendResult<()> {
+        let index = self.numbers_after(&numbers)?;
+        for (uu, num) in numbers {
+            let mut meta = self.actions[&uu].meta().clone();
+            meta.number = normalise_number(num);
+            self.actions.get_mut(&uu).unwrap().set_meta(meta);
+            self.mark_changed(uu);
+        }
+        self.numbers = index;
+        Ok(())
+    }
+    /// The cue numbers every action would have if the list was renumbered, in order, starting at
+    /// `start` and going up by `step` each time.
+    pub fn renumbered(&self, start: f64, step: f64) -> Vec<(Uuid, Option<String>)> {
+        self.order.iter()
+            .enumerate()
+            .map(|(i, &uu)| (uu, Some(format_number(start + step * (i as f64)))))
+            .collect()
+    }
+    /// Suggest a cue number for a new action: the whole number after the highest numeric cue
+    /// number in use (or 1, if there aren't any).
+    ///
+    /// Numbers too big to count up from exactly (like "1e20" or "inf") are ignored.
+    pub fn next_number(&self) -> String {
+        let max = self.numbers.keys()
+            .filter_map(|num| num.parse::<f64>().ok())
+            .filter(|&num| num.is_finite() && num < MAX_EXACT_NUMBER)
+            .fold(0.0, f64::max);
+        format!("{}", max.floor() as u64 + 1)
+    }
     pub fn insert(&mut self, uuid: Uuid, act: Action) {
         debug!("creating new action {}", uuid);
         assert!(self.actions.get(&uuid).is_none());
         assert!(self.position_of(uuid).is_none());
+        if let Some(ref num) = act.meta().number {
+            self.numbers.insert(num.clone(), uuid);
+        }
         self.actions.insert(uuid, act);
         self.order.push(uuid);
         self.order_changed = true;
+        if self.playhead.is_none() {
+            self.set_playhead_unchecked(Some(uuid));
+        }
     }
     pub fn insert_with_order(&mut self, uuid: Uuid, act: Action, mut order: usize) {
         debug!("creating new action {} with order {}", uuid, order);
         if order > self.order.len() {
             order = self.order.len();
         }
+        if let Some(ref num) = act.meta().number {
+            self.numbers.insert(num.clone(), uuid);
+        }
         self.actions.insert(uuid, act);
         self.order.insert(order, uuid);
         self.order_changed = true;
+        if self.playhead.is_none() {
+            self.set_playhead_unchecked(Some(uuid));
+        }
     }
     pub fn reorder(&mut self, uuid: Uuid, new_pos: usize) -> BackendResult<()> {
         let pos = self.position_of(uuid).ok_or("UUID not present in order")?;
@@ -94,7 +214,13 @@ impl ActionManager {
         if let Some(pos) = self.position_of(uuid) {
             self.order.remove(pos);
             self.order_changed = true;
+            if self.playhead == Some(uuid) {
+                /* Whatever was after the removed action is now standing by. */
+                let next = self.order.get(pos).cloned();
+                self.set_playhead_unchecked(next);
+            }
         }
+        self.numbers.retain(|_, uu| *uu != uuid);
         self.actions.remove(&uuid)
     }
     pub fn remove_for_editing(&mut self, uuid: Uuid, mark_changed: bool) -> Option<Action> {
@@ -119,6 +245,50 @@ impl ActionManager {
         }
         mem::replace(&mut self.order_changed, false)
     }
+    pub fn playhead(&self) -> Option<Uuid> {
+        self.playhead
+    }
+    /// Put the given action on standby (or nothing, if `None`).
+    pub fn set_playhead(&mut self, uuid: Option<Uuid>) -> BackendResult<()> {
+        if let Some(uu) = uuid {
+            if self.position_of(uu).is_none() {
+                bail!("UUID {} is not present in the order", uu);
+            }
+        }
+        self.set_playhead_unchecked(uuid);
+        Ok(())
+    }
+    fn set_playhead_unchecked(&mut self, uuid: Option<Uuid>) {
+        if self.playhead != uuid {
+            trace!("playhead moved to {:?}", uuid);
+            self.playhead = uuid;
+            self.playhead_changed = true;
+        }
+    }
+    /// Move the playhead to the next action in the order.
+    ///
+    /// Moving past the end of the order leaves nothing standing by.
+    pub fn playhead_next(&mut self) {
+        let next = match self.playhead.and_then(|uu| self.position_of(uu)) {
+            Some(pos) => self.order.get(pos + 1).cloned(),
+            None => None
+        };
+        self.set_playhead_unchecked(next);
+    }
+    /// Move the playhead to the previous action in the order.
+    ///
+    /// If nothing is standing by (i.e. we've run off the end), this goes to the last action.
+    pub fn playhead_previous(&mut self) {
+        let prev = match self.playhead.and_then(|uu| self.position_of(uu)) {
+            Some(0) => self.order.get(0).cloned(),
+            Some(pos) => self.order.get(pos - 1).cloned(),
+            None => self.order.last().cloned()
+        };
+        self.set_playhead_unchecked(prev);
+    }
+    pub fn clear_playhead_changed(&mut self) -> bool {
+        mem::replace(&mut self.playhead_changed, false)
+    }
     pub fn mark_changed(&mut self, uu: Uuid) {
         trace!("UUID {} was changed", uu);
         self.changed.insert(uu);
@@ -153,3 +323,84 @@ impl ActionManager {
         }
     }
 }
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Make a manager with an action for each of `numbers`, in order, returning their UUIDs.
+    fn manager(numbers: &[Option<&str>]) -> (ActionManager, Vec<Uuid>) {
+        let mut ret = ActionManager::new();
+        let uus = numbers.iter()
+            .map(|num| {
+                let uu = Uuid::new_v4();
+                let mut act = Action::wait();
+                act.set_meta(ActionMetadata { number: num.map(|n| n.to_string()), .. Default::default() });
+                ret.insert(uu, act);
+                uu
+            })
+            .collect();
+        (ret, uus)
+    }
+    fn number(am: &ActionManager, uu: Uuid) -> Option<String> {
+        am.get(&uu).unwrap().meta().number.clone()
+    }
+    #[test]
+    fn format_numbers() {
+        assert_eq!(format_number(1.0), "1");
+        assert_eq!(format_number(10.0), "10");
+        assert_eq!(format_number(1.5), "1.5");
+        assert_eq!(format_number(2.125), "2.125");
+        assert_eq!(format_number(100.25), "100.25");
+        assert_eq!(format_number(0.1 + 0.2), "0.3");
+    }
+    #[test]
+    fn renumbering() {
+        let (am, uus) = manager(&[Some("5"), None, Some("a")]);
+        assert_eq!(am.renumbered(1.0, 1.0), vec![
+            (uus[0], Some("1".into())),
+            (uus[1], Some("2".into())),
+            (uus[2], Some("3".into()))
+        ]);
+        assert_eq!(am.renumbered(10.0, 0.5), vec![
+            (uus[0], Some("10".into())),
+            (uus[1], Some("10.5".into())),
+            (uus[2], Some("11".into()))
+        ]);
+        /* It only works out the numbers; nothing's actually changed. */
+        assert_eq!(number(&am, uus[0]), Some("5".into()));
+        assert_eq!(number(&am, uus[1]), None);
+    }
+    #[test]
+    fn setting_numbers() {
+        let (mut am, uus) = manager(&[Some("1"), Some("2"), None]);
+        /* Swapping numbers is fine, since they're all changed at once. */
+        am.set_numbers(vec![(uus[0], Some("2".into())), (uus[1], Some(" 1 ".into()))]).unwrap();
+        assert_eq!(number(&am, uus[0]), Some("2".into()));
+        assert_eq!(number(&am, uus[1]), Some("1".into()));
+        assert_eq!(am.by_number("1"), Some(uus[1]));
+        /* Clashing with an action that isn't being renumbered is rejected, and changes nothing. */
+        let clash = vec![(uus[0], Some("3".into())), (uus[2], Some("1".into()))];
+        assert!(am.check_numbers(&clash).is_err());
+        assert!(am.set_numbers(clash).is_err());
+        assert_eq!(number(&am, uus[0]), Some("2".into()));
+        assert_eq!(number(&am, uus[2]), None);
+        assert_eq!(am.by_number("3"), None);
+        /* As is giving two actions the same number, or renumbering one that doesn't exist. */
+        assert!(am.set_numbers(vec![(uus[0], Some("4".into())), (uus[2], Some("4".into()))]).is_err());
+        assert!(am.set_numbers(vec![(Uuid::new_v4(), Some("5".into()))]).is_err());
+        assert_eq!(am.by_number("4"), None);
+        /* Renumbering with too small a step gives everything the same number. */
+        assert!(am.check_numbers(&am.renumbered(1.0, 0.0001)).is_err());
+        assert!(am.check_numbers(&am.renumbered(1.0, 0.5)).is_ok());
+        /* An empty number removes it. */
+        am.set_numbers(vec![(uus[0], Some("  ".into()))]).unwrap();
+        assert_eq!(number(&am, uus[0]), None);
+        assert_eq!(am.by_number("2"), None);
+    }
+    #[test]
+    fn next_numbers() {
+        assert_eq!(manager(&[]).0.next_number(), "1");
+        assert_eq!(manager(&[Some("3"), Some("7.5"), Some("a")]).0.next_number(), "8");
+        assert_eq!(manager(&[Some("2"), Some("inf"), Some("NaN"), Some("1e20"), Some("-5")]).0.next_number(), "3");
+    }
+}
@@ -1,13 +1,15 @@
 //! Plays back an audio file.
 
 use sqa_engine::{PlainSender, BufferSender};
-use sqa_engine::param::Parameter;
+use sqa_engine::param::{Parameter, FadeDetails, FadeCurve};
 use sqa_engine::sync::AudioThreadMessage;
 use sqa_ffmpeg::{Frame, MediaFile, MediaResult};
 use sqa_ffmpeg::errors::ErrorKind;
-use super::{ParameterError, ControllerParams, DurationInfoInt, PlaybackState, ActionController, EditableAction};
+use super::{ParameterError, ControllerParams, DurationInfoInt, DurationInfo, PlaybackState, ActionController, EditableAction, AsyncResult};
+use futures::Future;
 use state::{ServerMessage, Context, IntSender};
 use std::thread;
+use std::sync::Arc;
 use std::ops::Deref;
 use errors::*;
 use std::sync::mpsc::{Sender, Receiver, self};
@@ -25,6 +27,14 @@ pub fn lin_db(lin: f32) -> f32 {
 pub fn db_lin(db: f32) -> f32 {
     10.0_f32.powf(db / 20.0)
 }
+#[derive(Serialize, Deserialize)]
+#[serde(remote = "FadeCurve")]
+enum FadeCurveDef {
+    Linear,
+    Exponential,
+    Logarithmic,
+    SCurve
+}
 pub enum SpoolerMessage {
     Wakeup,
     Quit
@@ -122,7 +132,13 @@ pub struct Controller {
     pub params: AudioParams,
     pub rd: Option<RunningData>,
     file: Option<MediaResult<MediaFile>>,
-    url: Option<BackendResult<PathBuf>>
+    url: Option<BackendResult<PathBuf>>,
+    timeout: AsyncResult<(), ::std::io::Error>,
+    /// If we're stopping with a fade, when the fade will be done.
+    stop_at: Option<u64>,
+    /// The ID given to the fades `apply_envelope` makes, so we can tell them apart from
+    /// anyone else's.
+    envelope_id: Arc<()>
 }
 #[derive(Serialize, Deserialize, Clone, Debug, Default)]
 pub struct AudioChannel {
@@ -134,7 +150,19 @@ pub struct AudioParams {
     pub url: Option<String>,
     pub chans: Vec<AudioChannel>,
     pub master_vol: f32,
-    pub waveform_uuid: Option<Uuid>
+    pub waveform_uuid: Option<Uuid>,
+    /// How long to fade in for when starting. Zero means no fade in.
+    #[serde(default)]
+    pub fade_in: Duration,
+    /// How long to fade out for before the end of the file. Zero means no fade out.
+    ///
+    /// This is also how long stopping with a fade takes.
+    #[serde(default)]
+    pub fade_out: Duration,
+    /// The shape of the fades. Fade outs use its mirror image, so they sound like fade ins
+    /// in reverse.
+    #[serde(default, with = "FadeCurveDef")]
+    pub fade_curve: FadeCurve
 }
 impl Controller {
     pub fn new() -> Self {
@@ -158,6 +186,72 @@ impl Controller {
         let mf = MediaFile::new(&mut ctx.media, &uri)?;
         Ok(mf)
     }
+    fn has_envelope(&self) -> bool {
+        self.params.fade_in != Duration::new(0, 0) || self.params.fade_out != Duration::new(0, 0)
+    }
+    /// Whether the master volume is still doing what `apply_envelope` last told it to, rather
+    /// than something else (a fade action, or stopping everything) having taken it over.
+    fn envelope_owns_master(&self) -> bool {
+        let rd = match self.rd {
+            Some(ref rd) => rd,
+            None => return false
+        };
+        match rd.senders[0].master_volume() {
+            Parameter::LinearFade(ref fd) | Parameter::CurvedFade(ref fd, _) => {
+                Arc::ptr_eq(fd.id_ptr(), &self.envelope_id)
+            },
+            _ => false
+        }
+    }
+    fn schedule(&mut self, nanos: u64, ctx: &mut ControllerParams) -> BackendResult<()> {
+        self.timeout = ctx.timeout_after(DurationInfo::nanos_to_dur(nanos))?;
+        Ok(())
+    }
+    /// Make the master volume follow the fade in and fade out, given how far through the
+    /// file we are.
+    ///
+    /// Whilst fading in, a wakeup is scheduled for the end of the fade in, so that the fade
+    /// out can be set up then.
+    fn apply_envelope(&mut self, ctx: &mut ControllerParams) -> BackendResult<()> {
+        let now = ::sqa_engine::Sender::<()>::precise_time_ns();
+        let master = db_lin(self.params.master_vol);
+        let fade_in = DurationInfo::dur_to_nanos(self.params.fade_in);
+        let fade_out = DurationInfo::dur_to_nanos(self.params.fade_out);
+        let curve = self.params.fade_curve;
+        self.timeout = AsyncResult::Empty;
+        let wakeup = {
+            let rd = match self.rd {
+                Some(ref mut rd) => rd,
+                None => return Ok(())
+            };
+            let start = rd.durinfo.start_time;
+            let end = start + rd.durinfo.est_duration.map(DurationInfo::dur_to_nanos).unwrap_or(0);
+            let (param, wakeup) = if fade_in > 0 && now < start + fade_in {
+                let mut fd = FadeDetails::new_with_id(0.0, master, self.envelope_id.clone());
+                fd.set_duration_nanos(fade_in);
+                fd.start_from_time(start);
+                let wakeup = if fade_out > 0 { Some(start + fade_in - now) } else { None };
+                (Parameter::CurvedFade(fd, curve), wakeup)
+            }
+            else if fade_out > 0 {
+                /* If the fades overlap, the fade out starts when the fade in ends. */
+                let from = ::std::cmp::max(start + fade_in, end.saturating_sub(fade_out));
+                let mut fd = FadeDetails::new_with_id(master, 0.0, self.envelope_id.clone());
+                fd.set_duration_nanos(end.saturating_sub(from));
+                fd.start_from_time(from);
+                (Parameter::CurvedFade(fd, curve.mirror()), None)
+            }
+            else {
+                (Parameter::Raw(master), None)
+            };
+            rd.senders[0].set_master_volume(Box::new(param));
+            wakeup
+        };
+        if let Some(nanos) = wakeup {
+            self.schedule(nanos, ctx)?;
+        }
+        Ok(())
+    }
     fn open_file(&mut self, ctx: &mut Context) -> Option<MediaResult<MediaFile>> {
         if let Some(ref uri2) = self.url {
             let uri;
@@ -184,7 +278,7 @@ impl EditableAction for Controller {
     fn get_params(&self) -> &AudioParams {
         &self.params
     }
-    fn set_params(&mut self, mut p: AudioParams, ctx: ControllerParams) {
+    fn set_params(&mut self, mut p: AudioParams, mut ctx: ControllerParams) {
         if self.params.url != p.url {
             trace!("urls differ; remaking files etc");
             p.waveform_uuid = None;
@@ -209,15 +303,29 @@ impl EditableAction for Controller {
                 }
             }
         }
+        /* Only touch the master volume if it's actually been changed, so as not to undo any
+         * fades other actions are doing to it. */
+        let master_changed = self.params.master_vol != p.master_vol
+            || self.params.fade_in != p.fade_in
+            || self.params.fade_out != p.fade_out
+            || self.params.fade_curve != p.fade_curve;
         if let Some(ref mut rd) = self.rd {
             for (i, ch) in p.chans.iter().enumerate() {
                 if let Some(s) = rd.senders.get_mut(i) {
                     s.set_volume(Box::new(Parameter::Raw(db_lin(ch.vol))));
                 }
             }
-            rd.senders[0].set_master_volume(Box::new(Parameter::Raw(db_lin(p.master_vol))));
+            if master_changed {
+                rd.senders[0].set_master_volume(Box::new(Parameter::Raw(db_lin(p.master_vol))));
+            }
         }
         self.params = p;
+        let running = self.rd.as_ref().map(|rd| rd.durinfo.start_time != 0).unwrap_or(false);
+        if master_changed && running && self.stop_at.is_none() && self.has_envelope() {
+            if let Err(e) = self.apply_envelope(&mut ctx) {
+                warn!("failed to reapply fades: {}", e);
+            }
+        }
     }
 }
 impl ActionController for Controller {
@@ -291,7 +399,7 @@ impl ActionController for Controller {
     fn load(&mut self, params: ControllerParams) -> BackendResult<bool> {
         let mf = self.file.take().ok_or("File mysteriously disappeared")??;
         self.file = self.open_file(params.ctx);
-        let mut senders = params.ctx.mixer.new_senders(mf.channels(), mf.sample_rate() as u64);
+        let mut senders = params.ctx.mixer.new_senders(mf.channels(), mf.sample_rate() as u64)?;
         for (i, s) in senders.iter_mut().enumerate() {
             if let Some(ch) = self.params.chans.get(i) {
                 if let Some(ref ch) = ch.patch {
@@ -327,7 +435,8 @@ impl ActionController for Controller {
         });
         Ok(true)
     }
-    fn execute(&mut self, time: u64, _: ControllerParams) -> BackendResult<bool> {
+    fn execute(&mut self, time: u64, mut ctx: ControllerParams) -> BackendResult<bool> {
+        self.stop_at = None;
         if let Some(ref mut rd) = self.rd {
             for (i, sender) in rd.senders.iter_mut().enumerate() {
                 if rd.durinfo.start_time == 0 {
@@ -335,14 +444,40 @@ impl ActionController for Controller {
                         sender.set_volume(Box::new(Parameter::Raw(db_lin(ch.vol))));
                     }
                 }
-                sender.set_start_time(time);
+                let start_time = ctx.ctx.mixer.compensated_start_time(time, sender.output_patch());
+                sender.set_start_time(start_time);
                 sender.set_active(true);
             }
             rd.durinfo.start_time = time;
         }
+        if self.has_envelope() {
+            self.apply_envelope(&mut ctx)?;
+        }
         Ok(false)
     }
+    fn poll(&mut self, mut ctx: ControllerParams) -> bool {
+        let _ = self.timeout.poll();
+        if !self.timeout.is_complete() {
+            return self.timeout.is_waiting();
+        }
+        self.timeout = AsyncResult::Empty;
+        if self.stop_at.take().is_some() {
+            ctx.change_state(PlaybackState::Inactive);
+            return false;
+        }
+        if !self.envelope_owns_master() {
+            /* Someone else is fading us; leave them to it, rather than setting up the fade out. */
+            return false;
+        }
+        if let Err(e) = self.apply_envelope(&mut ctx) {
+            ctx.change_state(PlaybackState::Errored(e.to_string()));
+            return false;
+        }
+        self.timeout.is_waiting()
+    }
     fn pause(&mut self, _: ControllerParams) -> bool {
+        self.timeout = AsyncResult::Empty;
+        self.stop_at = None;
         if let Some(ref mut rd) = self.rd {
             for sender in rd.senders.iter_mut() {
                 sender.set_active(false);
@@ -354,10 +489,34 @@ impl ActionController for Controller {
         }
     }
     fn reset(&mut self, _: ControllerParams) {
+        self.timeout = AsyncResult::Empty;
+        self.stop_at = None;
         if let Some(rd) = self.rd.take() {
             let _ = rd.control.send(SpoolerMessage::Quit);
         }
     }
+    fn stop_with_fade(&mut self, dur: Option<Duration>, mut ctx: ControllerParams) -> bool {
+        let dur = dur.unwrap_or(self.params.fade_out);
+        if dur == Duration::new(0, 0) {
+            return false;
+        }
+        let now = ::sqa_engine::Sender::<()>::precise_time_ns();
+        match self.rd {
+            Some(ref mut rd) if rd.durinfo.start_time != 0 => {
+                /* Fade down from wherever we are now, even if that's halfway through a fade. */
+                let vol = rd.senders[0].master_volume().get(now);
+                let mut fd = FadeDetails::new(vol, 0.0);
+                fd.set_duration(dur);
+                fd.start_from_time(now);
+                let curve = self.params.fade_curve.mirror();
+                rd.senders[0].set_master_volume(Box::new(Parameter::CurvedFade(fd, curve)));
+            },
+            _ => return false
+        }
+        let nanos = DurationInfo::dur_to_nanos(dur);
+        self.stop_at = Some(now + nanos);
+        self.schedule(nanos, &mut ctx).is_ok()
+    }
     fn duration_info(&self) -> Option<DurationInfoInt> {
         if let Some(ref rd) = self.rd {
             let mut ret = rd.durinfo;
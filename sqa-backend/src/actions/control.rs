@@ -0,0 +1,188 @@
+//! Controlling the playback of other actions.
+
+use super::{ActionController, EditableAction, AsyncResult, PlaybackState, ControllerParams, ParameterError, DurationInfoInt, DurationInfo};
+use state::Context;
+use errors::BackendResult;
+use uuid::Uuid;
+use futures::Future;
+use sqa_engine::Sender;
+use std::time::Duration;
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ControlKind {
+    /// Stop the targets, if they're running.
+    Stop,
+    /// Pause the targets, if they're running.
+    Pause,
+    /// Load the targets, if they're inactive.
+    Load,
+    /// Start (execute) the targets.
+    Start,
+    /// Reset the targets, whatever state they're in.
+    Reset
+}
+impl Default for ControlKind {
+    fn default() -> Self {
+        ControlKind::Stop
+    }
+}
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct ControlParams {
+    pub kind: ControlKind,
+    pub target: Option<Uuid>,
+    #[serde(default)]
+    pub targets: Vec<Uuid>
+}
+impl ControlParams {
+    /// All the actions this action will control.
+    pub fn all_targets(&self) -> Vec<Uuid> {
+        self.target.iter().chain(self.targets.iter()).cloned().collect()
+    }
+}
+#[derive(Default)]
+pub struct Controller {
+    params: ControlParams,
+    /// Fires when it's time to control the targets (i.e. once any prewait is over).
+    timeout: AsyncResult<(), ::std::io::Error>,
+    /// The time we were executed for.
+    time: u64
+}
+impl Controller {
+    pub fn new() -> Self {
+        Default::default()
+    }
+    /// Do whatever we do to the targets.
+    fn control(&self, ctx: &mut ControllerParams) -> BackendResult<()> {
+        use self::PlaybackState::*;
+        let mut errs = vec![];
+        let time = self.time;
+        for uu in self.params.all_targets() {
+            let kind = self.params.kind;
+            let res = ctx.with_action(uu, |a, ctx, tx| -> BackendResult<()> {
+                let state = a.state.clone();
+                match (kind, &state) {
+                    (ControlKind::Stop, &Active(_)) |
+                    (ControlKind::Stop, &Paused(_)) |
+                    (ControlKind::Stop, &Loading) => a.reset(ctx, tx),
+                    (ControlKind::Stop, _) => {},
+                    (ControlKind::Pause, &Active(_)) => a.pause(ctx, tx),
+                    (ControlKind::Pause, _) => {},
+                    (ControlKind::Load, &Inactive) => a.load(ctx, tx)?,
+                    (ControlKind::Load, _) => {},
+                    (ControlKind::Start, _) => a.execute(time, ctx, tx)?,
+                    (ControlKind::Reset, &Inactive) |
+                    (ControlKind::Reset, &Unverified(_)) => {},
+                    (ControlKind::Reset, _) => a.reset(ctx, tx)
+                }
+                Ok(())
+            });
+            match res {
+                Some(Err(e)) => errs.push(format!("{}: {}", uu, e)),
+                None => errs.push(format!("{}: no such action", uu)),
+                _ => {}
+            }
+        }
+        if errs.len() > 0 {
+            bail!("Failed to control some targets: {}", errs.join("; "));
+        }
+        Ok(())
+    }
+}
+impl EditableAction for Controller {
+    type Parameters = ControlParams;
+    fn get_params(&self) -> &ControlParams {
+        &self.params
+    }
+    fn set_params(&mut self, params: ControlParams, _: ControllerParams) {
+        self.params = params;
+    }
+}
+impl ActionController for Controller {
+    fn desc(&self, ctx: &Context) -> String {
+        let verb = match self.params.kind {
+            ControlKind::Stop => "Stop",
+            ControlKind::Pause => "Pause",
+            ControlKind::Load => "Load",
+            ControlKind::Start => "Start",
+            ControlKind::Reset => "Reset"
+        };
+        let targets = self.params.all_targets();
+        match targets.len() {
+            0 => format!("{} [invalid]", verb),
+            1 => {
+                match ctx.actions.get(&targets[0]) {
+                    Some(tgt) => format!("{} {}", verb, tgt.meta.name.clone().unwrap_or_else(|| tgt.desc(ctx))),
+                    None => format!("{} [invalid]", verb)
+                }
+            },
+            n => format!("{} {} actions", verb, n)
+        }
+    }
+    fn verify_params(&self, ctx: &Context) -> Vec<ParameterError> {
+        let mut ret = vec![];
+        let targets = self.params.all_targets();
+        if targets.len() == 0 {
+            ret.push(ParameterError {
+                name: "target".into(),
+                err: "No target is specified.".into()
+            });
+        }
+        for uu in targets {
+            if ctx.actions.get(&uu).is_none() {
+                ret.push(ParameterError {
+                    name: "target".into(),
+                    err: format!("No action with UUID {} is present.", uu)
+                });
+            }
+        }
+        ret
+    }
+    fn execute(&mut self, time: u64, mut ctx: ControllerParams) -> BackendResult<bool> {
+        self.time = time;
+        let now = Sender::<()>::precise_time_ns();
+        if time <= now {
+            self.control(&mut ctx)?;
+            return Ok(true);
+        }
+        /* Wait until the prewait's over before doing anything to the targets. */
+        self.timeout = ctx.timeout_after(DurationInfo::nanos_to_dur(time - now))?;
+        Ok(false)
+    }
+    fn poll(&mut self, mut ctx: ControllerParams) -> bool {
+        let _ = self.timeout.poll();
+        if self.timeout.is_complete() {
+            self.timeout = AsyncResult::Empty;
+            match self.control(&mut ctx) {
+                Ok(_) => ctx.change_state(PlaybackState::Inactive),
+                Err(e) => ctx.change_state(PlaybackState::Errored(e.to_string()))
+            }
+            false
+        }
+        else {
+            true
+        }
+    }
+    fn pause(&mut self, _: ControllerParams) -> bool {
+        /* All there is to pause is the prewait; resuming waits out whatever's left of it. */
+        if self.timeout.is_waiting() {
+            self.timeout = AsyncResult::Empty;
+            true
+        }
+        else {
+            false
+        }
+    }
+    fn reset(&mut self, _: ControllerParams) {
+        self.timeout = AsyncResult::Empty;
+    }
+    fn duration_info(&self) -> Option<DurationInfoInt> {
+        if !self.timeout.is_waiting() {
+            return None;
+        }
+        Some(DurationInfoInt {
+            duration: Duration::new(0, 0),
+            start_time: self.time,
+            est_duration: None
+        })
+    }
+}
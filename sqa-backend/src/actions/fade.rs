@@ -2,28 +2,44 @@
 
 use sqa_engine::param::{Parameter, FadeDetails};
 use super::{ActionController, EditableAction, AsyncResult, PlaybackState, ActionType, ControllerParams, ParameterError, DurationInfoInt, DurationInfo};
-use async::PerformExt;
 use state::Context;
 use errors::BackendResult;
 use uuid::Uuid;
 use std::time::Duration;
 use std::default::Default;
-use tokio_core::reactor::Timeout;
 use futures::Future;
 use super::audio::{lin_db, db_lin};
 use sqa_engine::Sender;
 use std::sync::Arc;
+/// Parameters for a fade.
+///
+/// The fade applies to `target`, everything in `targets`, and (if `all_active` is set) every
+/// audio or live input action that's active when the fade starts. Group actions are expanded
+/// into their children. Each entry in `fades` applies to the channel of the same index in every
+/// target; channels without an entry are left alone.
 #[derive(Serialize, Deserialize, Clone, Debug, Default)]
 pub struct FadeParams {
     pub target: Option<Uuid>,
+    #[serde(default)]
+    pub targets: Vec<Uuid>,
+    #[serde(default)]
+    pub all_active: bool,
     pub fades: Vec<(bool, f32)>,
     pub fade_master: (bool, f32),
-    pub dur: Duration
+    pub dur: Duration,
+    /// Whether to stop (reset) the target once the fade is complete.
+    #[serde(default)]
+    pub stop_target_when_done: bool,
+    /// If set, the levels in `fades` and `fade_master` are relative to each channel's
+    /// current level (e.g. -6.0 means "6dB quieter than it is now"), rather than absolute.
+    #[serde(default)]
+    pub relative: bool
 }
 struct RunningData {
     params: FadeParams,
     start_time: u64,
-    fd: FadeDetails<f32>
+    /// The actions being faded, and a `FadeDetails` with the ID used for each.
+    targets: Vec<(Uuid, FadeDetails<f32>)>
 }
 #[derive(Default)]
 pub struct Controller {
@@ -41,12 +57,17 @@ impl Controller {
         } else {
             sdr.volume().get(gt)
         };
-        let mut fd = FadeDetails::new_with_id(vol, db_lin(fade), idp.clone());
-        self.rd.as_mut().unwrap().fd = fd.clone();
+        let to = if self.params.relative {
+            db_lin(lin_db(vol) + fade)
+        } else {
+            db_lin(fade)
+        };
+        let mut fd = FadeDetails::new_with_id(vol, to, idp.clone());
+        self.rd.as_mut().unwrap().targets.last_mut().unwrap().1 = fd.clone();
         fd.set_duration(self.params.dur);
         fd.start_from_time(time);
         trace!("applying fade [from {:.02}dB to {:.02}dB] to sender, {:.02}% complete already",
-               lin_db(vol), fade, 100.0 * fd.percentage_complete(Sender::<()>::precise_time_ns()));
+               lin_db(vol), lin_db(to), 100.0 * fd.percentage_complete(Sender::<()>::precise_time_ns()));
         let bx = Box::new(Parameter::LinearFade(fd));
         if master {
             sdr.set_master_volume(bx)
@@ -82,24 +103,82 @@ impl Controller {
     }
     fn freeze_sdrs(&mut self, ctx: ControllerParams) -> BackendResult<()> {
         if let Some(ref rd) = self.rd {
-            let tgt = ctx.ctx.actions.get_mut(rd.params.target.as_ref().unwrap())
-                .ok_or("Failed to get action")?;
-            let tgt = match tgt.ctl {
-                ActionType::Audio(ref mut t) => t,
-                _ => bail!("Action was wrong type")
-            };
-            let tgt = tgt.rd.as_mut()
-                .ok_or("Target isn't running or loaded")?;
-            for (i, sdr) in tgt.senders.iter_mut().enumerate() {
-                trace!("freezing sender #{}", i);
-                if i == 0 {
-                    Self::freeze_sdr(sdr, &rd.fd, rd.start_time, true);
+            for &(ref uu, ref fd) in rd.targets.iter() {
+                let tgt = match ctx.ctx.actions.get_mut(uu) {
+                    Some(t) => t,
+                    None => continue
+                };
+                let senders = match tgt.ctl.senders_mut() {
+                    Some(s) => s,
+                    None => continue
+                };
+                for (i, sdr) in senders.iter_mut().enumerate() {
+                    trace!("freezing sender #{} of {}", i, uu);
+                    if i == 0 {
+                        Self::freeze_sdr(sdr, fd, rd.start_time, true);
+                    }
+                    Self::freeze_sdr(sdr, fd, rd.start_time, false);
                 }
-                Self::freeze_sdr(sdr, &rd.fd, rd.start_time, false);
             }
         }
         Ok(())
     }
+    /// Stop whatever we were fading, if we've been asked to do that when the fade's done.
+    fn stop_targets_if_needed(&self, ctx: &mut ControllerParams) {
+        let targets = match self.rd {
+            Some(ref rd) if self.params.stop_target_when_done => rd.targets.iter().map(|x| x.0).collect(),
+            _ => vec![]
+        };
+        for tgt in targets {
+            trace!("fade complete; stopping target {}", tgt);
+            ctx.with_action(tgt, |a, ctx, tx| {
+                let running = match a.state {
+                    PlaybackState::Active(_) | PlaybackState::Paused(_) => true,
+                    _ => false
+                };
+                if running {
+                    a.reset(ctx, tx);
+                }
+            });
+        }
+    }
+    /// Expand a target into the actions playing audio it refers to (i.e. expand groups).
+    fn resolve_target(ctx: &Context, uu: Uuid, out: &mut Vec<Uuid>, depth: usize) {
+        /* Groups can (pathologically) contain themselves, so give up eventually. */
+        if depth > 16 || out.contains(&uu) {
+            return;
+        }
+        if let Some(act) = ctx.actions.get(&uu) {
+            match act.ctl {
+                ActionType::Audio(_) | ActionType::Live(_) => out.push(uu),
+                ActionType::Group(ref g) => {
+                    for child in g.get_params().children.iter() {
+                        Self::resolve_target(ctx, *child, out, depth + 1);
+                    }
+                },
+                _ => {}
+            }
+        }
+    }
+    /// Get all the actions playing audio this fade would apply to right now.
+    fn resolve_targets(&self, ctx: &Context) -> Vec<Uuid> {
+        let mut ret = vec![];
+        for uu in self.params.target.iter().chain(self.params.targets.iter()) {
+            Self::resolve_target(ctx, *uu, &mut ret, 0);
+        }
+        if self.params.all_active {
+            for uu in ctx.actions.action_list() {
+                if let Some(act) = ctx.actions.get(&uu) {
+                    if let PlaybackState::Active(_) = act.state {
+                        if act.ctl.audio_chans().is_some() && !ret.contains(&uu) {
+                            ret.push(uu);
+                        }
+                    }
+                }
+            }
+        }
+        ret
+    }
 }
 impl EditableAction for Controller {
     type Parameters = FadeParams;
@@ -107,13 +186,17 @@ impl EditableAction for Controller {
         &self.params
     }
     fn set_params(&mut self, mut params: FadeParams, ctx: ControllerParams) {
-        if let Some(tgt) = self.params.target.as_ref() {
-            if let Some(tgt) = ctx.ctx.actions.get(tgt) {
-                if let ActionType::Audio(ref ctl) = tgt.ctl {
-                    if ctl.params.chans.len() > params.fades.len() {
+        let mut targets = vec![];
+        for uu in params.target.iter().chain(params.targets.iter()) {
+            Self::resolve_target(ctx.ctx, *uu, &mut targets, 0);
+        }
+        for tgt in targets {
+            if let Some(tgt) = ctx.ctx.actions.get(&tgt) {
+                if let Some(chans) = tgt.ctl.audio_chans() {
+                    if chans > params.fades.len() {
                         let len = params.fades.len();
                         params.fades.extend(::std::iter::repeat((false, 0.0))
-                                            .take(ctl.params.chans.len() - len));
+                                            .take(chans - len));
                     }
                 }
             }
@@ -123,25 +206,46 @@ impl EditableAction for Controller {
 }
 impl ActionController for Controller {
     fn desc(&self, ctx: &Context) -> String {
-        if let Some(tgt) = self.params.target.as_ref() {
+        let verb = match (self.params.relative, self.params.stop_target_when_done) {
+            (false, false) => "Fade",
+            (false, true) => "Fade & stop",
+            (true, false) => "Relative fade",
+            (true, true) => "Relative fade & stop"
+        };
+        let n_targets = self.params.target.iter().count() + self.params.targets.len();
+        if self.params.all_active {
+            if n_targets == 0 {
+                return format!("{} all active audio", verb);
+            }
+            return format!("{} {} actions & all active audio", verb, n_targets);
+        }
+        if n_targets > 1 {
+            return format!("{} {} actions", verb, n_targets);
+        }
+        if let Some(tgt) = self.params.target.as_ref().or(self.params.targets.get(0)) {
             if let Some(tgt) = ctx.actions.get(tgt) {
-                if let ActionType::Audio(ref ctl) = tgt.ctl {
-                    return format!("Fade {}", tgt.meta.name.as_ref().unwrap_or(&ctl.desc(ctx)));
-                }
+                return format!("{} {}", verb, tgt.meta.name.clone().unwrap_or_else(|| tgt.desc(ctx)));
             }
         }
         format!("Fade [invalid]")
     }
     fn verify_params(&self, ctx: &Context) -> Vec<ParameterError> {
         let mut ret = vec![];
-        if let Some(tgt) = self.params.target.as_ref() {
+        let targets: Vec<&Uuid> = self.params.target.iter().chain(self.params.targets.iter()).collect();
+        if targets.len() == 0 && !self.params.all_active {
+            ret.push(ParameterError {
+                name: "target".into(),
+                err: "No target is specified.".into()
+            });
+        }
+        for tgt in targets {
             if let Some(tgt) = ctx.actions.get(tgt) {
                 match tgt.ctl {
-                    ActionType::Audio(_) => {},
+                    ActionType::Audio(_) | ActionType::Live(_) | ActionType::Group(_) => {},
                     _ => {
                         ret.push(ParameterError {
                             name: "target".into(),
-                            err: "You must target an audio action.".into()
+                            err: "You must target an audio, live input or group action.".into()
                         });
                     }
                 }
@@ -149,16 +253,10 @@ impl ActionController for Controller {
             else {
                 ret.push(ParameterError {
                     name: "target".into(),
-                    err: "No action with that UUID is present.".into()
+                    err: format!("No action with UUID {} is present.", tgt)
                 });
             }
         }
-        else {
-            ret.push(ParameterError {
-                name: "target".into(),
-                err: "No target is specified.".into()
-            });
-        }
         if !self.params.fade_master.0 && self.params.fades.iter().fold(true, |acc, elem|
                                          if elem.0 && !acc { true } else { acc }){
             ret.push(ParameterError {
@@ -171,6 +269,7 @@ impl ActionController for Controller {
     fn poll(&mut self, mut ctx: ControllerParams) -> bool {
         let _ = self.timeout.poll();
         if self.timeout.is_complete() {
+            self.stop_targets_if_needed(&mut ctx);
             trace!("changing state");
             ctx.change_state(PlaybackState::Inactive);
             false
@@ -194,42 +293,65 @@ impl ActionController for Controller {
         self.rd = None;
     }
     fn execute(&mut self, time: u64, mut ctx: ControllerParams) -> BackendResult<bool> {
-        {
-            let mut gt = time;
-            let tgt = ctx.ctx.actions.get_mut(self.params.target.as_ref().unwrap())
-                .ok_or("Failed to get action")?;
-            let tgt = match tgt.ctl {
-                ActionType::Audio(ref mut t) => t,
-                _ => bail!("Action was wrong type")
-            };
-            let tgt = tgt.rd.as_mut()
-                .ok_or("Target isn't running or loaded")?;
-            let idp = Arc::new(());
-            if let Some(ref rd) = self.rd {
+        let mut gt = time;
+        let targets = match self.rd {
+            Some(ref rd) => {
+                /* Resume the fade on whatever it was fading before it got paused. */
                 gt = rd.start_time;
                 trace!("resuming fade that started @ {}", gt);
-            }
-            self.rd = Some(RunningData {
-                params: self.params.clone(),
-                start_time: time,
-                fd: FadeDetails::new(0.0, 0.0)
-            });
+                rd.targets.iter().map(|x| x.0).collect()
+            },
+            None => self.resolve_targets(ctx.ctx)
+        };
+        if targets.len() == 0 {
+            bail!("Nothing to fade");
+        }
+        self.rd = Some(RunningData {
+            params: self.params.clone(),
+            start_time: time,
+            targets: vec![]
+        });
+        let mut errs = vec![];
+        for uu in targets {
+            let tgt = match ctx.ctx.actions.get_mut(&uu) {
+                Some(t) => t,
+                None => {
+                    errs.push(format!("{}: no such action", uu));
+                    continue;
+                }
+            };
+            let senders = match tgt.ctl.senders_mut() {
+                Some(s) => s,
+                None => {
+                    errs.push(format!("{}: target isn't running or loaded", uu));
+                    continue;
+                }
+            };
+            let idp = Arc::new(());
+            self.rd.as_mut().unwrap().targets.push((uu, FadeDetails::new(0.0, 0.0)));
             if self.params.fade_master.0 {
-                if let Some(sdr) = tgt.senders.get_mut(0) {
+                if let Some(sdr) = senders.get_mut(0) {
                     let fade = self.params.fade_master.1;
-                    trace!("applying fade to master");
+                    trace!("applying fade to master of {}", uu);
                     self.apply_fade_to_master(fade, sdr, &idp, time, gt);
                 }
             }
             for (i, (enabled, fade)) in self.params.fades.clone().into_iter().enumerate() {
                 if enabled {
-                    if let Some(sdr) = tgt.senders.get_mut(i) {
-                        trace!("applying fade to chan #{}", i);
+                    if let Some(sdr) = senders.get_mut(i) {
+                        trace!("applying fade to chan #{} of {}", i, uu);
                         self.apply_fade_to_sdr(fade, sdr, &idp, time, gt);
                     }
                 }
             }
         }
+        if self.rd.as_ref().unwrap().targets.len() == 0 {
+            self.rd = None;
+            bail!("Failed to fade any targets: {}", errs.join("; "));
+        }
+        for e in errs {
+            warn!("fade skipped target {}", e);
+        }
         let now = Sender::<()>::precise_time_ns();
         let mut positive = false;
         let delta = if time > now {
@@ -253,20 +375,21 @@ impl ActionController for Controller {
         }
         if dur > Duration::new(0, 0) {
             trace!("time now = {}, sched = {}, delta = {:?}, conf dur = {:?}, wait time = {:?}", now, time, _dur, self.params.dur, dur);
-            let timeout = Timeout::new(dur, ctx.ctx.handle.as_ref().unwrap())?;
-            self.timeout = timeout.perform(&mut ctx);
-            let _ = self.timeout.poll();
+            self.timeout = ctx.timeout_after(dur)?;
             Ok(false)
         }
         else {
             trace!("fade cue with 0 duration");
+            self.stop_targets_if_needed(&mut ctx);
             Ok(true)
         }
     }
     fn duration_info(&self) -> Option<DurationInfoInt> {
         if let Some(ref rd) = self.rd {
             let now = Sender::<()>::precise_time_ns();
-            let start = rd.fd.start_time();
+            let start = rd.targets.get(0)
+                .map(|x| x.1.start_time())
+                .unwrap_or(rd.start_time);
             let delta = if start > now { 0 } else { now - start };
             let elapsed = DurationInfo::nanos_to_dur(delta);
             let total_dur = rd.params.dur;
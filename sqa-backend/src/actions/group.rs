@@ -0,0 +1,288 @@
+//! Groups of actions, started together or one after another.
+
+use super::{ActionController, EditableAction, PlaybackState, ControllerParams, ParameterError, DurationInfoInt, DurationInfo, ActionType};
+use state::Context;
+use errors::BackendResult;
+use uuid::Uuid;
+use std::time::Duration;
+use sqa_engine::Sender;
+use rand::{self, Rng};
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum GroupMode {
+    /// Start all children simultaneously.
+    StartAll,
+    /// Start the first child; each subsequent execution of the group starts the next one.
+    StepThrough,
+    /// Play the children one after the other, optionally in a random order.
+    Playlist { shuffle: bool }
+}
+impl Default for GroupMode {
+    fn default() -> Self {
+        GroupMode::StartAll
+    }
+}
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct GroupParams {
+    pub children: Vec<Uuid>,
+    pub mode: GroupMode
+}
+#[derive(Default)]
+pub struct Controller {
+    params: GroupParams,
+    /// The order children get started in (shuffled, for shuffled playlists).
+    queue: Vec<Uuid>,
+    /// Index into `queue` of the next child to start.
+    next: usize,
+    /// When the group was started (0 if it hasn't been).
+    start_time: u64,
+    paused: bool,
+    est_duration: Option<Duration>,
+    /// The UUID of the group itself (used to spot groups that contain themselves).
+    uuid: Option<Uuid>
+}
+fn child_running(ctx: &Context, uu: &Uuid) -> bool {
+    use self::PlaybackState::*;
+    match ctx.actions.get(uu).map(|a| &a.state) {
+        Some(&Active(_)) | Some(&Paused(_)) | Some(&Loading) => true,
+        _ => false
+    }
+}
+/// Check whether descending from `uu` into the children of groups ever leads back to
+/// something on `path`, i.e. whether the groups form a cycle.
+fn forms_cycle(ctx: &Context, uu: Uuid, path: &mut Vec<Uuid>) -> bool {
+    if path.contains(&uu) {
+        return true;
+    }
+    let children = match ctx.actions.get(&uu).map(|a| &a.ctl) {
+        Some(&ActionType::Group(ref g)) => g.params.children.clone(),
+        _ => return false
+    };
+    path.push(uu);
+    let ret = children.into_iter().any(|child| forms_cycle(ctx, child, path));
+    path.pop();
+    ret
+}
+impl Controller {
+    pub fn new() -> Self {
+        Default::default()
+    }
+    fn build_queue(&mut self) {
+        self.queue = self.params.children.clone();
+        if let GroupMode::Playlist { shuffle: true } = self.params.mode {
+            rand::thread_rng().shuffle(&mut self.queue);
+        }
+        self.next = 0;
+    }
+    fn start_child(ctx: &mut ControllerParams, uu: Uuid, time: u64) {
+        let res = ctx.with_action(uu, |a, ctx, tx| a.execute(time, ctx, tx));
+        match res {
+            Some(Err(e)) => warn!("group failed to start child {}: {}", uu, e),
+            None => warn!("group child {} has disappeared", uu),
+            _ => {}
+        }
+    }
+    /// Start children from the queue until one of them keeps running (or we run out).
+    fn start_next(&mut self, ctx: &mut ControllerParams, time: u64) {
+        while let Some(&uu) = self.queue.get(self.next) {
+            self.next += 1;
+            Self::start_child(ctx, uu, time);
+            if child_running(ctx.ctx, &uu) {
+                break;
+            }
+        }
+    }
+    fn update_est_duration(&mut self, ctx: &Context) {
+        let durs = self.params.children.iter()
+            .filter_map(|uu| ctx.actions.get(uu))
+            .filter_map(|a| a.duration_info().and_then(|d| d.est_duration));
+        self.est_duration = match self.params.mode {
+            GroupMode::StartAll => durs.max(),
+            GroupMode::Playlist { .. } => Some(durs.fold(Duration::new(0, 0), |acc, d| acc + d)),
+            GroupMode::StepThrough => None
+        };
+    }
+}
+impl EditableAction for Controller {
+    type Parameters = GroupParams;
+    fn get_params(&self) -> &GroupParams {
+        &self.params
+    }
+    fn set_params(&mut self, params: GroupParams, ctx: ControllerParams) {
+        self.params = params;
+        self.uuid = Some(ctx.uuid);
+    }
+}
+impl ActionController for Controller {
+    fn desc(&self, _: &Context) -> String {
+        let n = self.params.children.len();
+        match self.params.mode {
+            GroupMode::StartAll => format!("Group of {} actions", n),
+            GroupMode::StepThrough => format!("Step through {} actions", n),
+            GroupMode::Playlist { shuffle: false } => format!("Playlist of {} actions", n),
+            GroupMode::Playlist { shuffle: true } => format!("Shuffled playlist of {} actions", n)
+        }
+    }
+    fn verify_params(&self, ctx: &Context) -> Vec<ParameterError> {
+        let mut ret = vec![];
+        if self.params.children.len() == 0 {
+            ret.push(ParameterError {
+                name: "children".into(),
+                err: "The group has no children.".into()
+            });
+        }
+        for uu in self.params.children.iter() {
+            if Some(*uu) == self.uuid {
+                ret.push(ParameterError {
+                    name: "children".into(),
+                    err: "The group contains itself.".into()
+                });
+            }
+            else if forms_cycle(ctx, *uu, &mut self.uuid.into_iter().collect()) {
+                ret.push(ParameterError {
+                    name: "children".into(),
+                    err: format!("Child {} contains this group, or is part of a cycle of groups.", uu)
+                });
+            }
+            else if ctx.actions.get(uu).is_none() {
+                ret.push(ParameterError {
+                    name: "children".into(),
+                    err: format!("No action with UUID {} is present.", uu)
+                });
+            }
+        }
+        ret
+    }
+    fn load(&mut self, mut ctx: ControllerParams) -> BackendResult<bool> {
+        /* Step-through groups get loaded again between steps, so don't lose our place. */
+        if self.queue.is_empty() {
+            self.build_queue();
+        }
+        for uu in self.params.children.clone() {
+            let res = ctx.with_action(uu, |a, ctx, tx| -> BackendResult<()> {
+                a.verify_params(ctx);
+                match a.state {
+                    PlaybackState::Inactive => a.load(ctx, tx)?,
+                    PlaybackState::Unverified(_) => bail!("Child {} has errors.", uu),
+                    _ => {}
+                }
+                if let PlaybackState::Errored(ref e) = a.state {
+                    bail!("Child {} failed to load: {}", uu, e);
+                }
+                Ok(())
+            });
+            if let Some(r) = res {
+                r?;
+            }
+        }
+        self.update_est_duration(ctx.ctx);
+        Ok(true)
+    }
+    fn execute(&mut self, time: u64, mut ctx: ControllerParams) -> BackendResult<bool> {
+        if self.paused {
+            /* Resume everything we paused at the same instant, so they stay aligned. */
+            self.paused = false;
+            let now = Sender::<()>::precise_time_ns();
+            for uu in self.params.children.clone() {
+                let paused = match ctx.ctx.actions.get(&uu).map(|a| &a.state) {
+                    Some(&PlaybackState::Paused(_)) => true,
+                    _ => false
+                };
+                if paused {
+                    Self::start_child(&mut ctx, uu, now);
+                }
+            }
+            return Ok(false);
+        }
+        if self.queue.len() != self.params.children.len() {
+            self.build_queue();
+        }
+        self.start_time = time;
+        let ret = match self.params.mode {
+            GroupMode::StartAll => {
+                for uu in self.params.children.clone() {
+                    Self::start_child(&mut ctx, uu, time);
+                }
+                false
+            },
+            GroupMode::StepThrough => {
+                if self.next >= self.queue.len() {
+                    self.next = 0;
+                }
+                let uu = self.queue[self.next];
+                self.next += 1;
+                Self::start_child(&mut ctx, uu, time);
+                true
+            },
+            GroupMode::Playlist { .. } => {
+                self.next = 0;
+                self.start_next(&mut ctx, time);
+                false
+            }
+        };
+        self.update_est_duration(ctx.ctx);
+        Ok(ret)
+    }
+    fn poll(&mut self, mut ctx: ControllerParams) -> bool {
+        if self.start_time == 0 || self.paused {
+            return false;
+        }
+        if let GroupMode::Playlist { .. } = self.params.mode {
+            let cur = if self.next > 0 { self.queue.get(self.next - 1).cloned() } else { None };
+            if !cur.map(|uu| child_running(ctx.ctx, &uu)).unwrap_or(false) {
+                self.start_next(&mut ctx, Sender::<()>::precise_time_ns());
+            }
+        }
+        self.update_est_duration(ctx.ctx);
+        let running = self.params.children.iter()
+            .any(|uu| child_running(ctx.ctx, uu));
+        if running {
+            true
+        }
+        else {
+            self.start_time = 0;
+            ctx.change_state(PlaybackState::Inactive);
+            false
+        }
+    }
+    fn pause(&mut self, mut ctx: ControllerParams) -> bool {
+        for uu in self.params.children.clone() {
+            let active = match ctx.ctx.actions.get(&uu).map(|a| &a.state) {
+                Some(&PlaybackState::Active(_)) => true,
+                _ => false
+            };
+            if active {
+                ctx.with_action(uu, |a, ctx, tx| a.pause(ctx, tx));
+            }
+        }
+        self.paused = true;
+        true
+    }
+    fn reset(&mut self, mut ctx: ControllerParams) {
+        for uu in self.params.children.clone() {
+            let idle = match ctx.ctx.actions.get(&uu).map(|a| &a.state) {
+                Some(&PlaybackState::Inactive) | Some(&PlaybackState::Unverified(_)) | None => true,
+                _ => false
+            };
+            if !idle {
+                ctx.with_action(uu, |a, ctx, tx| a.reset(ctx, tx));
+            }
+        }
+        self.queue.clear();
+        self.next = 0;
+        self.start_time = 0;
+        self.paused = false;
+    }
+    fn duration_info(&self) -> Option<DurationInfoInt> {
+        if self.start_time == 0 {
+            return None;
+        }
+        let now = Sender::<()>::precise_time_ns();
+        let delta = if self.start_time > now { 0 } else { now - self.start_time };
+        Some(DurationInfoInt {
+            duration: DurationInfo::nanos_to_dur(delta),
+            start_time: self.start_time,
+            est_duration: self.est_duration
+        })
+    }
+}
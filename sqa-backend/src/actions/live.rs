@@ -0,0 +1,170 @@
+//! Playing live inputs through to channels.
+
+use sqa_engine::{PlainSender, BufferSender};
+use sqa_engine::param::Parameter;
+use sqa_engine::Sender;
+use super::{ParameterError, ControllerParams, DurationInfoInt, DurationInfo, ActionController, EditableAction};
+use super::audio::db_lin;
+use state::Context;
+use errors::*;
+use uuid::Uuid;
+
+/// One route from an input to a channel.
+///
+/// To send one input to several channels (or several inputs to one channel), use several
+/// of these.
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct LiveChannel {
+    /// The number of the engine input port (starting from 0).
+    pub input: usize,
+    pub patch: Option<Uuid>,
+    pub vol: f32
+}
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct LiveParams {
+    pub chans: Vec<LiveChannel>,
+    pub master_vol: f32
+}
+pub struct RunningData {
+    /// Keeps the routes alive; dropping these stops them.
+    _owners: Vec<BufferSender>,
+    pub senders: Vec<PlainSender>,
+    start_time: u64
+}
+#[derive(Default)]
+pub struct Controller {
+    pub params: LiveParams,
+    pub rd: Option<RunningData>
+}
+impl Controller {
+    pub fn new() -> Self {
+        Default::default()
+    }
+}
+impl EditableAction for Controller {
+    type Parameters = LiveParams;
+
+    fn get_params(&self) -> &LiveParams {
+        &self.params
+    }
+    fn set_params(&mut self, p: LiveParams, _: ControllerParams) {
+        if let Some(ref mut rd) = self.rd {
+            for (i, ch) in p.chans.iter().enumerate() {
+                if let Some(s) = rd.senders.get_mut(i) {
+                    s.set_volume(Box::new(Parameter::Raw(db_lin(ch.vol))));
+                }
+            }
+            if let Some(s) = rd.senders.get_mut(0) {
+                s.set_master_volume(Box::new(Parameter::Raw(db_lin(p.master_vol))));
+            }
+        }
+        self.params = p;
+    }
+}
+impl ActionController for Controller {
+    fn desc(&self, _: &Context) -> String {
+        let mut inputs: Vec<usize> = self.params.chans.iter().map(|c| c.input + 1).collect();
+        inputs.sort();
+        inputs.dedup();
+        match inputs.len() {
+            0 => "Live input [invalid]".into(),
+            1 => format!("Live input {}", inputs[0]),
+            _ => {
+                let list: Vec<String> = inputs.iter().map(|i| i.to_string()).collect();
+                format!("Live inputs {}", list.join(", "))
+            }
+        }
+    }
+    fn verify_params(&self, ctx: &Context) -> Vec<ParameterError> {
+        let mut ret = vec![];
+        if self.params.chans.len() == 0 {
+            ret.push(ParameterError {
+                name: "chans".into(),
+                err: "No inputs are routed anywhere.".into()
+            });
+        }
+        for ch in self.params.chans.iter() {
+            if ch.input >= ctx.mixer.num_inputs() {
+                ret.push(ParameterError {
+                    name: "chans".into(),
+                    err: format!("Input {} does not exist.", ch.input + 1)
+                });
+            }
+            if let Some(ref patch) = ch.patch {
+                if ctx.mixer.obtain_channel(patch).is_none() {
+                    ret.push(ParameterError {
+                        name: "chans".into(),
+                        err: format!("The channel input {} is routed to does not exist.", ch.input + 1)
+                    });
+                }
+            }
+        }
+        ret
+    }
+    fn load(&mut self, params: ControllerParams) -> BackendResult<bool> {
+        let inputs: Vec<usize> = self.params.chans.iter().map(|c| c.input).collect();
+        let mut senders = params.ctx.mixer.new_input_senders(&inputs)?;
+        for (s, ch) in senders.iter_mut().zip(self.params.chans.iter()) {
+            if let Some(ref patch) = ch.patch {
+                let patch = params.ctx.mixer.obtain_channel(patch)
+                    .ok_or("One channel mysteriously disappeared")?;
+                s.set_output_patch(patch);
+            }
+        }
+        let plains = senders.iter()
+            .map(|s| s.make_plain())
+            .collect();
+        self.rd = Some(RunningData {
+            _owners: senders,
+            senders: plains,
+            start_time: 0
+        });
+        Ok(true)
+    }
+    fn execute(&mut self, time: u64, ctx: ControllerParams) -> BackendResult<bool> {
+        if let Some(ref mut rd) = self.rd {
+            for (i, sender) in rd.senders.iter_mut().enumerate() {
+                if rd.start_time == 0 {
+                    if let Some(ch) = self.params.chans.get(i) {
+                        sender.set_volume(Box::new(Parameter::Raw(db_lin(ch.vol))));
+                    }
+                    if i == 0 {
+                        sender.set_master_volume(Box::new(Parameter::Raw(db_lin(self.params.master_vol))));
+                    }
+                }
+                let start_time = ctx.ctx.mixer.compensated_start_time(time, sender.output_patch());
+                sender.set_start_time(start_time);
+                sender.set_active(true);
+            }
+            rd.start_time = time;
+        }
+        Ok(false)
+    }
+    fn pause(&mut self, _: ControllerParams) -> bool {
+        if let Some(ref mut rd) = self.rd {
+            for sender in rd.senders.iter_mut() {
+                sender.set_active(false);
+            }
+            true
+        }
+        else {
+            false
+        }
+    }
+    fn reset(&mut self, _: ControllerParams) {
+        self.rd = None;
+    }
+    fn duration_info(&self) -> Option<DurationInfoInt> {
+        let rd = match self.rd {
+            Some(ref rd) if rd.start_time != 0 => rd,
+            _ => return None
+        };
+        let now = Sender::<()>::precise_time_ns();
+        let delta = if rd.start_time > now { 0 } else { now - rd.start_time };
+        Some(DurationInfoInt {
+            duration: DurationInfo::nanos_to_dur(delta),
+            start_time: rd.start_time,
+            est_duration: None
+        })
+    }
+}
@@ -0,0 +1,272 @@
+//! Sending MIDI messages (including MIDI Show Control) out of the engine's MIDI port.
+
+use super::{ActionController, EditableAction, AsyncResult, PlaybackState, ControllerParams, ParameterError, DurationInfoInt, DurationInfo};
+use state::Context;
+use errors::BackendResult;
+use std::time::Duration;
+use futures::Future;
+use sqa_engine::Sender;
+use sqa_engine::midi::MAX_MIDI_EVENT_SIZE;
+
+/// A MIDI Show Control command.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MscCommand {
+    Go,
+    Stop,
+    Resume,
+    Load,
+    GoOff,
+    /// Fire the given macro number.
+    Fire(u8),
+    AllOff,
+    Restore,
+    Reset
+}
+impl MscCommand {
+    fn code(&self) -> u8 {
+        use self::MscCommand::*;
+        match *self {
+            Go => 0x01,
+            Stop => 0x02,
+            Resume => 0x03,
+            Load => 0x05,
+            Fire(_) => 0x07,
+            AllOff => 0x08,
+            Restore => 0x09,
+            Reset => 0x0A,
+            GoOff => 0x0B
+        }
+    }
+    fn takes_cue(&self) -> bool {
+        use self::MscCommand::*;
+        match *self {
+            Go | Stop | Resume | Load | GoOff => true,
+            _ => false
+        }
+    }
+}
+/// One MIDI message. MIDI channels are numbered from 1 to 16.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub enum MidiMessage {
+    NoteOn { channel: u8, note: u8, velocity: u8 },
+    NoteOff { channel: u8, note: u8, velocity: u8 },
+    ControlChange { channel: u8, controller: u8, value: u8 },
+    ProgramChange { channel: u8, program: u8 },
+    /// A MIDI Show Control message.
+    ///
+    /// `device_id` 0x7F and `command_format` 0x7F are the "all call" values. The cue
+    /// number, list and path are ASCII numbers like "12.5", and may be left empty (later
+    /// ones are ignored if earlier ones are empty).
+    Msc {
+        device_id: u8,
+        command_format: u8,
+        command: MscCommand,
+        cue: String,
+        list: String,
+        path: String
+    }
+}
+impl MidiMessage {
+    /// Encode the message into MIDI bytes.
+    pub fn encode(&self) -> Vec<u8> {
+        use self::MidiMessage::*;
+        let chan = |c: u8| c.saturating_sub(1) & 0x0F;
+        match *self {
+            NoteOn { channel, note, velocity } => vec![0x90 | chan(channel), note, velocity],
+            NoteOff { channel, note, velocity } => vec![0x80 | chan(channel), note, velocity],
+            ControlChange { channel, controller, value } => vec![0xB0 | chan(channel), controller, value],
+            ProgramChange { channel, program } => vec![0xC0 | chan(channel), program],
+            Msc { device_id, command_format, command, ref cue, ref list, ref path } => {
+                let mut ret = vec![0xF0, 0x7F, device_id, 0x02, command_format, command.code()];
+                if let MscCommand::Fire(mac) = command {
+                    ret.push(mac);
+                }
+                else if command.takes_cue() && cue.len() > 0 {
+                    ret.extend(cue.bytes());
+                    if list.len() > 0 {
+                        ret.push(0x00);
+                        ret.extend(list.bytes());
+                        if path.len() > 0 {
+                            ret.push(0x00);
+                            ret.extend(path.bytes());
+                        }
+                    }
+                }
+                ret.push(0xF7);
+                ret
+            }
+        }
+    }
+    fn verify(&self) -> Option<String> {
+        use self::MidiMessage::*;
+        let (channel, data): (u8, Vec<u8>) = match *self {
+            NoteOn { channel, note, velocity } => (channel, vec![note, velocity]),
+            NoteOff { channel, note, velocity } => (channel, vec![note, velocity]),
+            ControlChange { channel, controller, value } => (channel, vec![controller, value]),
+            ProgramChange { channel, program } => (channel, vec![program]),
+            Msc { device_id, command_format, command, ref cue, ref list, ref path } => {
+                let bad = [cue, list, path].iter()
+                    .any(|s| !s.chars().all(|c| c.is_digit(10) || c == '.'));
+                if bad {
+                    return Some("MSC cue numbers, lists and paths may only contain digits and '.'.".into());
+                }
+                if self.encode().len() > MAX_MIDI_EVENT_SIZE {
+                    return Some("The MSC message is too long.".into());
+                }
+                let mac = if let MscCommand::Fire(m) = command { m } else { 0 };
+                (1, vec![device_id, command_format, mac])
+            }
+        };
+        if channel < 1 || channel > 16 {
+            return Some(format!("MIDI channel {} is out of range (1-16).", channel));
+        }
+        if data.iter().any(|&b| b > 0x7F) {
+            return Some("MIDI data values must be between 0 and 127.".into());
+        }
+        None
+    }
+}
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct MidiEventParams {
+    /// When to send the message, relative to when the action is executed.
+    #[serde(default)]
+    pub offset: Duration,
+    pub msg: MidiMessage
+}
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct MidiParams {
+    pub events: Vec<MidiEventParams>
+}
+#[derive(Default)]
+pub struct Controller {
+    params: MidiParams,
+    timeout: AsyncResult<(), ::std::io::Error>,
+    start_time: u64,
+    /// How far through the events we'd got when we were paused; the ones before this have
+    /// already gone out, so aren't sent again when resuming.
+    elapsed: Option<u64>
+}
+impl Controller {
+    pub fn new() -> Self {
+        Default::default()
+    }
+    fn last_offset(&self) -> Duration {
+        self.params.events.iter()
+            .map(|e| e.offset)
+            .max()
+            .unwrap_or(Duration::new(0, 0))
+    }
+}
+impl EditableAction for Controller {
+    type Parameters = MidiParams;
+    fn get_params(&self) -> &MidiParams {
+        &self.params
+    }
+    fn set_params(&mut self, params: MidiParams, _: ControllerParams) {
+        self.params = params;
+    }
+}
+impl ActionController for Controller {
+    fn desc(&self, _: &Context) -> String {
+        use self::MidiMessage::*;
+        if self.params.events.len() != 1 {
+            return format!("{} MIDI messages", self.params.events.len());
+        }
+        match self.params.events[0].msg {
+            NoteOn { channel, note, .. } => format!("MIDI note {} on (ch {})", note, channel),
+            NoteOff { channel, note, .. } => format!("MIDI note {} off (ch {})", note, channel),
+            ControlChange { channel, controller, value } => format!("MIDI CC {} = {} (ch {})", controller, value, channel),
+            ProgramChange { channel, program } => format!("MIDI program change {} (ch {})", program, channel),
+            Msc { command, ref cue, .. } => {
+                if cue.len() > 0 {
+                    format!("MSC {:?} {}", command, cue)
+                }
+                else {
+                    format!("MSC {:?}", command)
+                }
+            }
+        }
+    }
+    fn verify_params(&self, _: &Context) -> Vec<ParameterError> {
+        let mut ret = vec![];
+        if self.params.events.len() == 0 {
+            ret.push(ParameterError {
+                name: "events".into(),
+                err: "No MIDI messages are specified.".into()
+            });
+        }
+        for ev in self.params.events.iter() {
+            if let Some(err) = ev.msg.verify() {
+                ret.push(ParameterError {
+                    name: "events".into(),
+                    err
+                });
+            }
+        }
+        ret
+    }
+    fn execute(&mut self, time: u64, mut ctx: ControllerParams) -> BackendResult<bool> {
+        /* The engine schedules the messages relative to `time`, so they come out
+         * sample-accurately, however long it takes us to get round to this. */
+        let sent = self.elapsed.take().unwrap_or(0);
+        for ev in self.params.events.iter() {
+            if DurationInfo::dur_to_nanos(ev.offset) < sent {
+                continue;
+            }
+            let data = ev.msg.encode();
+            ctx.ctx.mixer.send_midi(time + DurationInfo::dur_to_nanos(ev.offset), ctx.uuid, &data)?;
+        }
+        self.start_time = time;
+        let end = time + DurationInfo::dur_to_nanos(self.last_offset());
+        let now = Sender::<()>::precise_time_ns();
+        if end <= now {
+            return Ok(true);
+        }
+        self.timeout = ctx.timeout_after(DurationInfo::nanos_to_dur(end - now))?;
+        Ok(false)
+    }
+    fn poll(&mut self, mut ctx: ControllerParams) -> bool {
+        let _ = self.timeout.poll();
+        if self.timeout.is_complete() {
+            self.timeout = AsyncResult::Empty;
+            self.start_time = 0;
+            ctx.change_state(PlaybackState::Inactive);
+            false
+        }
+        else {
+            true
+        }
+    }
+    fn pause(&mut self, ctx: ControllerParams) -> bool {
+        /* Hold back the messages that haven't gone out yet; they're sent again on resume. */
+        ctx.ctx.mixer.cancel_midi(ctx.uuid);
+        let now = Sender::<()>::precise_time_ns();
+        self.elapsed = Some(now.saturating_sub(self.start_time));
+        self.timeout = AsyncResult::Empty;
+        true
+    }
+    fn reset(&mut self, ctx: ControllerParams) {
+        /* Stop any messages that haven't gone out yet from doing so. */
+        ctx.ctx.mixer.cancel_midi(ctx.uuid);
+        self.timeout = AsyncResult::Empty;
+        self.start_time = 0;
+        self.elapsed = None;
+    }
+    fn duration_info(&self) -> Option<DurationInfoInt> {
+        if self.start_time == 0 {
+            return None;
+        }
+        let delta = match self.elapsed {
+            Some(e) => e,
+            None => {
+                let now = Sender::<()>::precise_time_ns();
+                if self.start_time > now { 0 } else { now - self.start_time }
+            }
+        };
+        Some(DurationInfoInt {
+            duration: DurationInfo::nanos_to_dur(delta),
+            start_time: self.start_time,
+            est_duration: Some(self.last_offset())
+        })
+    }
+}
@@ -1,9 +1,9 @@
 use futures::Future;
-use async::AsyncResult;
+use async::{AsyncResult, PerformExt};
 use uuid::Uuid;
 use std::borrow::Cow;
 use sqa_engine::sync::AudioThreadMessage;
-use sqa_engine::Sender;
+use sqa_engine::{Sender, PlainSender};
 use state::{Context, ServerMessage};
 use rosc::OscType;
 use state::IntSender;
@@ -15,6 +15,15 @@ use tokio_core::reactor::Timeout;
 
 pub mod audio;
 pub mod fade;
+pub mod group;
+pub mod control;
+pub mod wait;
+pub mod osc;
+pub mod midi;
+pub mod timecode;
+pub mod process;
+pub mod live;
+pub mod record;
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct ParameterError {
@@ -50,6 +59,9 @@ impl DurationInfo {
         let ssn = nanos % 1_000_000_000;
         Duration::new(secs, ssn as _)
     }
+    pub(crate) fn dur_to_nanos(dur: Duration) -> u64 {
+        dur.as_secs() * 1_000_000_000 + dur.subsec_nanos() as u64
+    }
     pub fn elapsed(&self, rounded: bool) -> (Duration, bool) {
         let mut secs = self.elapsed.as_secs();
         let mut ssn = self.elapsed.subsec_nanos();
@@ -97,7 +109,9 @@ impl DurationInfoInt {
 pub struct ControllerParams<'a> {
     ctx: &'a mut Context,
     internal_tx: &'a IntSender,
-    uuid: Uuid
+    uuid: Uuid,
+    /// The metadata (name, cue number, etc.) of the action being controlled.
+    meta: &'a ActionMetadata
 }
 impl<'a> ControllerParams<'a> {
     pub fn change_state(&mut self, st: PlaybackState) {
@@ -106,9 +120,28 @@ impl<'a> ControllerParams<'a> {
     pub fn register_interest(&mut self) {
         self.ctx.actions.register_interest(self.uuid);
     }
+    /// Start a timeout that completes after `dur`, getting the action polled when it does.
+    pub fn timeout_after(&mut self, dur: Duration) -> BackendResult<AsyncResult<(), ::std::io::Error>> {
+        let timeout = Timeout::new(dur, self.ctx.handle.as_ref().unwrap())?;
+        let mut ret = timeout.perform(self);
+        let _ = ret.poll();
+        Ok(ret)
+    }
     pub fn unregister_interest(&mut self) {
         self.ctx.actions.unregister_interest(self.uuid);
     }
+    /// Run `f` on another action (e.g. a target or child of this one), if it exists.
+    pub(crate) fn with_action<R, F>(&mut self, uu: Uuid, f: F) -> Option<R>
+        where F: FnOnce(&mut Action, &mut Context, &IntSender) -> R {
+        match self.ctx.actions.remove_for_editing(uu, true) {
+            Some(mut a) => {
+                let ret = f(&mut a, &mut *self.ctx, self.internal_tx);
+                self.ctx.actions.insert_after_editing(uu, a);
+                Some(ret)
+            },
+            None => None
+        }
+    }
 }
 pub trait OscEditable {
     fn edit(&mut self, path: &str, args: Vec<OscType>) -> BackendResult<()>;
@@ -134,6 +167,12 @@ pub(crate) trait ActionController {
     }
     fn reset(&mut self, _ctx: ControllerParams) {
     }
+    /// Start stopping gracefully (e.g. fading out over `dur`, or the action's own configured
+    /// fade time if `None`), returning `true` if the action will go inactive by itself once
+    /// it's done. If this returns `false`, the action is reset straight away instead.
+    fn stop_with_fade(&mut self, _dur: Option<Duration>, _ctx: ControllerParams) -> bool {
+        false
+    }
     fn estimated_duration(&self) -> Duration {
         Duration::from_millis(0)
     }
@@ -147,11 +186,49 @@ pub(crate) trait ActionController {
 pub enum ActionType {
     Audio(audio::Controller),
     Fade(fade::Controller),
+    Group(group::Controller),
+    Control(control::Controller),
+    Wait(wait::Controller),
+    Osc(osc::Controller),
+    Midi(midi::Controller),
+    Timecode(timecode::Controller),
+    Process(process::Controller),
+    Live(live::Controller),
+    Record(record::Controller),
+}
+impl ActionType {
+    /// Get the senders of an action that plays audio, if it's loaded or running, so that
+    /// their volumes can be adjusted (e.g. by fades).
+    pub fn senders_mut(&mut self) -> Option<&mut Vec<PlainSender>> {
+        match *self {
+            ActionType::Audio(ref mut a) => a.rd.as_mut().map(|rd| &mut rd.senders),
+            ActionType::Live(ref mut a) => a.rd.as_mut().map(|rd| &mut rd.senders),
+            _ => None
+        }
+    }
+    /// Get how many channels an action that plays audio has, or `None` if it doesn't play
+    /// audio.
+    pub fn audio_chans(&self) -> Option<usize> {
+        match *self {
+            ActionType::Audio(ref a) => Some(a.params.chans.len()),
+            ActionType::Live(ref a) => Some(a.params.chans.len()),
+            _ => None
+        }
+    }
 }
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub enum ActionParameters {
     Audio(<audio::Controller as EditableAction>::Parameters),
-    Fade(<fade::Controller as EditableAction>::Parameters)
+    Fade(<fade::Controller as EditableAction>::Parameters),
+    Group(<group::Controller as EditableAction>::Parameters),
+    Control(<control::Controller as EditableAction>::Parameters),
+    Wait(<wait::Controller as EditableAction>::Parameters),
+    Osc(<osc::Controller as EditableAction>::Parameters),
+    Midi(<midi::Controller as EditableAction>::Parameters),
+    Timecode(<timecode::Controller as EditableAction>::Parameters),
+    Process(<process::Controller as EditableAction>::Parameters),
+    Live(<live::Controller as EditableAction>::Parameters),
+    Record(<record::Controller as EditableAction>::Parameters)
 }
 #[macro_use]
 pub mod macros {
@@ -160,7 +237,16 @@ pub mod macros {
             use self::ActionType::*;
             match $a {
                 Audio(ref mut a) => a as &mut ActionController,
-                Fade(ref mut a) => a as &mut ActionController
+                Fade(ref mut a) => a as &mut ActionController,
+                Group(ref mut a) => a as &mut ActionController,
+                Control(ref mut a) => a as &mut ActionController,
+                Wait(ref mut a) => a as &mut ActionController,
+                Osc(ref mut a) => a as &mut ActionController,
+                Midi(ref mut a) => a as &mut ActionController,
+                Timecode(ref mut a) => a as &mut ActionController,
+                Process(ref mut a) => a as &mut ActionController,
+                Live(ref mut a) => a as &mut ActionController,
+                Record(ref mut a) => a as &mut ActionController
             }
         }};
         ($a:expr) => {{
@@ -168,13 +254,31 @@ pub mod macros {
             match $a {
                 Audio(ref a) => a as &ActionController,
                 Fade(ref a) => a as &ActionController,
+                Group(ref a) => a as &ActionController,
+                Control(ref a) => a as &ActionController,
+                Wait(ref a) => a as &ActionController,
+                Osc(ref a) => a as &ActionController,
+                Midi(ref a) => a as &ActionController,
+                Timecode(ref a) => a as &ActionController,
+                Process(ref a) => a as &ActionController,
+                Live(ref a) => a as &ActionController,
+                Record(ref a) => a as &ActionController,
             }
         }};
         (params $a:expr) => {{
             use self::ActionType::*;
             match $a {
                 Audio(ref a) => ActionParameters::Audio(a.get_params().clone()),
-                Fade(ref a) => ActionParameters::Fade(a.get_params().clone())
+                Fade(ref a) => ActionParameters::Fade(a.get_params().clone()),
+                Group(ref a) => ActionParameters::Group(a.get_params().clone()),
+                Control(ref a) => ActionParameters::Control(a.get_params().clone()),
+                Wait(ref a) => ActionParameters::Wait(a.get_params().clone()),
+                Osc(ref a) => ActionParameters::Osc(a.get_params().clone()),
+                Midi(ref a) => ActionParameters::Midi(a.get_params().clone()),
+                Timecode(ref a) => ActionParameters::Timecode(a.get_params().clone()),
+                Process(ref a) => ActionParameters::Process(a.get_params().clone()),
+                Live(ref a) => ActionParameters::Live(a.get_params().clone()),
+                Record(ref a) => ActionParameters::Record(a.get_params().clone())
             }
         }};
     }
@@ -197,15 +301,43 @@ impl OpaqueAction {
     pub fn typ(&self) -> &str {
         match self.params {
             ActionParameters::Audio(_) => "audio",
-            ActionParameters::Fade(_) => "fade"
+            ActionParameters::Fade(_) => "fade",
+            ActionParameters::Group(_) => "group",
+            ActionParameters::Control(_) => "control",
+            ActionParameters::Wait(_) => "wait",
+            ActionParameters::Osc(_) => "osc",
+            ActionParameters::Midi(_) => "midi",
+            ActionParameters::Timecode(_) => "timecode",
+            ActionParameters::Process(_) => "process",
+            ActionParameters::Live(_) => "live",
+            ActionParameters::Record(_) => "record"
         }
     }
 }
+/// What happens to the next action in the order when this one runs.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FollowMode {
+    /// Nothing: the next action must be fired separately.
+    None,
+    /// The next action is fired (after the post-wait) as soon as this one starts.
+    AutoContinue,
+    /// The next action is fired (after the post-wait) when this one finishes.
+    AutoFollow
+}
+impl Default for FollowMode {
+    fn default() -> Self {
+        FollowMode::None
+    }
+}
 #[derive(Serialize, Deserialize, Clone, Debug, Default)]
 pub struct ActionMetadata {
     pub name: Option<String>,
     pub prewait: Duration,
-    pub number: Option<String>
+    pub number: Option<String>,
+    #[serde(default)]
+    pub follow: FollowMode,
+    #[serde(default)]
+    pub postwait: Duration
 }
 pub struct Action {
     state: PlaybackState,
@@ -213,7 +345,10 @@ pub struct Action {
     meta: ActionMetadata,
     timeout: AsyncResult<(), ::std::io::Error>,
     uu: Uuid,
-    start_asap: bool
+    start_asap: bool,
+    /// Whether the action is in the middle of a `stop_with_fade()` (and so shouldn't
+    /// trigger any follows when it goes inactive).
+    stopping: bool
 }
 macro_rules! new_impl {
     ($($aty:ident, $atyl:ident),*) => {
@@ -226,22 +361,23 @@ macro_rules! new_impl {
                         uu: Uuid::new_v4(),
                         meta: Default::default(),
                         timeout: Default::default(),
-                        start_asap: false
+                        start_asap: false,
+                        stopping: false
                     }
                 }
             )*
         }
     }
 }
-new_impl!(Audio, audio, Fade, fade);
+new_impl!(Audio, audio, Fade, fade, Group, group, Control, control, Wait, wait, Osc, osc, Midi, midi, Timecode, timecode, Process, process, Live, live, Record, record);
 impl Action {
     pub fn accept_audio_message(&mut self, ctx: &mut Context, sender: &IntSender, msg: &AudioThreadMessage) -> bool {
-        let cp: ControllerParams = ControllerParams { ctx: ctx, internal_tx: sender, uuid: self.uu };
+        let cp: ControllerParams = ControllerParams { ctx: ctx, internal_tx: sender, uuid: self.uu, meta: &self.meta };
         action!(mut self.ctl).accept_audio_message(msg, cp)
     }
     pub fn load(&mut self, ctx: &mut Context, sender: &IntSender) -> BackendResult<()> {
         self.verify_params(ctx);
-        let cp: ControllerParams = ControllerParams { ctx: ctx, internal_tx: sender, uuid: self.uu };
+        let cp: ControllerParams = ControllerParams { ctx: ctx, internal_tx: sender, uuid: self.uu, meta: &self.meta };
         if let PlaybackState::Inactive = self.state {
             let x = match action!(mut self.ctl).load(cp) {
                 Ok(b) => b,
@@ -263,7 +399,7 @@ impl Action {
         Ok(())
     }
     pub fn pause(&mut self, ctx: &mut Context, sender: &IntSender) {
-        let cp: ControllerParams = ControllerParams { ctx: ctx, internal_tx: sender, uuid: self.uu };
+        let cp: ControllerParams = ControllerParams { ctx: ctx, internal_tx: sender, uuid: self.uu, meta: &self.meta };
         let durinfo = if let PlaybackState::Active(_) = self.state {
             action!(self.ctl).duration_info().map(|x| x.into())
         }
@@ -275,9 +411,42 @@ impl Action {
         }
     }
     pub fn reset(&mut self, ctx: &mut Context, sender: &IntSender) {
-        let cp: ControllerParams = ControllerParams { ctx: ctx, internal_tx: sender, uuid: self.uu };
+        let cp: ControllerParams = ControllerParams { ctx: ctx, internal_tx: sender, uuid: self.uu, meta: &self.meta };
         action!(mut self.ctl).reset(cp);
         self.state = PlaybackState::Inactive;
+        self.stopping = false;
+    }
+    /// Stop the action, letting it fade out first if it's running and knows how to.
+    pub fn stop_with_fade(&mut self, dur: Option<Duration>, ctx: &mut Context, sender: &IntSender) {
+        let fading = if let PlaybackState::Active(_) = self.state {
+            let cp: ControllerParams = ControllerParams { ctx: ctx, internal_tx: sender, uuid: self.uu, meta: &self.meta };
+            action!(mut self.ctl).stop_with_fade(dur, cp)
+        }
+        else {
+            false
+        };
+        if fading {
+            self.stopping = true;
+        }
+        else {
+            self.reset(ctx, sender);
+        }
+    }
+    /// Called when the audio engine goes away (i.e. the JACK server shut down).
+    ///
+    /// Anything holding engine resources gets reset; anything that was actually playing
+    /// is marked as errored, so the operator knows it was cut off.
+    pub fn on_engine_shutdown(&mut self, ctx: &mut Context, sender: &IntSender) {
+        use self::PlaybackState::*;
+        let errored = match self.state {
+            Active(_) | Paused(_) => true,
+            Loading | Loaded => false,
+            _ => return
+        };
+        self.reset(ctx, sender);
+        if errored {
+            self.state = Errored("The audio engine was disconnected whilst this action was running.".into());
+        }
     }
     pub fn set_uuid(&mut self, uu: Uuid) {
         self.uu = uu;
@@ -320,13 +489,19 @@ impl Action {
             let _ = self.timeout.poll();
             continue_polling = true;
         }
-        let cp: ControllerParams = ControllerParams { ctx: ctx, internal_tx: sender, uuid: self.uu };
+        let cp: ControllerParams = ControllerParams { ctx: ctx, internal_tx: sender, uuid: self.uu, meta: &self.meta };
         let res = action!(mut self.ctl).poll(cp);
         if !continue_polling {
             continue_polling = res;
         }
         continue_polling
     }
+    /// Start the action at `time`, or resume it if it's paused.
+    ///
+    /// The controller's `execute()` is given the time the action (notionally) started at:
+    /// `time` plus the prewait when starting afresh, or, when resuming, `time` moved back by
+    /// however far in the action had got before it was paused (or forward by however much
+    /// of the prewait was left). So controllers just carry on from wherever that puts them.
     pub fn execute(&mut self, time: u64, ctx: &mut Context, sender: &IntSender) -> BackendResult<()> {
         use self::PlaybackState::*;
         loop {
@@ -336,12 +511,16 @@ impl Action {
                     break;
                 },
                 Loaded | Paused(None) => {
-                    let time = time + (self.meta.prewait.subsec_nanos() as u64) + (self.meta.prewait.as_secs() * 1_000_000_000);
+                    let fresh = if let Loaded = self.state { true } else { false };
+                    let time = time + DurationInfo::dur_to_nanos(self.meta.prewait);
                     self._execute(time, ctx, sender);
+                    if fresh {
+                        self.maybe_follow(FollowMode::AutoContinue, time, sender);
+                    }
                     break;
                 },
                 Paused(Some(dur)) => {
-                    let delta_nanos = (dur.elapsed.subsec_nanos() as u64) + (dur.elapsed.as_secs() * 1_000_000_000);
+                    let delta_nanos = DurationInfo::dur_to_nanos(dur.elapsed);
                     let time = if dur.pos {
                         time - delta_nanos
                     } else {
@@ -358,8 +537,10 @@ impl Action {
         Ok(())
     }
     fn _execute(&mut self, time: u64, ctx: &mut Context, sender: &IntSender) {
+        /* Resuming an action that was paused whilst stopping cancels the stop. */
+        self.stopping = false;
         let x = {
-            let cp: ControllerParams = ControllerParams { ctx: ctx, internal_tx: sender, uuid: self.uu };
+            let cp: ControllerParams = ControllerParams { ctx: ctx, internal_tx: sender, uuid: self.uu, meta: &self.meta };
             match action!(mut self.ctl).execute(time, cp) {
                 Ok(b) => b,
                 Err(e) => {
@@ -370,6 +551,7 @@ impl Action {
         };
         if x {
             self.state = PlaybackState::Inactive;
+            self.maybe_follow(FollowMode::AutoFollow, Sender::<()>::precise_time_ns(), sender);
         }
         else {
             ctx.actions.register_interest(self.uu);
@@ -397,7 +579,11 @@ impl Action {
                         Paused(_) => {},
                         Active(_) => {},
                         Inactive => {
+                            let finished = if let Active(_) = self.state { !self.stopping } else { false };
                             self.reset(ctx, sender);
+                            if finished {
+                                self.maybe_follow(FollowMode::AutoFollow, Sender::<()>::precise_time_ns(), sender);
+                            }
                             return Ok(())
                         },
                         x => bail!(format!("Wrong state change for {:?}: {:?}", self.state, x))
@@ -440,11 +626,37 @@ impl Action {
             }
         }
     }
+    /// If this action's follow mode is `mode`, ask for the next action to be fired
+    /// (relative to `time`, the time this action started or finished).
+    fn maybe_follow(&self, mode: FollowMode, time: u64, sender: &IntSender) {
+        if self.meta.follow == mode {
+            sender.send(ServerMessage::ActionFollow(self.uu, time));
+        }
+    }
+    pub fn desc(&self, ctx: &Context) -> String {
+        action!(self.ctl).desc(ctx)
+    }
+    pub(crate) fn duration_info(&self) -> Option<DurationInfoInt> {
+        action!(self.ctl).duration_info()
+    }
+    /// Whether this is a fade action.
+    pub fn is_fade(&self) -> bool {
+        match self.ctl {
+            ActionType::Fade(_) => true,
+            _ => false
+        }
+    }
+    pub fn state(&self) -> &PlaybackState {
+        &self.state
+    }
+    pub fn meta(&self) -> &ActionMetadata {
+        &self.meta
+    }
     pub fn set_meta(&mut self, data: ActionMetadata) {
         self.meta = data; /* neat */
     }
     pub fn set_params(&mut self, data: ActionParameters, ctx: &mut Context, sender: &IntSender) -> BackendResult<()> {
-        let ctx: ControllerParams = ControllerParams { ctx: ctx, internal_tx: sender, uuid: self.uu };
+        let ctx: ControllerParams = ControllerParams { ctx: ctx, internal_tx: sender, uuid: self.uu, meta: &self.meta };
         match self.ctl {
             ActionType::Audio(ref mut a) => {
                 if let ActionParameters::Audio(d) = data {
@@ -463,6 +675,87 @@ impl Action {
                 else {
                     bail!("wrong type of action parameters");
                 }
+            },
+            ActionType::Group(ref mut a) => {
+                if let ActionParameters::Group(d) = data {
+                    a.set_params(d, ctx);
+                    Ok(())
+                }
+                else {
+                    bail!("wrong type of action parameters");
+                }
+            },
+            ActionType::Control(ref mut a) => {
+                if let ActionParameters::Control(d) = data {
+                    a.set_params(d, ctx);
+                    Ok(())
+                }
+                else {
+                    bail!("wrong type of action parameters");
+                }
+            },
+            ActionType::Wait(ref mut a) => {
+                if let ActionParameters::Wait(d) = data {
+                    a.set_params(d, ctx);
+                    Ok(())
+                }
+                else {
+                    bail!("wrong type of action parameters");
+                }
+            },
+            ActionType::Osc(ref mut a) => {
+                if let ActionParameters::Osc(d) = data {
+                    a.set_params(d, ctx);
+                    Ok(())
+                }
+                else {
+                    bail!("wrong type of action parameters");
+                }
+            },
+            ActionType::Midi(ref mut a) => {
+                if let ActionParameters::Midi(d) = data {
+                    a.set_params(d, ctx);
+                    Ok(())
+                }
+                else {
+                    bail!("wrong type of action parameters");
+                }
+            },
+            ActionType::Timecode(ref mut a) => {
+                if let ActionParameters::Timecode(d) = data {
+                    a.set_params(d, ctx);
+                    Ok(())
+                }
+                else {
+                    bail!("wrong type of action parameters");
+                }
+            },
+            ActionType::Process(ref mut a) => {
+                if let ActionParameters::Process(d) = data {
+                    a.set_params(d, ctx);
+                    Ok(())
+                }
+                else {
+                    bail!("wrong type of action parameters");
+                }
+            },
+            ActionType::Live(ref mut a) => {
+                if let ActionParameters::Live(d) = data {
+                    a.set_params(d, ctx);
+                    Ok(())
+                }
+                else {
+                    bail!("wrong type of action parameters");
+                }
+            },
+            ActionType::Record(ref mut a) => {
+                if let ActionParameters::Record(d) = data {
+                    a.set_params(d, ctx);
+                    Ok(())
+                }
+                else {
+                    bail!("wrong type of action parameters");
+                }
             }
         }
     }
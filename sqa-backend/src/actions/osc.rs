@@ -0,0 +1,332 @@
+//! Sending OSC messages to other devices.
+
+use super::{ActionController, EditableAction, AsyncResult, PlaybackState, ControllerParams, ParameterError, DurationInfoInt, DurationInfo};
+use async::PerformExt;
+use state::Context;
+use errors::*;
+use rosc::{encoder, OscMessage, OscPacket, OscType};
+use std::net::{SocketAddr, ToSocketAddrs, UdpSocket};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+use tokio_core::net::TcpStream;
+use tokio_io::codec::length_delimited::Framed;
+use futures::{stream, Future, Sink};
+use sqa_engine::Sender;
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OscTransport {
+    Udp,
+    /// OSC 1.0-style TCP, where each packet is prefixed with its length.
+    Tcp
+}
+impl Default for OscTransport {
+    fn default() -> Self {
+        OscTransport::Udp
+    }
+}
+/// An argument to an OSC message.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub enum OscArgument {
+    Int(i32),
+    Float(f32),
+    String(String),
+    Blob(Vec<u8>),
+    Long(i64),
+    Double(f64),
+    Bool(bool),
+    Nil
+}
+impl OscArgument {
+    pub fn to_osc(&self) -> OscType {
+        use self::OscArgument::*;
+        match *self {
+            Int(i) => OscType::Int(i),
+            Float(f) => OscType::Float(f),
+            String(ref s) => OscType::String(s.clone()),
+            Blob(ref b) => OscType::Blob(b.clone()),
+            Long(l) => OscType::Long(l),
+            Double(d) => OscType::Double(d),
+            Bool(b) => OscType::Bool(b),
+            Nil => OscType::Nil
+        }
+    }
+}
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct OscSendMessage {
+    pub addr: String,
+    pub args: Vec<OscArgument>
+}
+impl OscSendMessage {
+    pub fn to_osc(&self) -> OscMessage {
+        OscMessage {
+            addr: self.addr.clone(),
+            args: if self.args.len() == 0 {
+                None
+            }
+            else {
+                Some(self.args.iter().map(|a| a.to_osc()).collect())
+            }
+        }
+    }
+}
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct OscParams {
+    /// Where to send the messages to, as `host:port`.
+    pub dest: String,
+    pub transport: OscTransport,
+    pub messages: Vec<OscSendMessage>
+}
+/// The result of looking up an `OscParams::dest`, once it's been looked up.
+type ResolvedDest = Arc<Mutex<Option<Result<SocketAddr, String>>>>;
+#[derive(Default)]
+pub struct Controller {
+    params: OscParams,
+    dest: ResolvedDest,
+    /// Fires when it's time to send the messages (i.e. once any prewait is over).
+    timeout: AsyncResult<(), ::std::io::Error>,
+    /// The time we were executed for.
+    time: u64,
+    send: AsyncResult<(), BackendError>
+}
+fn resolve(dest: &str) -> Result<SocketAddr, String> {
+    match dest.to_socket_addrs() {
+        Ok(mut addrs) => addrs.next().ok_or_else(|| format!("{} doesn't resolve to any addresses", dest)),
+        Err(e) => Err(format!("Couldn't resolve {}: {}", dest, e))
+    }
+}
+/// Send some already-encoded packets to `addr` over UDP.
+fn send_udp(addr: &SocketAddr, pkts: &[Vec<u8>]) -> BackendResult<()> {
+    let bind = if addr.is_ipv4() { "0.0.0.0:0" } else { "[::]:0" };
+    let sock = UdpSocket::bind(bind)?;
+    for pkt in pkts {
+        sock.send_to(pkt, addr)?;
+    }
+    Ok(())
+}
+impl Controller {
+    pub fn new() -> Self {
+        Default::default()
+    }
+    /// Start looking up the destination. Hostnames get looked up in another thread, since
+    /// DNS can take ages and we don't want to hold up everything else.
+    fn start_resolving(&mut self) {
+        self.dest = Arc::new(Mutex::new(None));
+        if let Ok(addr) = self.params.dest.parse::<SocketAddr>() {
+            *self.dest.lock().unwrap() = Some(Ok(addr));
+            return;
+        }
+        let dest = self.params.dest.clone();
+        let result = self.dest.clone();
+        thread::spawn(move || {
+            let res = resolve(&dest);
+            *result.lock().unwrap() = Some(res);
+        });
+    }
+    fn resolved_dest(&self) -> BackendResult<SocketAddr> {
+        match *self.dest.lock().unwrap() {
+            Some(Ok(a)) => Ok(a),
+            Some(Err(ref e)) => bail!("{}", e),
+            None => bail!("Still looking up {}", self.params.dest)
+        }
+    }
+    /// Send the messages, returning `true` if they've all gone (or `false` if they're on
+    /// their way, in which case `self.send` completes once they've gone).
+    fn send_messages(&mut self, ctx: &mut ControllerParams) -> BackendResult<bool> {
+        let addr = self.resolved_dest()?;
+        let pkts = self.encode_messages()?;
+        match self.params.transport {
+            OscTransport::Udp => {
+                send_udp(&addr, &pkts)?;
+                Ok(true)
+            },
+            OscTransport::Tcp => {
+                let fut = TcpStream::connect(&addr, ctx.ctx.handle.as_ref().unwrap())
+                    .and_then(move |st| {
+                        Framed::<_, Vec<u8>>::new(st)
+                            .send_all(stream::iter_ok(pkts))
+                            .map(|_| ())
+                    })
+                    .map_err(BackendError::from);
+                self.send = fut.perform(ctx);
+                let _ = self.send.poll();
+                Ok(false)
+            }
+        }
+    }
+    fn encode_messages(&self) -> BackendResult<Vec<Vec<u8>>> {
+        let mut ret = vec![];
+        for msg in self.params.messages.iter() {
+            ret.push(encoder::encode(&OscPacket::Message(msg.to_osc()))?);
+        }
+        Ok(ret)
+    }
+}
+impl EditableAction for Controller {
+    type Parameters = OscParams;
+    fn get_params(&self) -> &OscParams {
+        &self.params
+    }
+    fn set_params(&mut self, params: OscParams, _: ControllerParams) {
+        let changed = params.dest != self.params.dest;
+        self.params = params;
+        if changed && self.params.dest.contains(':') {
+            self.start_resolving();
+        }
+    }
+}
+impl ActionController for Controller {
+    fn desc(&self, _: &Context) -> String {
+        match self.params.messages.len() {
+            0 => format!("OSC [invalid]"),
+            1 => format!("OSC {} to {}", self.params.messages[0].addr, self.params.dest),
+            n => format!("{} OSC messages to {}", n, self.params.dest)
+        }
+    }
+    fn verify_params(&self, _: &Context) -> Vec<ParameterError> {
+        let mut ret = vec![];
+        let port = self.params.dest.rsplitn(2, ':').next()
+            .and_then(|p| p.parse::<u16>().ok());
+        if !self.params.dest.contains(':') || port.is_none() {
+            ret.push(ParameterError {
+                name: "dest".into(),
+                err: "The destination must be of the form host:port.".into()
+            });
+        }
+        else if let Some(Err(ref e)) = *self.dest.lock().unwrap() {
+            ret.push(ParameterError {
+                name: "dest".into(),
+                err: e.clone()
+            });
+        }
+        if self.params.messages.len() == 0 {
+            ret.push(ParameterError {
+                name: "messages".into(),
+                err: "No messages are specified.".into()
+            });
+        }
+        for msg in self.params.messages.iter() {
+            if !msg.addr.starts_with('/') {
+                ret.push(ParameterError {
+                    name: "messages".into(),
+                    err: format!("The OSC address '{}' doesn't start with a '/'.", msg.addr)
+                });
+            }
+        }
+        ret
+    }
+    fn execute(&mut self, time: u64, mut ctx: ControllerParams) -> BackendResult<bool> {
+        self.time = time;
+        let now = Sender::<()>::precise_time_ns();
+        if time <= now {
+            return self.send_messages(&mut ctx);
+        }
+        /* Hold on to the messages until the prewait's over. */
+        self.timeout = ctx.timeout_after(DurationInfo::nanos_to_dur(time - now))?;
+        Ok(false)
+    }
+    fn poll(&mut self, mut ctx: ControllerParams) -> bool {
+        let _ = self.timeout.poll();
+        if self.timeout.is_complete() {
+            self.timeout = AsyncResult::Empty;
+            return match self.send_messages(&mut ctx) {
+                Ok(false) => true,
+                Ok(true) => {
+                    ctx.change_state(PlaybackState::Inactive);
+                    false
+                },
+                Err(e) => {
+                    ctx.change_state(PlaybackState::Errored(format!("Failed to send OSC: {}", e)));
+                    false
+                }
+            };
+        }
+        let _ = self.send.poll();
+        if self.send.is_complete() {
+            let res = ::std::mem::replace(&mut self.send, AsyncResult::Empty).as_result();
+            match res {
+                Ok(_) => ctx.change_state(PlaybackState::Inactive),
+                Err(e) => ctx.change_state(PlaybackState::Errored(format!("Failed to send OSC: {}", e)))
+            }
+            false
+        }
+        else {
+            true
+        }
+    }
+    fn pause(&mut self, _: ControllerParams) -> bool {
+        /* We can hold off sending until later, but not pause a send that's under way. */
+        if self.timeout.is_waiting() {
+            self.timeout = AsyncResult::Empty;
+            true
+        }
+        else {
+            false
+        }
+    }
+    fn reset(&mut self, _: ControllerParams) {
+        self.timeout = AsyncResult::Empty;
+        self.send = AsyncResult::Empty;
+    }
+    fn duration_info(&self) -> Option<DurationInfoInt> {
+        if !self.timeout.is_waiting() {
+            return None;
+        }
+        Some(DurationInfoInt {
+            duration: Duration::new(0, 0),
+            start_time: self.time,
+            est_duration: None
+        })
+    }
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rosc::decoder;
+    use std::time::Duration;
+
+    #[test]
+    fn udp_send_arrives_intact() {
+        let listener = UdpSocket::bind("127.0.0.1:0").unwrap();
+        listener.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        let addr = listener.local_addr().unwrap();
+        let mut ctl = Controller::new();
+        ctl.params = OscParams {
+            dest: addr.to_string(),
+            transport: OscTransport::Udp,
+            messages: vec![OscSendMessage {
+                addr: "/cue/1/go".into(),
+                args: vec![OscArgument::Int(42), OscArgument::String("hello".into())]
+            }]
+        };
+        ctl.start_resolving();
+        assert_eq!(ctl.resolved_dest().unwrap(), addr);
+        let pkts = ctl.encode_messages().unwrap();
+        send_udp(&addr, &pkts).unwrap();
+        let mut buf = [0u8; 1024];
+        let (n, _) = listener.recv_from(&mut buf).unwrap();
+        match decoder::decode(&buf[..n]).unwrap() {
+            OscPacket::Message(msg) => {
+                assert_eq!(msg.addr, "/cue/1/go");
+                let args = msg.args.unwrap();
+                assert_eq!(args.len(), 2);
+                assert_eq!(args[0].clone().int(), Some(42));
+                assert_eq!(args[1].clone().string(), Some("hello".into()));
+            },
+            x => panic!("expected a message, got {:?}", x)
+        }
+    }
+    #[test]
+    fn hostnames_are_resolved_in_the_background() {
+        let mut ctl = Controller::new();
+        ctl.params.dest = "localhost:53000".into();
+        ctl.start_resolving();
+        for _ in 0..100 {
+            if ctl.dest.lock().unwrap().is_some() {
+                break;
+            }
+            thread::sleep(Duration::from_millis(50));
+        }
+        assert_eq!(ctl.resolved_dest().unwrap().port(), 53000);
+    }
+}
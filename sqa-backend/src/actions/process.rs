@@ -0,0 +1,234 @@
+//! Running an external program.
+
+use super::{ActionController, EditableAction, AsyncResult, PlaybackState, ControllerParams, ParameterError, DurationInfoInt, DurationInfo};
+use state::Context;
+use errors::BackendResult;
+use std::time::Duration;
+use std::process::{Command, Child};
+use std::path::Path;
+use std::thread;
+use futures::Future;
+use sqa_engine::Sender;
+
+/// How often we check whether the process has exited.
+const POLL_INTERVAL_MS: u64 = 100;
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct ProcessParams {
+    /// The program to run; if it isn't a path, it's looked up in `PATH`.
+    pub program: String,
+    #[serde(default)]
+    pub args: Vec<String>,
+    /// Extra environment variables to set, on top of the backend's own environment.
+    #[serde(default)]
+    pub env: Vec<(String, String)>,
+    /// The working directory to run the program in.
+    #[serde(default)]
+    pub cwd: Option<String>,
+    /// Whether to stay active until the process exits (erroring if it fails), rather than
+    /// completing as soon as it's started.
+    #[serde(default)]
+    pub wait: bool,
+    /// If waiting, how long to give the process before killing it.
+    #[serde(default)]
+    pub timeout: Option<Duration>
+}
+/// Wait for `child` to exit on another thread, so that it doesn't hang around as a zombie
+/// and we don't block the event loop waiting for it.
+fn reap(mut child: Child) {
+    thread::spawn(move || {
+        let _ = child.wait();
+    });
+}
+#[derive(Default)]
+pub struct Controller {
+    params: ProcessParams,
+    child: Option<Child>,
+    timeout: AsyncResult<(), ::std::io::Error>,
+    /// Whether `timeout` is waiting for the prewait to finish, rather than for the next check.
+    spawn_pending: bool,
+    start_time: u64
+}
+impl Controller {
+    pub fn new() -> Self {
+        Default::default()
+    }
+    fn kill(&mut self) {
+        if let Some(mut child) = self.child.take() {
+            if let Ok(None) = child.try_wait() {
+                debug!("killing process {}", child.id());
+                let _ = child.kill();
+            }
+            reap(child);
+        }
+    }
+    /// Start the process, returning whether we're already done (i.e. we aren't waiting).
+    fn spawn(&mut self, time: u64, ctx: &mut ControllerParams) -> BackendResult<bool> {
+        let mut cmd = Command::new(&self.params.program);
+        cmd.args(&self.params.args);
+        for &(ref k, ref v) in self.params.env.iter() {
+            cmd.env(k, v);
+        }
+        if let Some(ref cwd) = self.params.cwd {
+            cmd.current_dir(cwd);
+        }
+        let child = match cmd.spawn() {
+            Ok(c) => c,
+            Err(e) => bail!("Failed to run {}: {}", self.params.program, e)
+        };
+        debug!("started process {} ({})", child.id(), self.params.program);
+        if !self.params.wait {
+            reap(child);
+            return Ok(true);
+        }
+        self.child = Some(child);
+        self.start_time = time;
+        self.schedule(ctx)?;
+        Ok(false)
+    }
+    fn schedule(&mut self, ctx: &mut ControllerParams) -> BackendResult<()> {
+        self.timeout = ctx.timeout_after(Duration::from_millis(POLL_INTERVAL_MS))?;
+        Ok(())
+    }
+    fn timed_out(&self) -> bool {
+        match self.duration_info() {
+            Some(info) => self.params.timeout.map(|t| info.duration >= t).unwrap_or(false),
+            None => false
+        }
+    }
+}
+impl EditableAction for Controller {
+    type Parameters = ProcessParams;
+    fn get_params(&self) -> &ProcessParams {
+        &self.params
+    }
+    fn set_params(&mut self, params: ProcessParams, _: ControllerParams) {
+        self.params = params;
+    }
+}
+impl ActionController for Controller {
+    fn desc(&self, _: &Context) -> String {
+        let name = Path::new(&self.params.program).file_name()
+            .map(|x| x.to_string_lossy().into_owned())
+            .unwrap_or_else(|| self.params.program.clone());
+        format!("Run {}", name)
+    }
+    fn verify_params(&self, _: &Context) -> Vec<ParameterError> {
+        let mut ret = vec![];
+        if self.params.program.trim().len() == 0 {
+            ret.push(ParameterError {
+                name: "program".into(),
+                err: "This field is required.".into()
+            });
+        }
+        if let Some(ref cwd) = self.params.cwd {
+            if !Path::new(cwd).is_dir() {
+                ret.push(ParameterError {
+                    name: "cwd".into(),
+                    err: format!("The directory {} does not exist.", cwd)
+                });
+            }
+        }
+        if self.params.timeout == Some(Duration::new(0, 0)) {
+            ret.push(ParameterError {
+                name: "timeout".into(),
+                err: "The timeout can't be zero.".into()
+            });
+        }
+        ret
+    }
+    fn execute(&mut self, time: u64, mut ctx: ControllerParams) -> BackendResult<bool> {
+        /* Don't leave anything from last time lying around. */
+        self.kill();
+        self.timeout = AsyncResult::Empty;
+        let now = Sender::<()>::precise_time_ns();
+        if time <= now {
+            return self.spawn(time, &mut ctx);
+        }
+        /* Wait until the prewait's over before starting the process. */
+        self.timeout = ctx.timeout_after(DurationInfo::nanos_to_dur(time - now))?;
+        self.spawn_pending = true;
+        self.start_time = time;
+        Ok(false)
+    }
+    fn poll(&mut self, mut ctx: ControllerParams) -> bool {
+        let _ = self.timeout.poll();
+        if !self.timeout.is_complete() {
+            return true;
+        }
+        self.timeout = AsyncResult::Empty;
+        if self.spawn_pending {
+            self.spawn_pending = false;
+            let now = Sender::<()>::precise_time_ns();
+            self.start_time = 0;
+            match self.spawn(now, &mut ctx) {
+                Ok(false) => return true,
+                Ok(true) => ctx.change_state(PlaybackState::Inactive),
+                Err(e) => ctx.change_state(PlaybackState::Errored(e.to_string()))
+            }
+            return false;
+        }
+        let res = match self.child {
+            Some(ref mut c) => c.try_wait(),
+            None => return false
+        };
+        let state = match res {
+            Ok(Some(status)) => {
+                debug!("process {} exited with {}", self.params.program, status);
+                self.child = None;
+                if status.success() {
+                    PlaybackState::Inactive
+                }
+                else {
+                    PlaybackState::Errored(format!("The process failed ({}).", status))
+                }
+            },
+            Ok(None) => {
+                if self.timed_out() {
+                    self.kill();
+                    PlaybackState::Errored("The process timed out, and was killed.".into())
+                }
+                else if let Err(e) = self.schedule(&mut ctx) {
+                    PlaybackState::Errored(e.to_string())
+                }
+                else {
+                    return true;
+                }
+            },
+            Err(e) => PlaybackState::Errored(format!("Failed to check on the process: {}", e))
+        };
+        self.start_time = 0;
+        ctx.change_state(state);
+        false
+    }
+    fn pause(&mut self, _: ControllerParams) -> bool {
+        /* We can hold off starting the process until later, but not pause it once it's
+         * running. */
+        if self.spawn_pending {
+            self.spawn_pending = false;
+            self.timeout = AsyncResult::Empty;
+            true
+        }
+        else {
+            false
+        }
+    }
+    fn reset(&mut self, _: ControllerParams) {
+        self.kill();
+        self.timeout = AsyncResult::Empty;
+        self.spawn_pending = false;
+        self.start_time = 0;
+    }
+    fn duration_info(&self) -> Option<DurationInfoInt> {
+        if self.start_time == 0 {
+            return None;
+        }
+        let now = Sender::<()>::precise_time_ns();
+        let delta = if self.start_time > now { 0 } else { now - self.start_time };
+        Some(DurationInfoInt {
+            duration: DurationInfo::nanos_to_dur(delta),
+            start_time: self.start_time,
+            est_duration: self.params.timeout
+        })
+    }
+}
@@ -0,0 +1,363 @@
+//! Recording the engine's input ports to a file.
+
+use super::{ActionController, EditableAction, AsyncResult, PlaybackState, ControllerParams, ParameterError, DurationInfoInt, DurationInfo, ActionParameters, ActionMetadata};
+use super::audio::AudioParams;
+use state::{Context, ServerMessage, IntSender};
+use errors::*;
+use std::time::Duration;
+use std::thread;
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError};
+use std::path::{Path, PathBuf};
+use std::fs::File;
+use std::io::BufWriter;
+use futures::Future;
+use hound::{WavWriter, WavSpec, SampleFormat};
+use chrono::Local;
+use sqa_engine::{Sender, BufferRecorder, PlainRecorder};
+use sqa_engine::sync::AudioThreadMessage;
+use url::Url;
+use uuid::Uuid;
+
+/// How often we check on the recording.
+const POLL_INTERVAL_MS: u64 = 500;
+/// How long the writer thread waits for a message before writing out what it has.
+const WRITE_INTERVAL_MS: u64 = 50;
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct RecordParams {
+    /// The numbers of the engine input ports to record (starting from 0), one per channel
+    /// of the recorded file.
+    pub inputs: Vec<usize>,
+    /// Where to save the recording, as a WAV file.
+    ///
+    /// `{show}` is replaced with the name of the current savefile, `{cue}` with this action's
+    /// cue number (or name), and `{timestamp}` with the date and time recording started.
+    /// Relative paths are relative to the savefile's directory, if there is one.
+    pub path: String,
+    /// Whether to make an audio action that plays back the recording, once it's done.
+    #[serde(default)]
+    pub create_cue: bool,
+    /// How long to record for. If `None`, we carry on until stopped.
+    #[serde(default)]
+    pub max_duration: Option<Duration>
+}
+pub enum WriterMessage {
+    Stop
+}
+/// Writes out recorded audio from a separate thread, so as not to hold up the backend.
+pub struct WriterContext {
+    rec: BufferRecorder,
+    writer: WavWriter<BufWriter<File>>,
+    uuid: Uuid,
+    sender: IntSender,
+    rx: Receiver<WriterMessage>,
+    path: PathBuf,
+    /// Whether to ask for an audio action to be made out of the recording, once it's done.
+    create_cue: bool
+}
+impl WriterContext {
+    /// Write out everything that's been recorded so far, returning the number of frames written.
+    fn drain(&mut self) -> BackendResult<u64> {
+        let avail = self.rec.bufs.iter()
+            .map(|b| b.size())
+            .min()
+            .unwrap_or(0);
+        for _ in 0..avail {
+            for buf in self.rec.bufs.iter() {
+                self.writer.write_sample(buf.try_pop().unwrap_or(0.0))?;
+            }
+        }
+        Ok(avail as u64)
+    }
+    fn write(&mut self) -> BackendResult<u64> {
+        let mut frames = 0;
+        loop {
+            let stop = match self.rx.recv_timeout(Duration::from_millis(WRITE_INTERVAL_MS)) {
+                Ok(WriterMessage::Stop) | Err(RecvTimeoutError::Disconnected) => true,
+                Err(RecvTimeoutError::Timeout) => false
+            };
+            frames += self.drain()?;
+            if stop {
+                return Ok(frames);
+            }
+        }
+    }
+    /// Write everything out until told to stop, then tell the backend how it went.
+    pub fn run(mut self) {
+        let res = self.write();
+        /* Stop recording before finalizing, so nothing else piles up. */
+        self.rec.set_active(false);
+        let WriterContext { writer, uuid, sender, path, create_cue, .. } = self;
+        let res = res.and_then(|frames| {
+            writer.finalize()?;
+            Ok(frames)
+        });
+        match res {
+            Ok(frames) => {
+                debug!("recorded {} frames to {}", frames, path.display());
+                if frames > 0 && create_cue {
+                    sender.send(ServerMessage::RecordingFinished(path));
+                }
+            },
+            Err(e) => {
+                error!("recording writer error: {:?}", e);
+                let msg = format!("Error writing recording: {}", e);
+                sender.send(ServerMessage::ActionStateChange(uuid, PlaybackState::Errored(msg)));
+            }
+        }
+    }
+}
+/// Make an audio action that plays back the recording at `path`.
+///
+/// This is called when a recording action's writer thread is done with the file (see
+/// `ServerMessage::RecordingFinished`).
+pub fn create_playback_action(ctx: &mut Context, path: &Path) -> BackendResult<Uuid> {
+    let url = match Url::from_file_path(path) {
+        Ok(u) => u.into_string(),
+        Err(_) => bail!("Couldn't make a URL out of {}", path.display())
+    };
+    let params = AudioParams {
+        url: Some(url),
+        ..Default::default()
+    };
+    let meta = ActionMetadata {
+        name: path.file_name().map(|x| x.to_string_lossy().into_owned()),
+        ..Default::default()
+    };
+    ctx.create_action("audio", Some(ActionParameters::Audio(params)), Some(meta), None)
+}
+pub struct RunningData {
+    control: mpsc::Sender<WriterMessage>,
+    rec: PlainRecorder,
+    path: PathBuf
+}
+#[derive(Default)]
+pub struct Controller {
+    params: RecordParams,
+    rd: Option<RunningData>,
+    timeout: AsyncResult<(), ::std::io::Error>,
+    /// When recording (notionally) started, taking into account time spent paused.
+    start_time: u64,
+    /// How far in we were when we were paused.
+    elapsed: Option<Duration>,
+    /// The number of overruns we've already warned about.
+    overruns: usize
+}
+/// Stop a substituted value from introducing extra path components.
+fn sanitize(s: &str) -> String {
+    s.chars()
+        .map(|c| if c == '/' || c == '\\' { '_' } else { c })
+        .collect()
+}
+impl Controller {
+    pub fn new() -> Self {
+        Default::default()
+    }
+    /// Work out where to save the recording, by filling in the path template.
+    fn expand_path(&self, ctx: &ControllerParams) -> BackendResult<PathBuf> {
+        let show = ctx.ctx.savefile.as_ref()
+            .and_then(|p| Path::new(p).file_stem())
+            .map(|s| s.to_string_lossy().into_owned())
+            .unwrap_or_else(|| "untitled".into());
+        let cue = ctx.meta.number.as_ref()
+            .or(ctx.meta.name.as_ref())
+            .map(|s| s.clone())
+            .unwrap_or_else(|| "record".into());
+        let timestamp = Local::now().format("%Y-%m-%d_%H-%M-%S").to_string();
+        let path = self.params.path
+            .replace("{show}", &sanitize(&show))
+            .replace("{cue}", &sanitize(&cue))
+            .replace("{timestamp}", &timestamp);
+        let path = PathBuf::from(path);
+        if path.is_absolute() {
+            return Ok(path);
+        }
+        let base = match ctx.ctx.savefile.as_ref().and_then(|p| Path::new(p).parent()) {
+            Some(dir) if dir != Path::new("") => dir.to_path_buf(),
+            _ => ::std::env::current_dir()?
+        };
+        Ok(base.join(path))
+    }
+    fn schedule(&mut self, ctx: &mut ControllerParams) -> BackendResult<()> {
+        self.timeout = ctx.timeout_after(Duration::from_millis(POLL_INTERVAL_MS))?;
+        Ok(())
+    }
+    fn start(&mut self, ctx: &mut ControllerParams) -> BackendResult<()> {
+        let path = self.expand_path(ctx)?;
+        let rec = ctx.ctx.mixer.new_recorder(&self.params.inputs)?;
+        let spec = WavSpec {
+            channels: self.params.inputs.len() as u16,
+            sample_rate: rec.sample_rate as u32,
+            bits_per_sample: 32,
+            sample_format: SampleFormat::Float
+        };
+        let writer = match WavWriter::create(&path, spec) {
+            Ok(w) => w,
+            Err(e) => bail!("Failed to create {}: {}", path.display(), e)
+        };
+        debug!("recording to {}", path.display());
+        let (tx, rx) = mpsc::channel();
+        let plain = rec.make_plain();
+        let wctx = WriterContext {
+            rec: rec,
+            writer: writer,
+            uuid: ctx.uuid,
+            sender: ctx.internal_tx.clone(),
+            rx: rx,
+            path: path.clone(),
+            create_cue: self.params.create_cue
+        };
+        thread::spawn(move || {
+            wctx.run()
+        });
+        self.overruns = 0;
+        self.rd = Some(RunningData {
+            control: tx,
+            rec: plain,
+            path: path
+        });
+        Ok(())
+    }
+    /// Stop recording. The writer thread finishes writing out in its own time, and makes
+    /// the playback action if we want one.
+    fn finish(&mut self) {
+        if let Some(mut rd) = self.rd.take() {
+            rd.rec.set_active(false);
+            let _ = rd.control.send(WriterMessage::Stop);
+        }
+    }
+}
+impl EditableAction for Controller {
+    type Parameters = RecordParams;
+    fn get_params(&self) -> &RecordParams {
+        &self.params
+    }
+    fn set_params(&mut self, params: RecordParams, _: ControllerParams) {
+        self.params = params;
+    }
+}
+impl ActionController for Controller {
+    fn desc(&self, _: &Context) -> String {
+        let inputs: Vec<String> = self.params.inputs.iter().map(|i| (i + 1).to_string()).collect();
+        match inputs.len() {
+            0 => "Record [invalid]".into(),
+            1 => format!("Record input {}", inputs[0]),
+            _ => format!("Record inputs {}", inputs.join(", "))
+        }
+    }
+    fn verify_params(&self, ctx: &Context) -> Vec<ParameterError> {
+        let mut ret = vec![];
+        if self.params.inputs.len() == 0 {
+            ret.push(ParameterError {
+                name: "inputs".into(),
+                err: "No inputs are being recorded.".into()
+            });
+        }
+        for &input in self.params.inputs.iter() {
+            if input >= ctx.mixer.num_inputs() {
+                ret.push(ParameterError {
+                    name: "inputs".into(),
+                    err: format!("Input {} does not exist.", input + 1)
+                });
+            }
+        }
+        if self.params.path.trim().len() == 0 {
+            ret.push(ParameterError {
+                name: "path".into(),
+                err: "This field is required.".into()
+            });
+        }
+        if self.params.max_duration == Some(Duration::new(0, 0)) {
+            ret.push(ParameterError {
+                name: "max_duration".into(),
+                err: "The maximum duration can't be zero.".into()
+            });
+        }
+        ret
+    }
+    fn execute(&mut self, time: u64, mut ctx: ControllerParams) -> BackendResult<bool> {
+        /* When resuming, we carry on with the same file. */
+        if self.rd.is_none() {
+            self.start(&mut ctx)?;
+        }
+        if let Some(ref mut rd) = self.rd {
+            rd.rec.set_start_time(time);
+            rd.rec.set_active(true);
+        }
+        self.elapsed = None;
+        self.start_time = time;
+        self.schedule(&mut ctx)?;
+        Ok(false)
+    }
+    fn poll(&mut self, mut ctx: ControllerParams) -> bool {
+        let _ = self.timeout.poll();
+        if !self.timeout.is_complete() {
+            return true;
+        }
+        self.timeout = AsyncResult::Empty;
+        if let Some(ref rd) = self.rd {
+            let overruns = rd.rec.overruns();
+            if overruns > self.overruns {
+                warn!("recording to {} dropped audio ({} overruns)", rd.path.display(), overruns);
+                self.overruns = overruns;
+            }
+        }
+        let done = match (self.duration_info(), self.params.max_duration) {
+            (Some(info), Some(max)) => info.duration >= max,
+            _ => false
+        };
+        if done {
+            ctx.change_state(PlaybackState::Inactive);
+            return false;
+        }
+        if let Err(e) = self.schedule(&mut ctx) {
+            ctx.change_state(PlaybackState::Errored(e.to_string()));
+            return false;
+        }
+        true
+    }
+    fn pause(&mut self, _: ControllerParams) -> bool {
+        if let Some(info) = self.duration_info() {
+            self.elapsed = Some(info.duration);
+        }
+        self.timeout = AsyncResult::Empty;
+        if let Some(ref mut rd) = self.rd {
+            rd.rec.set_active(false);
+        }
+        true
+    }
+    fn reset(&mut self, _: ControllerParams) {
+        self.timeout = AsyncResult::Empty;
+        self.start_time = 0;
+        self.elapsed = None;
+        self.finish();
+    }
+    fn accept_audio_message(&mut self, msg: &AudioThreadMessage, mut ctx: ControllerParams) -> bool {
+        if let AudioThreadMessage::CaptureRejected(ref c) = *msg {
+            if self.rd.as_ref().map(|rd| rd.rec.uuid() == c.uuid).unwrap_or(false) {
+                let msg = "The audio engine is already recording as many things as it can at once.";
+                ctx.change_state(PlaybackState::Errored(msg.into()));
+                return true;
+            }
+        }
+        false
+    }
+    fn duration_info(&self) -> Option<DurationInfoInt> {
+        if self.start_time == 0 {
+            return None;
+        }
+        let duration = match self.elapsed {
+            Some(e) => e,
+            None => {
+                let now = Sender::<()>::precise_time_ns();
+                let delta = if self.start_time > now { 0 } else { now - self.start_time };
+                DurationInfo::nanos_to_dur(delta)
+            }
+        };
+        Some(DurationInfoInt {
+            duration,
+            start_time: self.start_time,
+            est_duration: self.params.max_duration
+        })
+    }
+}
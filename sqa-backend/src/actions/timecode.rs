@@ -0,0 +1,421 @@
+//! Generating timecode: LTC audio on a mixer channel, or MTC out of the engine's MIDI port.
+
+use super::{ActionController, EditableAction, AsyncResult, PlaybackState, ControllerParams, ParameterError, DurationInfoInt, DurationInfo};
+use super::audio::db_lin;
+use state::Context;
+use errors::BackendResult;
+use std::time::Duration;
+use futures::Future;
+use uuid::Uuid;
+use sqa_engine::{Sender, BufferSender};
+use sqa_engine::param::Parameter;
+use sqa_engine::ltc::{LtcFrame, LtcEncoder};
+use timecode::{FrameRate, Timecode};
+
+/// How often we generate more timecode.
+const GENERATE_INTERVAL_MS: u64 = 100;
+/// How far ahead of time timecode is generated.
+const LOOKAHEAD_MS: u64 = 300;
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub enum TimecodeOutput {
+    /// LTC audio, on the given mixer channel, at the given volume (in dB).
+    Ltc { patch: Option<Uuid>, vol: f32 },
+    /// MIDI Time Code, out of the engine's MIDI port.
+    Mtc
+}
+impl Default for TimecodeOutput {
+    fn default() -> Self {
+        TimecodeOutput::Mtc
+    }
+}
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct TimecodeParams {
+    pub output: TimecodeOutput,
+    pub rate: FrameRate,
+    /// The timecode to start from.
+    pub start: Timecode,
+    /// How long to generate timecode for. If `None`, we carry on until stopped.
+    #[serde(default)]
+    pub duration: Option<Duration>
+}
+#[derive(Default)]
+pub struct Controller {
+    params: TimecodeParams,
+    timeout: AsyncResult<(), ::std::io::Error>,
+    /// When the timecode was (notionally) at `start`, taking into account time spent paused.
+    start_time: u64,
+    /// How far in we were when we were paused.
+    elapsed: Option<Duration>,
+    /// How much timecode we've generated so far: quarter frames for MTC, frames for LTC.
+    generated: u64,
+    ltc: Option<(BufferSender, LtcEncoder)>
+}
+impl Controller {
+    pub fn new() -> Self {
+        Default::default()
+    }
+    /// The timecode a given number of frames after the start.
+    fn timecode_at(&self, frames: u64) -> Timecode {
+        let rate = self.params.rate;
+        Timecode::from_frames(self.params.start.to_frames(rate) + frames, rate)
+    }
+    fn quarter_frame(&self, qf: u64) -> [u8; 2] {
+        let tc = self.timecode_at((qf / 8) * 2);
+        let piece = (qf % 8) as u8;
+        let val = match piece {
+            0 => tc.frames & 0x0F,
+            1 => tc.frames >> 4,
+            2 => tc.seconds & 0x0F,
+            3 => tc.seconds >> 4,
+            4 => tc.minutes & 0x0F,
+            5 => tc.minutes >> 4,
+            6 => tc.hours & 0x0F,
+            _ => (tc.hours >> 4) | (self.params.rate.mtc_code() << 1)
+        };
+        [0xF1, (piece << 4) | val]
+    }
+    /// The number of MTC quarter frames that are due by `elapsed` nanoseconds after the start,
+    /// i.e. the index of the first quarter frame that isn't.
+    fn quarter_frames_due(&self, elapsed: u64) -> u64 {
+        let qf_nanos = self.params.rate.frame_nanos() / 4.0;
+        (elapsed as f64 / qf_nanos).ceil() as u64
+    }
+    fn full_frame(&self, frames: u64) -> [u8; 10] {
+        let tc = self.timecode_at(frames);
+        let hr = tc.hours | (self.params.rate.mtc_code() << 5);
+        [0xF0, 0x7F, 0x7F, 0x01, 0x01, hr, tc.minutes, tc.seconds, tc.frames, 0xF7]
+    }
+    /// Generate timecode up to `LOOKAHEAD_MS` into the future.
+    fn generate(&mut self, ctx: &mut ControllerParams) -> BackendResult<()> {
+        let frame_nanos = self.params.rate.frame_nanos();
+        let end = self.params.duration.map(DurationInfo::dur_to_nanos);
+        if let TimecodeOutput::Mtc = self.params.output {
+            let now = Sender::<()>::precise_time_ns();
+            let mut upto = now.saturating_sub(self.start_time) + LOOKAHEAD_MS * 1_000_000;
+            if let Some(end) = end {
+                upto = ::std::cmp::min(upto, end);
+            }
+            loop {
+                let at = (self.generated as f64 * frame_nanos / 4.0) as u64;
+                if at >= upto {
+                    break;
+                }
+                let msg = self.quarter_frame(self.generated);
+                ctx.ctx.mixer.send_midi(self.start_time + at, ctx.uuid, &msg)?;
+                self.generated += 1;
+            }
+            return Ok(());
+        }
+        let mut buf = vec![];
+        let rate = self.params.rate;
+        loop {
+            if let Some(end) = end {
+                if (self.generated as f64 * frame_nanos) as u64 >= end {
+                    break;
+                }
+            }
+            let tc = self.timecode_at(self.generated);
+            let pushed = match self.ltc {
+                Some((ref mut sender, ref mut enc)) => {
+                    let lookahead = (sender.sample_rate * LOOKAHEAD_MS / 1000) as usize;
+                    if sender.buf.size() < lookahead {
+                        buf.clear();
+                        let bits = LtcFrame::bits_for(tc.hours, tc.minutes, tc.seconds, tc.frames, rate == FrameRate::Fps2997Drop);
+                        enc.encode(bits, &mut buf);
+                        for x in buf.iter() {
+                            sender.buf.try_push(*x);
+                        }
+                        true
+                    }
+                    else {
+                        false
+                    }
+                },
+                None => false
+            };
+            if !pushed {
+                break;
+            }
+            self.generated += 1;
+        }
+        Ok(())
+    }
+    fn schedule(&mut self, ctx: &mut ControllerParams) -> BackendResult<()> {
+        self.timeout = ctx.timeout_after(Duration::from_millis(GENERATE_INTERVAL_MS))?;
+        Ok(())
+    }
+}
+impl EditableAction for Controller {
+    type Parameters = TimecodeParams;
+    fn get_params(&self) -> &TimecodeParams {
+        &self.params
+    }
+    fn set_params(&mut self, params: TimecodeParams, _: ControllerParams) {
+        if let TimecodeOutput::Ltc { vol, .. } = params.output {
+            if let Some((ref mut sender, _)) = self.ltc {
+                sender.set_volume(Box::new(Parameter::Raw(db_lin(vol))));
+            }
+        }
+        self.params = params;
+    }
+}
+impl ActionController for Controller {
+    fn desc(&self, _: &Context) -> String {
+        let kind = match self.params.output {
+            TimecodeOutput::Ltc { .. } => "LTC",
+            TimecodeOutput::Mtc => "MTC"
+        };
+        format!("{} from {}", kind, self.params.start)
+    }
+    fn verify_params(&self, ctx: &Context) -> Vec<ParameterError> {
+        let mut ret = vec![];
+        if !self.params.start.is_valid(self.params.rate) {
+            ret.push(ParameterError {
+                name: "start".into(),
+                err: format!("{} isn't a valid timecode at this frame rate.", self.params.start)
+            });
+        }
+        if self.params.duration == Some(Duration::new(0, 0)) {
+            ret.push(ParameterError {
+                name: "duration".into(),
+                err: "The duration can't be zero.".into()
+            });
+        }
+        if let TimecodeOutput::Ltc { patch, .. } = self.params.output {
+            match patch {
+                Some(ch) => if ctx.mixer.obtain_channel(&ch).is_none() {
+                    ret.push(ParameterError {
+                        name: "output".into(),
+                        err: "The output channel does not exist.".into()
+                    });
+                },
+                None => ret.push(ParameterError {
+                    name: "output".into(),
+                    err: "LTC needs an output channel.".into()
+                })
+            }
+        }
+        ret
+    }
+    fn load(&mut self, mut ctx: ControllerParams) -> BackendResult<bool> {
+        self.generated = 0;
+        if let TimecodeOutput::Ltc { patch, vol } = self.params.output {
+            let ch = patch.and_then(|ch| ctx.ctx.mixer.obtain_channel(&ch))
+                .ok_or("The output channel mysteriously disappeared")?;
+            let sample_rate = ctx.ctx.mixer.sample_rate()?;
+            let mut sender = ctx.ctx.mixer.new_sender(sample_rate)?;
+            sender.set_output_patch(ch);
+            sender.set_volume(Box::new(Parameter::Raw(db_lin(vol))));
+            let enc = LtcEncoder::new(sample_rate, self.params.rate.fps());
+            self.ltc = Some((sender, enc));
+            self.generate(&mut ctx)?;
+        }
+        Ok(true)
+    }
+    fn execute(&mut self, time: u64, mut ctx: ControllerParams) -> BackendResult<bool> {
+        self.elapsed = None;
+        self.start_time = time;
+        if let Some((ref mut sender, _)) = self.ltc {
+            let start_time = ctx.ctx.mixer.compensated_start_time(time, sender.output_patch());
+            sender.set_start_time(start_time);
+            sender.set_active(true);
+        }
+        if let TimecodeOutput::Mtc = self.params.output {
+            /* Skip anything we're too late for, and tell receivers where we are. */
+            let qf_nanos = self.params.rate.frame_nanos() / 4.0;
+            let now = Sender::<()>::precise_time_ns();
+            if now > time {
+                let late = self.quarter_frames_due(now - time);
+                self.generated = ::std::cmp::max(self.generated, late);
+            }
+            let at = time + (self.generated as f64 * qf_nanos) as u64;
+            let msg = self.full_frame(self.generated / 4);
+            ctx.ctx.mixer.send_midi(at, ctx.uuid, &msg)?;
+        }
+        self.generate(&mut ctx)?;
+        self.schedule(&mut ctx)?;
+        Ok(false)
+    }
+    fn poll(&mut self, mut ctx: ControllerParams) -> bool {
+        let _ = self.timeout.poll();
+        if !self.timeout.is_complete() {
+            return true;
+        }
+        self.timeout = AsyncResult::Empty;
+        let now = Sender::<()>::precise_time_ns();
+        if let Some(end) = self.params.duration.map(DurationInfo::dur_to_nanos) {
+            if now >= self.start_time + end {
+                self.ltc = None;
+                self.start_time = 0;
+                ctx.change_state(PlaybackState::Inactive);
+                return false;
+            }
+        }
+        if let Err(e) = self.generate(&mut ctx).and_then(|_| self.schedule(&mut ctx)) {
+            ctx.change_state(PlaybackState::Errored(e.to_string()));
+            return false;
+        }
+        true
+    }
+    fn pause(&mut self, ctx: ControllerParams) -> bool {
+        if let Some(info) = self.duration_info() {
+            self.elapsed = Some(info.duration);
+        }
+        self.timeout = AsyncResult::Empty;
+        if let Some((ref mut sender, _)) = self.ltc {
+            sender.set_active(false);
+        }
+        if let TimecodeOutput::Mtc = self.params.output {
+            /* Take back anything we hadn't sent yet, so we can carry on from here later. */
+            ctx.ctx.mixer.cancel_midi(ctx.uuid);
+            let now = Sender::<()>::precise_time_ns();
+            self.generated = self.quarter_frames_due(now.saturating_sub(self.start_time));
+        }
+        true
+    }
+    fn reset(&mut self, ctx: ControllerParams) {
+        ctx.ctx.mixer.cancel_midi(ctx.uuid);
+        self.ltc = None;
+        self.timeout = AsyncResult::Empty;
+        self.start_time = 0;
+        self.elapsed = None;
+        self.generated = 0;
+    }
+    fn duration_info(&self) -> Option<DurationInfoInt> {
+        if self.start_time == 0 {
+            return None;
+        }
+        let duration = match self.elapsed {
+            Some(e) => e,
+            None => {
+                let now = Sender::<()>::precise_time_ns();
+                let delta = if self.start_time > now { 0 } else { now - self.start_time };
+                DurationInfo::nanos_to_dur(delta)
+            }
+        };
+        Some(DurationInfoInt {
+            duration,
+            start_time: self.start_time,
+            est_duration: self.params.duration
+        })
+    }
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use timecode::{TimecodeChaser, TimecodeConf, TimecodeSource};
+    use sqa_engine::midi::MidiEvent;
+    use sqa_engine::ltc::LtcDecoder;
+
+    fn controller(rate: FrameRate, start: Timecode) -> Controller {
+        let mut ret = Controller::new();
+        ret.params.rate = rate;
+        ret.params.start = start;
+        ret
+    }
+    fn mtc_chaser(rate: FrameRate) -> TimecodeChaser {
+        let mut ret = TimecodeChaser::new();
+        ret.set_conf(TimecodeConf { source: TimecodeSource::Mtc, rate, freewheel: Duration::new(0, 0) });
+        ret
+    }
+    /// Send quarter frame `qf` from `ctl` to `ch` at `time`, returning the chaser's position
+    /// (as a timecode) if this completed a sequence.
+    fn send_qf(ctl: &Controller, ch: &mut TimecodeChaser, qf: u64, time: u64) -> Option<Timecode> {
+        let ev = MidiEvent::new(time, Uuid::nil(), &ctl.quarter_frame(qf)).unwrap();
+        if ch.on_midi(&ev) {
+            ch.position(time).map(|p| Timecode::from_frames(p as u64, ctl.params.rate))
+        }
+        else {
+            None
+        }
+    }
+    #[test]
+    fn mtc_round_trip() {
+        /* Going over a minute boundary, which drop frame skips frames at. */
+        let start = Timecode::new(0, 10, 59, 20);
+        for &rate in [FrameRate::Fps24, FrameRate::Fps25, FrameRate::Fps2997Drop, FrameRate::Fps30].iter() {
+            let ctl = controller(rate, start);
+            let mut ch = mtc_chaser(rate);
+            let qf_nanos = rate.frame_nanos() / 4.0;
+            let mut positions = vec![];
+            for qf in 0..(8 * 20) {
+                if let Some(tc) = send_qf(&ctl, &mut ch, qf, (qf as f64 * qf_nanos) as u64) {
+                    positions.push(tc);
+                }
+            }
+            /* Each sequence describes the frame it started in; the chaser adds on the
+             * two frames it took to send. */
+            let expected = (0..20).map(|i| ctl.timecode_at(i * 2 + 2)).collect::<Vec<_>>();
+            assert_eq!(positions, expected, "{:?}", rate);
+        }
+    }
+    #[test]
+    fn mtc_full_frame() {
+        let ctl = controller(FrameRate::Fps25, Timecode::new(1, 2, 3, 4));
+        assert_eq!(ctl.full_frame(0), [0xF0, 0x7F, 0x7F, 0x01, 0x01, 0x21, 2, 3, 4, 0xF7]);
+        assert_eq!(ctl.full_frame(25), [0xF0, 0x7F, 0x7F, 0x01, 0x01, 0x21, 2, 4, 4, 0xF7]);
+        let ctl = controller(FrameRate::Fps2997Drop, Timecode::new(23, 59, 59, 29));
+        assert_eq!(ctl.full_frame(0), [0xF0, 0x7F, 0x7F, 0x01, 0x01, 0x57, 59, 59, 29, 0xF7]);
+        /* Wraps around at 24 hours. */
+        assert_eq!(ctl.full_frame(1), [0xF0, 0x7F, 0x7F, 0x01, 0x01, 0x40, 0, 0, 0, 0xF7]);
+    }
+    #[test]
+    fn ltc_round_trip() {
+        let rate = FrameRate::Fps2997Drop;
+        let start = Timecode::new(0, 10, 59, 20);
+        let ctl = controller(rate, start);
+        let mut enc = LtcEncoder::new(48000, rate.fps());
+        let mut dec = LtcDecoder::new(48000);
+        let mut samples = vec![];
+        for i in 0..30 {
+            let tc = ctl.timecode_at(i);
+            enc.encode(LtcFrame::bits_for(tc.hours, tc.minutes, tc.seconds, tc.frames, true), &mut samples);
+        }
+        let decoded = samples.into_iter()
+            .filter_map(|s| dec.feed(s))
+            .map(|bits| {
+                let fr = LtcFrame { time: 0, bits };
+                assert!(fr.drop_frame());
+                let (h, m, s, f) = fr.timecode();
+                Timecode::new(h, m, s, f)
+            })
+            .collect::<Vec<_>>();
+        assert!(decoded.len() >= 28, "only decoded {} frames", decoded.len());
+        let first = decoded[0].to_frames(rate) - start.to_frames(rate);
+        for (i, tc) in decoded.iter().enumerate() {
+            assert_eq!(*tc, ctl.timecode_at(first + i as u64));
+        }
+        assert!(decoded.contains(&Timecode::new(0, 11, 0, 2)));
+    }
+    #[test]
+    fn pause_resumes_from_the_right_quarter_frame() {
+        /* 25fps, so a quarter frame every 10ms. */
+        let rate = FrameRate::Fps25;
+        let ctl = controller(rate, Timecode::new(0, 0, 10, 0));
+        assert_eq!(ctl.quarter_frames_due(0), 0);
+        assert_eq!(ctl.quarter_frames_due(52_000_000), 6);
+        assert_eq!(ctl.quarter_frames_due(60_000_000), 6);
+        assert_eq!(ctl.quarter_frames_due(60_000_001), 7);
+        /* Pause 52ms in, having sent quarter frames 0 to 5, and resume 5 seconds later
+         * (with the start time moved on by the same amount, as `Action::execute` does). */
+        let qf_nanos = 10_000_000;
+        let paused_at = 52_000_000;
+        let pause_len = 5_000_000_000;
+        let mut ch = mtc_chaser(rate);
+        let resume_from = ctl.quarter_frames_due(paused_at);
+        for qf in 0..resume_from {
+            assert_eq!(send_qf(&ctl, &mut ch, qf, qf * qf_nanos), None);
+        }
+        assert!(resume_from * qf_nanos >= paused_at);
+        let mut positions = vec![];
+        for qf in resume_from..16 {
+            if let Some(tc) = send_qf(&ctl, &mut ch, qf, pause_len + qf * qf_nanos) {
+                positions.push((qf, tc));
+            }
+        }
+        /* The sequence we were halfway through when we paused is completed, and carries on
+         * as if nothing happened. */
+        assert_eq!(positions, vec![(7, ctl.timecode_at(2)), (15, ctl.timecode_at(4))]);
+    }
+}
@@ -0,0 +1,100 @@
+//! Waiting for a set amount of time.
+
+use super::{ActionController, EditableAction, AsyncResult, PlaybackState, ControllerParams, ParameterError, DurationInfoInt, DurationInfo};
+use state::Context;
+use errors::BackendResult;
+use std::time::Duration;
+use futures::Future;
+use sqa_engine::Sender;
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct WaitParams {
+    pub dur: Duration
+}
+#[derive(Default)]
+pub struct Controller {
+    params: WaitParams,
+    timeout: AsyncResult<(), ::std::io::Error>,
+    /// When the wait (notionally) started, taking into account time spent paused.
+    start_time: u64,
+    /// How long we'd waited for when we were paused.
+    elapsed: Option<Duration>
+}
+impl Controller {
+    pub fn new() -> Self {
+        Default::default()
+    }
+}
+impl EditableAction for Controller {
+    type Parameters = WaitParams;
+    fn get_params(&self) -> &WaitParams {
+        &self.params
+    }
+    fn set_params(&mut self, params: WaitParams, _: ControllerParams) {
+        self.params = params;
+    }
+}
+impl ActionController for Controller {
+    fn desc(&self, _: &Context) -> String {
+        let secs = self.params.dur.as_secs() as f64 + self.params.dur.subsec_nanos() as f64 / 1_000_000_000.0;
+        format!("Wait {:.02}s", secs)
+    }
+    fn verify_params(&self, _: &Context) -> Vec<ParameterError> {
+        vec![]
+    }
+    fn execute(&mut self, time: u64, mut ctx: ControllerParams) -> BackendResult<bool> {
+        self.elapsed = None;
+        self.start_time = time;
+        let end = time + DurationInfo::dur_to_nanos(self.params.dur);
+        let now = Sender::<()>::precise_time_ns();
+        if end <= now {
+            return Ok(true);
+        }
+        let dur = DurationInfo::nanos_to_dur(end - now);
+        trace!("waiting for {:?}", dur);
+        self.timeout = ctx.timeout_after(dur)?;
+        Ok(false)
+    }
+    fn poll(&mut self, mut ctx: ControllerParams) -> bool {
+        let _ = self.timeout.poll();
+        if self.timeout.is_complete() {
+            self.timeout = AsyncResult::Empty;
+            self.start_time = 0;
+            ctx.change_state(PlaybackState::Inactive);
+            false
+        }
+        else {
+            true
+        }
+    }
+    fn pause(&mut self, _: ControllerParams) -> bool {
+        if let Some(info) = self.duration_info() {
+            self.elapsed = Some(info.duration);
+        }
+        self.timeout = AsyncResult::Empty;
+        true
+    }
+    fn reset(&mut self, _: ControllerParams) {
+        self.timeout = AsyncResult::Empty;
+        self.start_time = 0;
+        self.elapsed = None;
+    }
+    fn duration_info(&self) -> Option<DurationInfoInt> {
+        if self.start_time == 0 {
+            return None;
+        }
+        let duration = match self.elapsed {
+            Some(e) => e,
+            None => {
+                let now = Sender::<()>::precise_time_ns();
+                let delta = if self.start_time > now { 0 } else { now - self.start_time };
+                DurationInfo::nanos_to_dur(delta)
+            }
+        };
+        Some(DurationInfoInt {
+            duration,
+            start_time: self.start_time,
+            est_duration: Some(self.params.dur)
+        })
+    }
+}
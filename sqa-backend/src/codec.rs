@@ -2,11 +2,13 @@ use std::net::SocketAddr;
 use tokio_core::net::{TcpStream, UdpCodec};
 use rosc::{decoder, encoder, OscMessage, OscPacket, OscType};
 use errors::*;
-use mixer::MixerConf;
+use mixer::{MixerConf, EngineState, EngineStats};
 use errors::BackendErrorKind::*;
 use undo::UndoState;
 use actions::{ActionParameters, ActionMetadata, OpaqueAction};
 use waveform::{WaveformRequest, WaveformReply};
+use triggers::TriggerConf;
+use timecode::TimecodeStatus;
 use std::collections::HashMap;
 use tokio_io::codec::length_delimited::Framed;
 use futures::{Stream, Sink, Async, AsyncSink};
@@ -59,16 +61,78 @@ pub enum Command {
     ResetAction { #[subst] uuid: Uuid },
     #[oscpath = "/action/{uuid}/pause"]
     PauseAction { #[subst] uuid: Uuid },
+    #[oscpath = "/action/{uuid}/fadeout"]
+    FadeOutAction { #[subst] uuid: Uuid },
     #[oscpath = "/action/{uuid}/reorder"]
     ReorderAction { #[subst] uuid: Uuid, #[ser] new_pos: usize },
+    #[oscpath = "/action/{uuid}/go"]
+    GoAction { #[subst] uuid: Uuid },
+    #[oscpath = "/cue/{number}"]
+    CueInfo { #[subst] number: String },
+    #[oscpath = "/cue/{number}/update"]
+    UpdateCueParams { #[subst] number: String, #[ser] params: ActionParameters, #[ser] desc: Option<String> },
+    #[oscpath = "/cue/{number}/updatemeta"]
+    UpdateCueMetadata { #[subst] number: String, #[ser] meta: ActionMetadata },
+    #[oscpath = "/cue/{number}/delete"]
+    DeleteCue { #[subst] number: String },
+    #[oscpath = "/cue/{number}/verify"]
+    VerifyCue { #[subst] number: String },
+    #[oscpath = "/cue/{number}/load"]
+    LoadCue { #[subst] number: String },
+    #[oscpath = "/cue/{number}/execute"]
+    ExecuteCue { #[subst] number: String },
+    #[oscpath = "/cue/{number}/reset"]
+    ResetCue { #[subst] number: String },
+    #[oscpath = "/cue/{number}/pause"]
+    PauseCue { #[subst] number: String },
+    #[oscpath = "/cue/{number}/fadeout"]
+    FadeOutCue { #[subst] number: String },
+    #[oscpath = "/cue/{number}/reorder"]
+    ReorderCue { #[subst] number: String, #[ser] new_pos: usize },
+    #[oscpath = "/cue/{number}/go"]
+    GoCue { #[subst] number: String },
+    #[oscpath = "/cues/next"]
+    NextCueNumber,
+    #[oscpath = "/cues/renumber"]
+    RenumberCues { #[verbatim = "float"] start: f32, #[verbatim = "float"] step: f32 },
+    #[oscpath = "/cues/set"]
+    SetCueNumbers { #[ser] numbers: Vec<(Uuid, Option<String>)> },
+    #[oscpath = "/go"]
+    Go,
+    #[oscpath = "/playhead"]
+    GetPlayhead,
+    #[oscpath = "/playhead/next"]
+    PlayheadNext,
+    #[oscpath = "/playhead/previous"]
+    PlayheadPrevious,
+    #[oscpath = "/playhead/set"]
+    SetPlayhead { #[ser] uuid: Option<Uuid> },
     #[oscpath = "/mixer/config"]
     GetMixerConf,
     #[oscpath = "/mixer/config/set"]
     SetMixerConf { #[ser] conf: MixerConf },
+    #[oscpath = "/mixer/engine"]
+    GetEngineState,
+    #[oscpath = "/mixer/stats"]
+    GetEngineStats,
+    #[oscpath = "/triggers"]
+    GetTriggerConf,
+    #[oscpath = "/triggers/set"]
+    SetTriggerConf { #[ser] conf: TriggerConf },
+    #[oscpath = "/timecode"]
+    GetTimecodeStatus,
     #[oscpath = "/system/save"]
     MakeSavefile { #[verbatim = "string"] save_to: String },
     #[oscpath = "/system/load"]
     LoadSavefile { #[verbatim = "string"] load_from: String, #[verbatim = "bool"] force: bool },
+    #[oscpath = "/system/panic"]
+    Panic,
+    #[oscpath = "/system/stopall"]
+    StopAll { #[verbatim = "float"] fade_time: f32 },
+    #[oscpath = "/system/pauseall"]
+    PauseAll,
+    #[oscpath = "/system/resumeall"]
+    ResumeAll,
     #[oscpath = "/system/undo"]
     Undo,
     #[oscpath = "/system/redo"]
@@ -109,8 +173,22 @@ pub enum Reply {
     ActionMaybePaused { #[subst] uuid: Uuid, #[ser] res: Result<(), String> },
     #[oscpath = "/reply/action/{uuid}/reset"]
     ActionReset { #[subst] uuid: Uuid, #[ser] res: Result<(), String> },
+    #[oscpath = "/reply/action/{uuid}/fadeout"]
+    ActionFadingOut { #[subst] uuid: Uuid, #[ser] res: Result<(), String> },
     #[oscpath = "/reply/action/{uuid}/reorder"]
     ActionReordered { #[subst] uuid: Uuid, #[ser] res: Result<(), String> },
+    #[oscpath = "/error/nosuchcue"]
+    NoSuchCue { #[verbatim = "string"] number: String },
+    #[oscpath = "/reply/cues/next"]
+    ReplyNextCueNumber { #[verbatim = "string"] number: String },
+    #[oscpath = "/reply/cues/set"]
+    CueNumbersSet { #[ser] res: Result<(), String> },
+    #[oscpath = "/reply/go"]
+    Went { #[ser] res: Result<Uuid, String> },
+    #[oscpath = "/reply/playhead/set"]
+    PlayheadSet { #[ser] res: Result<(), String> },
+    #[oscpath = "/update/playhead"]
+    UpdatePlayhead { #[ser] uuid: Option<Uuid> },
     #[oscpath = "/reply/mixer/config"]
     MixerConfSet { #[ser] res: Result<(), String> },
     #[oscpath = "/reply/actionlist"]
@@ -123,10 +201,26 @@ pub enum Reply {
     UpdateActionDeleted { #[subst] uuid: Uuid },
     #[oscpath = "/update/mixer/config"]
     UpdateMixerConf { #[ser] conf: MixerConf },
+    #[oscpath = "/update/mixer/engine"]
+    UpdateEngineState { #[ser] state: EngineState },
+    #[oscpath = "/reply/mixer/stats"]
+    EngineStatsRetrieved { #[ser] res: Result<EngineStats, String> },
+    #[oscpath = "/update/mixer/stats"]
+    UpdateEngineStats { #[ser] stats: EngineStats },
+    #[oscpath = "/reply/triggers/set"]
+    TriggerConfSet { #[ser] res: Result<(), String> },
+    #[oscpath = "/update/triggers"]
+    UpdateTriggerConf { #[ser] conf: TriggerConf },
+    #[oscpath = "/update/timecode"]
+    UpdateTimecodeStatus { #[ser] status: TimecodeStatus },
     #[oscpath = "/reply/system/save"]
     SavefileMade { #[ser] res: Result<(), String> },
     #[oscpath = "/reply/system/load"]
     SavefileLoaded { #[ser] res: Result<(), String> },
+    #[oscpath = "/reply/system/panic"]
+    Panicked,
+    #[oscpath = "/reply/system/stopall"]
+    StoppedAll { #[ser] res: Result<(), String> },
     #[oscpath = "/reply/system/undostate"]
     ReplyUndoState { #[ser] ctx: UndoState },
     #[oscpath = "/reply/waveform/{uuid}/generated"]
@@ -6,6 +6,40 @@ use save::Savefile;
 use waveform::WaveformContext;
 use state::{Context, CD};
 use errors::*;
+use std::time::Duration;
+
+/// The longest fade (in seconds) a stop-all command may ask for.
+const MAX_STOP_ALL_FADE_SECS: f32 = 3600.0;
+
+/// Turn a command that refers to an action by its cue number into the equivalent command that
+/// uses its UUID, and a request to renumber the cues into the list of new numbers.
+///
+/// This happens before anything else sees the command, so the undo machinery only ever has to
+/// deal with UUIDs. If the cue number isn't in use, it's returned as the error.
+pub fn resolve_cues(ctx: &Context, c: Command) -> Result<Command, String> {
+    use self::Command::*;
+    let uuid = |number: String| {
+        ctx.actions.by_number(&number).ok_or(number)
+    };
+    Ok(match c {
+        CueInfo { number } => ActionInfo { uuid: uuid(number)? },
+        UpdateCueParams { number, params, desc } => UpdateActionParams { uuid: uuid(number)?, params, desc },
+        UpdateCueMetadata { number, meta } => UpdateActionMetadata { uuid: uuid(number)?, meta },
+        DeleteCue { number } => DeleteAction { uuid: uuid(number)? },
+        VerifyCue { number } => VerifyAction { uuid: uuid(number)? },
+        LoadCue { number } => LoadAction { uuid: uuid(number)? },
+        ExecuteCue { number } => ExecuteAction { uuid: uuid(number)? },
+        ResetCue { number } => ResetAction { uuid: uuid(number)? },
+        PauseCue { number } => PauseAction { uuid: uuid(number)? },
+        FadeOutCue { number } => FadeOutAction { uuid: uuid(number)? },
+        ReorderCue { number, new_pos } => ReorderAction { uuid: uuid(number)?, new_pos },
+        GoCue { number } => GoAction { uuid: uuid(number)? },
+        RenumberCues { start, step } => SetCueNumbers {
+            numbers: ctx.actions.renumbered(start as f64, step as f64)
+        },
+        x => x
+    })
+}
 pub fn process_command(ctx: &mut Context, d: &mut CD, c: Command, rd: ReplyData) -> BackendResult<()> {
     use self::Command::*;
     use self::Reply::*;
@@ -75,9 +109,7 @@ pub fn process_command(ctx: &mut Context, d: &mut CD, c: Command, rd: ReplyData)
             d.respond(&rd, ActionParamsUpdated { uuid, res })?;
         },
         UpdateActionMetadata { uuid, meta } => {
-            let res = do_with_ctx!(ctx, uuid, |a: &mut Action| {
-                Ok(a.set_meta(meta))
-            });
+            let res = ctx.actions.set_meta(uuid, meta).map_err(|e| e.to_string());
             d.respond(&rd, ActionMetadataUpdated { uuid, res })?;
         },
         LoadAction { uuid } => {
@@ -87,6 +119,7 @@ pub fn process_command(ctx: &mut Context, d: &mut CD, c: Command, rd: ReplyData)
             d.respond(&rd, ActionLoaded { uuid, res })?;
         },
         ResetAction { uuid } => {
+            ctx.cancel_follows(uuid);
             let res = do_with_ctx!(ctx, uuid, |a: &mut Action| {
                 a.reset(ctx, &d.int_sender);
                 Ok(())
@@ -100,13 +133,49 @@ pub fn process_command(ctx: &mut Context, d: &mut CD, c: Command, rd: ReplyData)
             });
             d.respond(&rd, ActionMaybePaused { uuid, res })?;
         },
-        ExecuteAction { uuid } => {
+        FadeOutAction { uuid } => {
+            ctx.cancel_follows(uuid);
             let res = do_with_ctx!(ctx, uuid, |a: &mut Action| {
-                a.execute(::sqa_engine::Sender::<()>::precise_time_ns(), ctx, &d.int_sender)
-                    .map_err(|e| e.to_string())
+                a.stop_with_fade(None, ctx, &d.int_sender);
+                Ok(())
             });
+            d.respond(&rd, ActionFadingOut { uuid, res })?;
+        },
+        ExecuteAction { uuid } => {
+            let res = ctx.execute_action(d, uuid, ::sqa_engine::Sender::<()>::precise_time_ns());
             d.respond(&rd, ActionExecuted { uuid, res })?;
         },
+        GoAction { uuid } => {
+            let res = match ctx.actions.set_playhead(Some(uuid)) {
+                Ok(_) => ctx.go(d, ::sqa_engine::Sender::<()>::precise_time_ns()),
+                Err(e) => Err(e.to_string())
+            };
+            d.respond(&rd, Went { res })?;
+        },
+        Go => {
+            let res = ctx.go(d, ::sqa_engine::Sender::<()>::precise_time_ns());
+            d.respond(&rd, Went { res })?;
+        },
+        GetPlayhead => {
+            d.respond(&rd, UpdatePlayhead { uuid: ctx.actions.playhead() })?;
+        },
+        PlayheadNext => {
+            ctx.actions.playhead_next();
+        },
+        PlayheadPrevious => {
+            ctx.actions.playhead_previous();
+        },
+        SetPlayhead { uuid } => {
+            let res = ctx.actions.set_playhead(uuid).map_err(|e| e.to_string());
+            d.respond(&rd, PlayheadSet { res })?;
+        },
+        NextCueNumber => {
+            d.respond(&rd, ReplyNextCueNumber { number: ctx.actions.next_number() })?;
+        },
+        SetCueNumbers { numbers } => {
+            let res = ctx.actions.set_numbers(numbers).map_err(|e| e.to_string());
+            d.respond(&rd, CueNumbersSet { res })?;
+        },
         ActionList => {
             ctx.on_all_actions_changed(d);
         },
@@ -132,6 +201,24 @@ pub fn process_command(ctx: &mut Context, d: &mut CD, c: Command, rd: ReplyData)
                                          .map_err(|e| e.to_string())})?;
             d.respond(&rd, UpdateMixerConf { conf: ctx.mixer.obtain_config() })?;
         },
+        GetEngineState => {
+            d.respond(&rd, UpdateEngineState { state: ctx.mixer.state() })?;
+        },
+        GetTriggerConf => {
+            d.respond(&rd, UpdateTriggerConf { conf: ctx.triggers.obtain_config() })?;
+        },
+        SetTriggerConf { conf } => {
+            let res = ctx.triggers.process_config(conf, ctx.handle.as_ref());
+            d.respond(&rd, TriggerConfSet { res: res.map_err(|e| e.to_string()) })?;
+            d.broadcast(UpdateTriggerConf { conf: ctx.triggers.obtain_config() })?;
+        },
+        GetTimecodeStatus => {
+            d.respond(&rd, UpdateTimecodeStatus { status: ctx.triggers.timecode_status() })?;
+        },
+        GetEngineStats => {
+            let res = ctx.mixer.stats().map_err(|e| e.to_string());
+            d.respond(&rd, EngineStatsRetrieved { res })?;
+        },
         MakeSavefile { save_to } => {
             let res = Savefile::save_to_file(ctx, &save_to);
             d.respond(&rd, SavefileMade { res: res.map_err(|e| e.to_string()) })?;
@@ -143,6 +230,28 @@ pub fn process_command(ctx: &mut Context, d: &mut CD, c: Command, rd: ReplyData)
         GetUndoState => {
             d.respond(&rd, ReplyUndoState { ctx: ctx.undo.state() })?;
         },
+        Panic => {
+            ctx.panic(d);
+            d.respond(&rd, Panicked)?;
+        },
+        StopAll { fade_time } => {
+            /* `fade_time` is in seconds. */
+            let res = if !fade_time.is_finite() || fade_time > MAX_STOP_ALL_FADE_SECS {
+                Err(format!("The fade time must be a number of seconds, no more than {}.", MAX_STOP_ALL_FADE_SECS))
+            }
+            else {
+                let millis = (fade_time.max(0.0) * 1000.0) as u64;
+                ctx.stop_all_with_fade(d, Duration::from_millis(millis))
+                    .map_err(|e| e.to_string())
+            };
+            d.respond(&rd, StoppedAll { res })?;
+        },
+        PauseAll => {
+            ctx.pause_all(d);
+        },
+        ResumeAll => {
+            ctx.resume_all(d);
+        },
         Undo => {
             if let Some(cmd) = ctx.undo.undo() {
                 ctx.on_undo_changed(d);
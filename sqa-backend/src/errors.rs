@@ -13,6 +13,7 @@ error_chain! {
         RmpEncode(::rmp_serde::encode::Error);
         Io(::std::io::Error);
         StrParse(::std::string::ParseError);
+        Wav(::hound::Error);
     }
     errors {
         OSC(t: String) {
@@ -17,6 +17,8 @@ extern crate url;
 #[macro_use] extern crate log;
 extern crate fern;
 extern crate tokio_io;
+extern crate rand;
+extern crate hound;
 
 #[macro_use]
 pub mod action_manager;
@@ -30,6 +32,8 @@ pub mod errors;
 pub mod mixer;
 pub mod save;
 pub mod waveform;
+pub mod triggers;
+pub mod timecode;
 pub mod async;
 
 pub static VERSION: &str = env!("CARGO_PKG_VERSION");
@@ -52,6 +56,33 @@ mod jack {
         }
     }
 }
+/// Read the engine's capacity limits from the environment, falling back to the defaults
+/// for anything not specified, unparseable, or outside the range the engine can cope with.
+fn engine_config() -> sqa_engine::EngineConfig {
+    fn var(name: &str, default: usize, min: usize, max: usize) -> usize {
+        match ::std::env::var(name).ok().map(|x| x.parse::<usize>()) {
+            Some(Ok(v)) if v >= min && v <= max => v,
+            Some(Ok(v)) => {
+                warn!("ignoring out-of-range value for {}: {} (must be between {} and {})", name, v, min, max);
+                default
+            },
+            Some(Err(e)) => {
+                warn!("ignoring invalid value for {}: {}", name, e);
+                default
+            },
+            None => default
+        }
+    }
+    let def = sqa_engine::EngineConfig::default();
+    sqa_engine::EngineConfig {
+        max_players: var("SQA_MAX_PLAYERS", def.max_players, 1, 4096),
+        max_chans: var("SQA_MAX_CHANS", def.max_chans, 1, 1024),
+        stream_buffer_size: var("SQA_STREAM_BUFFER_SIZE", def.stream_buffer_size, 1024, 10_000_000),
+        max_midi_events: var("SQA_MAX_MIDI_EVENTS", def.max_midi_events, 1, 65536),
+        inputs: var("SQA_INPUTS", def.inputs, 0, 256),
+        max_captures: var("SQA_MAX_CAPTURES", def.max_captures, 0, 256)
+    }
+}
 pub fn main() {
     fern::Dispatch::new()
         .format(|out, message, record| {
@@ -69,7 +100,10 @@ pub fn main() {
     sqa_engine::sqa_jack::handler::set_logging_handler(jack::Logger);
     info!("[+] Initialising reactor...");
     let mut core = Core::new().unwrap();
-    let ctx = Context::new(core.remote());
+    let conf = engine_config();
+    info!("[+] Engine limits: {} players, {} channels, {} samples per stream buffer",
+          conf.max_players, conf.max_chans, conf.stream_buffer_size);
+    let ctx = Context::new(core.remote(), conf);
     let hdl = core.handle();
     let addr = "127.0.0.1:1234".parse().unwrap();
     let sock = UdpSocket::bind(&addr, &hdl).unwrap();
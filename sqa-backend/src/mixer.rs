@@ -1,11 +1,27 @@
 //! Module for keeping track of the SQA Engine.
 use uuid::Uuid;
-use sqa_engine::{EngineContext, BufferSender, sqa_jack};
+use sqa_engine::{EngineContext, EngineConfig, BufferSender, PlainSender, BufferRecorder, sqa_jack};
+use sqa_engine::midi::MidiEvent;
+use sqa_engine::param::{Parameter, FadeDetails};
 use std::collections::HashMap;
 use std::thread;
+use std::time::Duration;
+use sqa_engine::sync::AudioThreadMessage;
 use state::{ServerMessage, IntSender};
 use errors::*;
 
+/// Borrows the engine out of a `MixerContext`, bailing if JACK isn't running.
+///
+/// (This is a macro, not a method, so that the other fields can still be borrowed.)
+macro_rules! engine {
+    ($self:ident) => {
+        match $self.engine {
+            Some(ref mut ec) => ec,
+            None => bail!("The JACK server is not running ({}).", $self.disconnect_reason)
+        }
+    }
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Channel {
     pub name: String,
@@ -18,40 +34,137 @@ pub struct MixerConf {
     pub channels: Vec<Channel>,
     pub defs: Vec<Uuid>
 }
+/// Whether the backend currently has a working connection to JACK.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub enum EngineState {
+    /// The engine is connected, and processing audio.
+    Running,
+    /// The JACK server shut down (for the given reason). Reconnection attempts are being
+    /// made periodically.
+    Disconnected(String)
+}
+/// Buffer statistics for one `Player` in the audio thread.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct PlayerStats {
+    pub uuid: Uuid,
+    /// How many samples are in the player's buffer.
+    pub buffer_fill: usize,
+    /// How many samples the player's buffer can hold.
+    pub buffer_capacity: usize,
+    /// Whether the player is currently playing.
+    pub active: bool
+}
+/// A snapshot of the audio engine's health.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct EngineStats {
+    /// JACK's DSP load, as a percentage.
+    pub cpu_load: f32,
+    /// The JACK buffer size, in frames.
+    pub buffer_size: u32,
+    /// The JACK sample rate, in Hz.
+    pub sample_rate: u32,
+    /// How many xruns JACK has reported since the engine was (re)connected.
+    pub xruns: usize,
+    /// How many `Player`s the audio thread is processing.
+    pub num_senders: usize,
+    pub max_players: usize,
+    pub max_chans: usize,
+    pub players: Vec<PlayerStats>
+}
 pub struct MixerContext {
-    engine: EngineContext,
+    engine: Option<EngineContext>,
+    config: EngineConfig,
+    senders: HashMap<Uuid, PlainSender>,
+    disconnect_reason: String,
+    sender: Option<IntSender>,
     channels: HashMap<Uuid, Channel>,
     defs: Vec<Uuid>
 }
 
 impl MixerContext {
-    pub fn new() -> BackendResult<Self> {
-        let ec = EngineContext::new(Some("sqa-backend"))?;
+    pub fn new(config: EngineConfig) -> BackendResult<Self> {
+        let ec = EngineContext::new(Some("sqa-backend"), config)?;
         Ok(MixerContext {
-            engine: ec,
+            engine: Some(ec),
+            config: config,
+            senders: HashMap::new(),
+            disconnect_reason: String::new(),
+            sender: None,
             channels: HashMap::new(),
             defs: vec![]
         })
     }
+    pub fn state(&self) -> EngineState {
+        if self.engine.is_some() {
+            EngineState::Running
+        }
+        else {
+            EngineState::Disconnected(self.disconnect_reason.clone())
+        }
+    }
     pub fn start_messaging(&mut self, s: IntSender) {
-        let mut hdl = self.engine.get_handle().unwrap();
+        self.sender = Some(s.clone());
+        let mut hdl = match self.engine.as_mut().and_then(|ec| ec.get_handle()) {
+            Some(h) => h,
+            None => return
+        };
         thread::spawn(move || {
             loop {
                 let msg = hdl.recv();
+                let shutdown = if let AudioThreadMessage::Shutdown(_) = msg { true } else { false };
                 s.send(ServerMessage::Audio(msg)); // FIXME on failure?
+                if shutdown {
+                    debug!("engine shut down; messaging thread exiting");
+                    return;
+                }
             }
         });
     }
+    /// Called when the JACK server shuts down. Drops the (now useless) engine.
+    pub fn on_engine_shutdown(&mut self, reason: &str) {
+        self.engine = None;
+        self.senders.clear();
+        self.disconnect_reason = reason.into();
+    }
+    /// Attempt to reconnect to the JACK server, after it shut down.
+    ///
+    /// On success, all channels in the current configuration are recreated, and their
+    /// patches reconnected where possible.
+    pub fn reconnect(&mut self) -> BackendResult<()> {
+        if self.engine.is_some() {
+            return Ok(());
+        }
+        let mut ec = EngineContext::new(Some("sqa-backend"), self.config)?;
+        info!("reconnected to JACK; rebuilding channels");
+        for ch in self.channels.values_mut() {
+            ch.eid = ec.new_channel(&ch.name)?;
+            if let Some(ref patch) = ch.patch {
+                /* The device this channel was patched to might not be back yet. If it
+                 * isn't, reconnect_patches() will sort it out when it is. */
+                let res = ec.conn.get_port_by_name(patch)
+                    .and_then(|port| ec.conn.connect_ports(ec.chans[ch.eid].as_ref().unwrap(), &port));
+                if let Err(e) = res {
+                    warn!("couldn't restore patch of {} to {}: {}", ch.name, patch, e);
+                }
+            }
+        }
+        self.engine = Some(ec);
+        if let Some(s) = self.sender.clone() {
+            self.start_messaging(s);
+        }
+        Ok(())
+    }
     pub fn default_config(&mut self) -> BackendResult<()> {
-        for (i, port) in self.engine.conn
+        let engine = engine!(self);
+        for (i, port) in engine.conn
             .get_ports(None, None, Some(sqa_jack::PORT_IS_INPUT | sqa_jack::PORT_IS_PHYSICAL))?
             .into_iter()
             .enumerate() {
                 let name = format!("default-chan-{}", i);
-                let eid = self.engine.new_channel(&name)?;
+                let eid = engine.new_channel(&name)?;
                 let patch = port.get_name(false)?;
                 let uu = Uuid::new_v4();
-                self.engine.conn.connect_ports(self.engine.chans[eid].as_ref().unwrap(), &port)?;
+                engine.conn.connect_ports(engine.chans[eid].as_ref().unwrap(), &port)?;
                 self.channels.insert(uu, Channel {
                     name: name,
                     uuid: uu,
@@ -75,23 +188,167 @@ impl MixerContext {
     pub fn obtain_def(&self, idx: usize) -> Option<Uuid> {
         self.defs.get(idx).map(|x| *x)
     }
-    pub fn new_sender(&mut self, sample_rate: u64) -> BufferSender {
-        self.engine.new_sender(sample_rate)
+    pub fn stats(&self) -> BackendResult<EngineStats> {
+        let ec = match self.engine {
+            Some(ref ec) => ec,
+            None => bail!("The JACK server is not running ({}).", self.disconnect_reason)
+        };
+        let players = self.senders.values()
+            .map(|s| PlayerStats {
+                uuid: s.uuid(),
+                buffer_fill: s.buffer_fill(),
+                buffer_capacity: s.buffer_capacity(),
+                active: s.active()
+            })
+            .collect();
+        Ok(EngineStats {
+            cpu_load: ec.conn.cpu_load(),
+            buffer_size: ec.conn.buffer_size(),
+            sample_rate: ec.conn.sample_rate(),
+            xruns: ec.xrun_count(),
+            num_senders: ec.num_senders(),
+            max_players: ec.config().max_players,
+            max_chans: ec.config().max_chans,
+            players
+        })
+    }
+    /// Called when the audio thread stops processing a player, so we can stop tracking it.
+    pub fn on_player_removed(&mut self, uu: Uuid) {
+        self.senders.remove(&uu);
     }
-    pub fn new_senders(&mut self, n: usize, sample_rate: u64) -> Vec<BufferSender> {
+    /// Fade every sender that's playing down to silence over `dur`, starting now.
+    pub fn fade_all(&mut self, dur: Duration) {
+        let now = ::sqa_engine::Sender::<()>::precise_time_ns();
+        for s in self.senders.values_mut() {
+            if !s.active() {
+                continue;
+            }
+            /* Senders can share a master volume; that's fine, the fade just gets set twice. */
+            let vol = s.master_volume().get(now);
+            let mut fd = FadeDetails::new(vol, 0.0);
+            fd.set_duration(dur);
+            fd.start_from_time(now);
+            s.set_master_volume(Box::new(Parameter::LinearFade(fd)));
+        }
+    }
+    /// Stop every sender from playing, straight away.
+    pub fn silence_all(&mut self) {
+        for s in self.senders.values_mut() {
+            s.set_active(false);
+        }
+    }
+    /// Send a MIDI message out of the engine's MIDI port at a given time.
+    ///
+    /// `owner` can be passed to `cancel_midi()` to stop the message being sent, if it
+    /// hasn't been already.
+    pub fn send_midi(&mut self, time: u64, owner: Uuid, msg: &[u8]) -> BackendResult<()> {
+        let ev = MidiEvent::new(time, owner, msg)?;
+        engine!(self).send_midi(ev)?;
+        Ok(())
+    }
+    pub fn cancel_midi(&mut self, owner: Uuid) {
+        if let Some(ref mut ec) = self.engine {
+            ec.cancel_midi(owner);
+        }
+    }
+    /// Get the sample rate the engine is running at.
+    pub fn sample_rate(&mut self) -> BackendResult<u64> {
+        Ok(engine!(self).conn.sample_rate() as u64)
+    }
+    pub fn new_sender(&mut self, sample_rate: u64) -> BackendResult<BufferSender> {
+        let ret = engine!(self).new_sender(sample_rate);
+        self.senders.insert(ret.uuid(), ret.make_plain());
+        Ok(ret)
+    }
+    pub fn new_senders(&mut self, n: usize, sample_rate: u64) -> BackendResult<Vec<BufferSender>> {
+        let engine = engine!(self);
         let mut senders: Vec<BufferSender> = Vec::with_capacity(n);
-        let master = self.engine.new_sender(sample_rate);
+        let master = engine.new_sender(sample_rate);
         let others: Vec<_> = (0..(n-1))
-            .map(|_| self.engine.new_sender_with_master(&master))
+            .map(|_| engine.new_sender_with_master(&master))
             .collect();
         senders.push(master);
         senders.extend(others.into_iter());
-        senders
+        for s in senders.iter() {
+            self.senders.insert(s.uuid(), s.make_plain());
+        }
+        Ok(senders)
+    }
+    /// Make senders that play through input ports `inputs` (see
+    /// `EngineContext::new_input_sender()`), all sharing the first one's master volume.
+    pub fn new_input_senders(&mut self, inputs: &[usize]) -> BackendResult<Vec<BufferSender>> {
+        let engine = engine!(self);
+        let mut senders: Vec<BufferSender> = Vec::with_capacity(inputs.len());
+        for &input in inputs.iter() {
+            let s = match senders.get(0) {
+                Some(master) => engine.new_input_sender_with_master(input, master)?,
+                None => engine.new_input_sender(input)?
+            };
+            senders.push(s);
+        }
+        for s in senders.iter() {
+            self.senders.insert(s.uuid(), s.make_plain());
+        }
+        Ok(senders)
+    }
+    /// Make a recorder that records input ports `inputs` (see
+    /// `EngineContext::new_recorder()`).
+    pub fn new_recorder(&mut self, inputs: &[usize]) -> BackendResult<BufferRecorder> {
+        Ok(engine!(self).new_recorder(inputs)?)
+    }
+    /// Get the number of audio input ports the engine has.
+    pub fn num_inputs(&self) -> usize {
+        self.config.inputs
+    }
+    /// Get the start time a sender patched to channel `eid` should have if it's fired at
+    /// `time`, so that it's heard at the same time as senders on channels with more latency.
+    pub fn compensated_start_time(&self, time: u64, eid: usize) -> u64 {
+        match self.engine {
+            Some(ref ec) => ec.compensated_start_time(time, eid),
+            None => time
+        }
     }
     pub fn obtain_channel(&self, uu: &Uuid) -> Option<usize> {
         self.channels.get(uu).map(|x| x.eid)
     }
+    /// Re-establish the patches of any channels that are patched to the port called `name`.
+    ///
+    /// This is called when a port appears in the JACK graph, so that channels patched to a
+    /// device that went away come back when it does. Returns whether any connections
+    /// were made; channels that can't be reconnected are logged and skipped, and an error
+    /// is only returned if none of them could be.
+    pub fn reconnect_patches(&mut self, name: &str) -> BackendResult<bool> {
+        let mut ret = false;
+        let mut err = None;
+        let engine = match self.engine {
+            Some(ref mut ec) => ec,
+            None => return Ok(false)
+        };
+        for ch in self.channels.values() {
+            if ch.patch.as_ref().map(|x| x as &str) != Some(name) {
+                continue;
+            }
+            let res = engine.chans[ch.eid].ok_or_else(|| BackendError::from("Channel removed or logic error"))
+                .and_then(|ech| {
+                    let port = engine.conn.get_port_by_name(name)?;
+                    engine.conn.connect_ports(&ech, &port)?;
+                    Ok(())
+                });
+            match res {
+                Ok(_) => ret = true,
+                Err(e) => {
+                    warn!("failed to reconnect channel {} to {}: {}", ch.name, name, e);
+                    err = Some(e);
+                }
+            }
+        }
+        match err {
+            Some(e) if !ret => Err(e),
+            _ => Ok(ret)
+        }
+    }
     pub fn process_config(&mut self, conf: MixerConf) -> BackendResult<()> {
+        let engine = engine!(self);
         let mut touched = vec![];
         /* Trying to create two ports with the same name will make JACK unhappy.
          * To avoid this, we remove channels that don't match UUIDs but do match names
@@ -99,7 +356,7 @@ impl MixerContext {
         for (uu, ch) in &self.channels {
             for nch in &conf.channels {
                 if ch.name == nch.name && ch.uuid != nch.uuid {
-                    self.engine.remove_channel(ch.eid)?;
+                    engine.remove_channel(ch.eid)?;
                     touched.push(*uu);
                 }
             }
@@ -107,32 +364,32 @@ impl MixerContext {
         for mut ch in conf.channels {
             /* The following weird structure is brought to you by the borrow checker */
             let x = if let Some(ref mut c2) = self.channels.get_mut(&ch.uuid) {
-                let mut ech = self.engine.chans[c2.eid].ok_or("Channel removed or logic error")?;
+                let mut ech = engine.chans[c2.eid].ok_or("Channel removed or logic error")?;
                 if ch.name != c2.name {
                     ech.set_short_name(&ch.name)?;
                     c2.name = ch.name;
                 }
                 if ch.patch != c2.patch {
                     if let Some(ref old) = c2.patch {
-                        if let Ok(port) = self.engine.conn.get_port_by_name(&old) {
+                        if let Ok(port) = engine.conn.get_port_by_name(&old) {
                             let _ = /* We don't care if we can't disconnect the port: it may have gone
                                 away or something, and throwing an error here is unhelpful */
-                                self.engine.conn.disconnect_ports(&ech, &port);
+                                engine.conn.disconnect_ports(&ech, &port);
                         }
                     }
                     if let Some(new) = ch.patch {
-                        let port = self.engine.conn.get_port_by_name(&new)?;
-                        self.engine.conn.connect_ports(&ech, &port)?;
+                        let port = engine.conn.get_port_by_name(&new)?;
+                        engine.conn.connect_ports(&ech, &port)?;
                     }
                 }
                 touched.push(c2.uuid);
                 None
             }
             else {
-                ch.eid = self.engine.new_channel(&ch.name)?;
+                ch.eid = engine.new_channel(&ch.name)?;
                 if let Some(ref new) = ch.patch {
-                    let port = self.engine.conn.get_port_by_name(&new)?;
-                    self.engine.conn.connect_ports(self.engine.chans[ch.eid].as_ref().unwrap(), &port)?;
+                    let port = engine.conn.get_port_by_name(&new)?;
+                    engine.conn.connect_ports(engine.chans[ch.eid].as_ref().unwrap(), &port)?;
                 }
                 touched.push(ch.uuid);
                 Some(ch)
@@ -149,7 +406,7 @@ impl MixerContext {
         });
         for (uu, ch) in self.channels.iter_mut() {
             if !touched.contains(uu) {
-                self.engine.remove_channel(ch.eid)?;
+                engine.remove_channel(ch.eid)?;
             }
         }
         self.channels.retain(|uu, _| {
@@ -3,9 +3,11 @@
 use uuid::Uuid;
 use actions::{ActionMetadata, ActionParameters, OpaqueAction};
 use mixer::MixerConf;
+use triggers::TriggerConf;
 use std::collections::HashMap;
 use errors::*;
 use state::{CD, Context};
+use codec::Reply;
 use undo::UndoContext;
 use rmp_serde;
 use action_manager::ActionManager;
@@ -33,7 +35,9 @@ pub struct Savefile {
     actions: HashMap<Uuid, SavedAction>,
     order: Vec<Uuid>,
     mixer_conf: MixerConf,
-    undo: UndoContext
+    undo: UndoContext,
+    #[serde(default)]
+    triggers: TriggerConf
 }
 impl Savefile {
     pub fn save_to_file(ctx: &mut Context, path: &str) -> BackendResult<()> {
@@ -41,6 +45,7 @@ impl Savefile {
         let data = Self::new_from_ctx(ctx)?;
         let data = rmp_serde::to_vec(&data)?;
         file.write_all(&data)?;
+        ctx.savefile = Some(path.into());
         Ok(())
     }
     pub fn apply_from_file(ctx: &mut Context, path: &str, d: Option<&mut CD>, force: bool) -> BackendResult<()> {
@@ -49,6 +54,7 @@ impl Savefile {
         file.read_to_end(&mut buf)?;
         let mut data: Self = rmp_serde::from_slice(&buf)?;
         data.apply_to_ctx(ctx, d, force)?;
+        ctx.savefile = Some(path.into());
         Ok(())
     }
     pub fn new_from_ctx(ctx: &mut Context) -> BackendResult<Self> {
@@ -66,7 +72,8 @@ impl Savefile {
         let undo = ctx.undo.clone();
         let ver = SAVEFILE_VERSION.into();
         let order = ctx.actions.order().clone();
-        Ok(Self { ver, actions, mixer_conf, undo, order })
+        let triggers = ctx.triggers.obtain_config();
+        Ok(Self { ver, actions, mixer_conf, undo, order, triggers })
     }
     pub fn apply_to_ctx(&mut self, ctx: &mut Context, mut d: Option<&mut CD>, force: bool) -> BackendResult<()> {
         if self.ver != SAVEFILE_VERSION && !force {
@@ -78,7 +85,12 @@ impl Savefile {
             d.broadcast(rpl)?;
         }
         for (uu, sa) in self.actions.iter_mut() {
-            let res = ctx.create_action(&sa.typ, Some(sa.params.clone()), Some(sa.meta.clone()), Some(*uu));
+            let mut meta = sa.meta.clone();
+            if let Some(other) = meta.number.as_ref().and_then(|n| ctx.actions.by_number(n)) {
+                warn!("action {} has the same cue number as {}; removing its number", uu, other);
+                meta.number = None;
+            }
+            let res = ctx.create_action(&sa.typ, Some(sa.params.clone()), Some(meta), Some(*uu));
             if !force {
                 let _ = res?;
             }
@@ -91,8 +103,13 @@ impl Savefile {
         if !force {
             let _ = res?;
         }
+        let res = ctx.triggers.process_config(self.triggers.clone(), ctx.handle.as_ref());
+        if !force {
+            let _ = res?;
+        }
         ctx.undo = self.undo.clone();
         if let Some(ref mut d) = d {
+            d.broadcast(Reply::UpdateTriggerConf { conf: ctx.triggers.obtain_config() })?;
             ctx.on_all_actions_changed(d);
             ctx.on_undo_changed(d);
         }
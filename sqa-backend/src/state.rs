@@ -5,31 +5,79 @@ use uuid::Uuid;
 use handlers::{ConnHandler, ConnData, ReplyData};
 use codec::{Command, Reply};
 use std::collections::HashMap;
-use actions::{Action, ActionParameters, ActionMetadata, PlaybackState};
+use actions::{Action, ActionParameters, ActionMetadata, PlaybackState, DurationInfo};
+use actions::record;
 use sqa_engine::sync::{AudioThreadMessage};
+use sqa_engine::EngineConfig;
 use sqa_ffmpeg::MediaContext;
 use mixer::{MixerContext};
 use undo::{self, UndoContext};
 use waveform::WaveformContext;
+use triggers::TriggerContext;
 use errors::*;
 use handlers;
 use commands;
-use tokio_core::reactor::Handle;
+use tokio_core::reactor::{Handle, Timeout};
 use action_manager::ActionManager;
+use async::AsyncResult;
+use futures::Future;
+use std::time::Duration;
+use std::io;
+use std::path::PathBuf;
+
+/// How long to wait between attempts to reconnect to JACK, after it shuts down.
+const RECONNECT_INTERVAL_MS: u64 = 2000;
+/// How often engine statistics are broadcast to subscribers.
+const STATS_INTERVAL_MS: u64 = 1000;
 pub struct Context {
     pub remote: Remote,
     pub mixer: MixerContext,
     pub media: MediaContext,
     pub undo: UndoContext,
     pub waveform: WaveformContext,
+    pub triggers: TriggerContext,
     pub actions: ActionManager,
     pub sender: Option<IntSender>,
     pub handle: Option<Handle>,
+    pub reconnect_timeout: AsyncResult<(), io::Error>,
+    pub stats_timeout: AsyncResult<(), io::Error>,
+    /// When this completes, a stop-all with a fade (see `stop_all_with_fade()`) is done fading.
+    pub stop_all_timeout: AsyncResult<(), io::Error>,
+    pub follows: Vec<PendingFollow>,
+    /// What `pause_all()` paused, if it's been called and not resumed yet.
+    pub paused_all: Option<PausedAll>,
+    /// The path of the savefile last saved to or loaded from, if any.
+    pub savefile: Option<String>,
 }
 pub enum ServerMessage {
     Audio(AudioThreadMessage),
     ActionStateChange(Uuid, PlaybackState),
     ActionWarning(Uuid, String),
+    /// The action with the given UUID wants the next action in the order to be fired, its
+    /// post-wait after the given time. (See `FollowMode`.)
+    ActionFollow(Uuid, u64),
+    /// A recording action finished writing out the given file, and wants an audio action
+    /// made to play it back.
+    RecordingFinished(PathBuf),
+}
+/// A follow (see `ServerMessage::ActionFollow`) that is waiting out its post-wait.
+pub struct PendingFollow {
+    /// The action that asked to be followed.
+    from: Uuid,
+    /// The action to fire.
+    next: Uuid,
+    /// The time to fire it at.
+    time: u64,
+    timeout: AsyncResult<(), io::Error>
+}
+/// What `pause_all()` paused, so that `resume_all()` can carry on from where it left off.
+#[derive(Default)]
+pub struct PausedAll {
+    /// The actions that were paused.
+    actions: Vec<Uuid>,
+    /// Follows that were waiting out their post-wait: the action that asked to be followed,
+    /// the action to fire, and how long (in nanoseconds) was left to wait.
+    follows: Vec<(Uuid, Uuid, u64)>
 }
 
 pub type IntSender = handlers::IntSender<ServerMessage>;
@@ -44,7 +92,13 @@ impl ConnHandler for Context {
     }
     fn wakeup(&mut self, d: &mut CD) {
         WaveformContext::on_wakeup(self, d).unwrap();
-        ActionManager::on_wakeup(self, d)
+        self.poll_reconnect(d);
+        self.poll_stats(d);
+        self.poll_follows(d);
+        self.poll_stop_all(d);
+        TriggerContext::on_wakeup(self, d);
+        ActionManager::on_wakeup(self, d);
+        self.flush_changes(d);
     }
     fn internal(&mut self, d: &mut CD, m: ServerMessage) {
         match m {
@@ -52,12 +106,46 @@ impl ConnHandler for Context {
                 use self::AudioThreadMessage::*;
                 match msg {
                     PlayerAdded(uu) => debug!("player added: {}", uu),
-                    PlayerRejected(ref p) => warn!("player rejected: {}", p.uuid),
-                    PlayerRemoved(ref p) => debug!("player removed: {}", p.uuid),
+                    PlayerRejected(ref p) => {
+                        warn!("player rejected: {}", p.uuid);
+                        self.mixer.on_player_removed(p.uuid);
+                    },
+                    PlayerRemoved(ref p) => {
+                        debug!("player removed: {}", p.uuid);
+                        self.mixer.on_player_removed(p.uuid);
+                    },
+                    CaptureAdded(uu) => debug!("capture added: {}", uu),
+                    CaptureRejected(ref c) => warn!("capture rejected: {}", c.uuid),
+                    CaptureRemoved(ref c) => debug!("capture removed: {}", c.uuid),
                     PlayerInvalidOutpatch(uu) => trace!("player has invalid outpatch: {}", uu),
                     PlayerBufHalf(uu) => trace!("player buf at half: {}", uu),
                     PlayerBufEmpty(uu) => warn!("player buf at empty: {}", uu),
-                    Xrun => warn!("audio thread xrun")
+                    Xrun => warn!("audio thread xrun"),
+                    PortRegistered(ref name, reg) => {
+                        trace!("port {} {}", name, if reg { "registered" } else { "unregistered" });
+                        if reg {
+                            self.on_port_registered(d, name);
+                        }
+                    },
+                    PortConnected(ref from, ref to, conn) => {
+                        trace!("ports {} and {} {}", from, to, if conn { "connected" } else { "disconnected" });
+                    },
+                    ClientRegistered(ref name, reg) => {
+                        debug!("JACK client {} {}", name, if reg { "registered" } else { "unregistered" });
+                    },
+                    MidiReceived(ref ev) => {
+                        trace!("MIDI received: {:?}", ev);
+                        TriggerContext::on_midi(self, d, ev);
+                    },
+                    LtcReceived(ref fr) => {
+                        trace!("LTC received: {:?}", fr);
+                        TriggerContext::on_ltc(self, d, fr);
+                    },
+                    Shutdown(ref reason) => {
+                        error!("JACK server shut down: {}", reason);
+                        self.on_engine_shutdown(d, reason);
+                        return;
+                    }
                 }
                 for (uu, mut act) in self.actions.remove_all_for_editing().into_iter() {
                     act.accept_audio_message(self, &d.int_sender, &msg);
@@ -73,38 +161,53 @@ impl ConnHandler for Context {
                     self.actions.insert_after_editing(uu, act);
                 }
             },
+            ServerMessage::ActionFollow(uu, time) => {
+                self.on_action_follow(d, uu, time);
+            },
+            ServerMessage::RecordingFinished(path) => {
+                if let Err(e) = record::create_playback_action(self, &path) {
+                    warn!("failed to create audio action for recording: {}", e);
+                }
+                self.flush_changes(d);
+            },
             _ => {}
         }
     }
     fn external(&mut self, d: &mut CD, c: Command, rd: ReplyData) -> BackendResult<()> {
+        let c = match commands::resolve_cues(self, c) {
+            Ok(c) => c,
+            Err(number) => {
+                d.respond(&rd, Reply::NoSuchCue { number })?;
+                return Ok(());
+            }
+        };
         if let Some(ch) = undo::cmd_as_undoable(self, &c) {
             self.undo.register_change(ch);
             self.on_undo_changed(d);
         }
         let res = commands::process_command(self, d, c, rd);
-        for changed in self.actions.clear_changed() {
-            let _: BackendResult<()> = do_with_ctx!(self, changed, |a: &mut Action| {
-                self.on_action_changed(d, a);
-                Ok(())
-            }, false);
-        }
-        if self.actions.clear_order_changed() {
-            self.on_order_changed(d);
-        }
+        self.flush_changes(d);
         res
     }
 }
 impl Context {
-    pub fn new(r: Remote) -> Self {
+    pub fn new(r: Remote, conf: EngineConfig) -> Self {
         let mut ctx = Context {
             remote: r,
-            mixer: MixerContext::new().unwrap(),
+            mixer: MixerContext::new(conf).unwrap(),
             media: ::sqa_ffmpeg::init().unwrap(),
             undo: UndoContext::new(),
             actions: ActionManager::new(),
             waveform: WaveformContext::new(),
+            triggers: TriggerContext::new(),
             sender: None,
             handle: None,
+            reconnect_timeout: Default::default(),
+            stats_timeout: Default::default(),
+            stop_all_timeout: Default::default(),
+            follows: vec![],
+            paused_all: None,
+            savefile: None,
         };
         ctx.mixer.default_config().unwrap();
         ctx
@@ -128,6 +231,21 @@ impl Context {
         let order = self.actions.order().clone();
         Reply::ReplyActionList { list: resp, order }
     }
+    /// Broadcast anything about the actions that was marked as changed.
+    pub fn flush_changes(&mut self, d: &mut CD) {
+        for changed in self.actions.clear_changed() {
+            let _: BackendResult<()> = do_with_ctx!(self, changed, |a: &mut Action| {
+                self.on_action_changed(d, a);
+                Ok(())
+            }, false);
+        }
+        if self.actions.clear_order_changed() {
+            self.on_order_changed(d);
+        }
+        if self.actions.clear_playhead_changed() {
+            self.on_playhead_changed(d);
+        }
+    }
     pub fn on_action_changed(&mut self, d: &mut CD, action: &mut Action) {
         if let Ok(data) = action.get_data(self) {
             if let Err(e) = d.broadcast(Reply::UpdateActionInfo {
@@ -150,6 +268,298 @@ impl Context {
             error!("fixme: error in on_order_changed: {:?}", e);
         }
     }
+    fn on_action_follow(&mut self, d: &mut CD, uu: Uuid, time: u64) {
+        let postwait = match self.actions.get(&uu) {
+            Some(a) => a.meta().postwait,
+            None => return
+        };
+        let next = match self.actions.position_of(uu).and_then(|p| self.actions.order().get(p + 1)) {
+            Some(&n) => n,
+            None => return
+        };
+        /* Whatever we're about to fire shouldn't be standing by any more. */
+        if self.actions.playhead() == Some(next) {
+            self.actions.playhead_next();
+        }
+        let time = time + DurationInfo::dur_to_nanos(postwait);
+        self.schedule_follow(d, uu, next, time);
+    }
+    /// Fire `next` at `time` on behalf of `from`, waiting until then if it's in the future.
+    fn schedule_follow(&mut self, d: &mut CD, from: Uuid, next: Uuid, time: u64) {
+        let now = ::sqa_engine::Sender::<()>::precise_time_ns();
+        if time <= now {
+            self.fire_follow(d, next, time);
+            return;
+        }
+        let delta = time - now;
+        let timeout = Timeout::new(Duration::new(delta / 1_000_000_000, (delta % 1_000_000_000) as u32), self.handle.as_ref().unwrap())
+            .unwrap();
+        let mut pf = PendingFollow {
+            from, next, time,
+            timeout: AsyncResult::Waiting(Box::new(timeout))
+        };
+        let _ = pf.timeout.poll();
+        self.follows.push(pf);
+    }
+    fn poll_follows(&mut self, d: &mut CD) {
+        let mut ready = vec![];
+        for mut pf in ::std::mem::replace(&mut self.follows, vec![]) {
+            let _ = pf.timeout.poll();
+            if pf.timeout.is_complete() {
+                ready.push(pf);
+            }
+            else {
+                self.follows.push(pf);
+            }
+        }
+        for pf in ready {
+            self.fire_follow(d, pf.next, pf.time);
+        }
+    }
+    fn fire_follow(&mut self, d: &mut CD, uu: Uuid, time: u64) {
+        debug!("following on to action {}", uu);
+        if let Some(mut act) = self.actions.remove_for_editing(uu, false) {
+            if let Err(e) = act.execute(time, self, &d.int_sender) {
+                warn!("failed to follow on to action {}: {}", uu, e);
+            }
+            self.on_action_changed(d, &mut act);
+            self.actions.insert_after_editing(uu, act);
+        }
+    }
+    /// Execute the action that's standing by (at the given time), and move the playhead on.
+    pub fn go(&mut self, d: &mut CD, time: u64) -> Result<Uuid, String> {
+        match self.actions.playhead() {
+            Some(uuid) => {
+                self.actions.playhead_next();
+                self.execute_action(d, uuid, time).map(|_| uuid)
+            },
+            None => Err("Nothing is standing by.".into())
+        }
+    }
+    pub fn execute_action(&mut self, d: &mut CD, uuid: Uuid, time: u64) -> Result<(), String> {
+        do_with_ctx!(self, uuid, |a: &mut Action| {
+            a.execute(time, self, &d.int_sender)
+                .map_err(|e| e.to_string())
+        })
+    }
+    /// Stop (reset) an action, cancelling anything it would have caused to follow on.
+    pub fn stop_action(&mut self, d: &mut CD, uuid: Uuid) -> Result<(), String> {
+        self.cancel_follows(uuid);
+        do_with_ctx!(self, uuid, |a: &mut Action| {
+            a.reset(self, &d.int_sender);
+            Ok(())
+        })
+    }
+    /// Stop every action that's running, paused, loading or errored, and cancel all pending
+    /// follows.
+    pub fn stop_all(&mut self, d: &mut CD) {
+        self.follows.clear();
+        self.paused_all = None;
+        self.stop_all_timeout = AsyncResult::Empty;
+        for uu in self.actions.action_list() {
+            let running = match self.actions.get(&uu).map(|a| a.state()) {
+                Some(&PlaybackState::Active(_)) |
+                Some(&PlaybackState::Paused(_)) |
+                Some(&PlaybackState::Loading) |
+                Some(&PlaybackState::Errored(_)) => true,
+                _ => false
+            };
+            if running {
+                let _ = self.stop_action(d, uu);
+            }
+        }
+    }
+    /// Fade every sender out over `dur`, then stop every action (as `stop_all()` does).
+    ///
+    /// Follows are cancelled straight away, so nothing new starts during the fade. Fade
+    /// actions are stopped too, so that they don't take over from it.
+    pub fn stop_all_with_fade(&mut self, d: &mut CD, dur: Duration) -> BackendResult<()> {
+        if dur == Duration::new(0, 0) {
+            self.stop_all(d);
+            return Ok(());
+        }
+        self.follows.clear();
+        self.paused_all = None;
+        for uu in self.actions.action_list() {
+            let fading = match self.actions.get(&uu) {
+                Some(a) if a.is_fade() => match *a.state() {
+                    PlaybackState::Active(_) | PlaybackState::Paused(_) => true,
+                    _ => false
+                },
+                _ => false
+            };
+            if fading {
+                let _ = self.stop_action(d, uu);
+            }
+        }
+        self.mixer.fade_all(dur);
+        let timeout = Timeout::new(dur, self.handle.as_ref().unwrap())?;
+        self.stop_all_timeout = AsyncResult::Waiting(Box::new(timeout));
+        let _ = self.stop_all_timeout.poll();
+        Ok(())
+    }
+    fn poll_stop_all(&mut self, d: &mut CD) {
+        let _ = self.stop_all_timeout.poll();
+        if self.stop_all_timeout.is_complete() {
+            self.stop_all(d);
+        }
+    }
+    /// Stop everything, right now: silence every sender, then stop every action.
+    pub fn panic(&mut self, d: &mut CD) {
+        warn!("panic!");
+        self.mixer.silence_all();
+        self.stop_all(d);
+    }
+    /// Cancel any follows the given action asked for that haven't fired yet.
+    pub fn cancel_follows(&mut self, uu: Uuid) {
+        self.follows.retain(|pf| pf.from != uu);
+        if let Some(ref mut pa) = self.paused_all {
+            pa.follows.retain(|&(from, _, _)| from != uu);
+        }
+    }
+    /// Pause every active action, and hold any follows that are waiting, remembering what
+    /// was paused so that `resume_all()` can restart it.
+    ///
+    /// Calling this again before resuming pauses anything that's started since.
+    pub fn pause_all(&mut self, d: &mut CD) {
+        let now = ::sqa_engine::Sender::<()>::precise_time_ns();
+        let mut pa = self.paused_all.take().unwrap_or_default();
+        for pf in ::std::mem::replace(&mut self.follows, vec![]) {
+            pa.follows.push((pf.from, pf.next, pf.time.saturating_sub(now)));
+        }
+        for uu in self.actions.action_list() {
+            let active = match self.actions.get(&uu).map(|a| a.state()) {
+                Some(&PlaybackState::Active(_)) => true,
+                _ => false
+            };
+            if !active {
+                continue;
+            }
+            let res: Result<bool, String> = do_with_ctx!(self, uu, |a: &mut Action| {
+                a.pause(self, &d.int_sender);
+                Ok(if let PlaybackState::Paused(_) = *a.state() { true } else { false })
+            });
+            match res {
+                Ok(true) => pa.actions.push(uu),
+                Ok(false) => warn!("action {} couldn't be paused", uu),
+                Err(e) => warn!("failed to pause action {}: {}", uu, e)
+            }
+        }
+        debug!("paused {} actions and {} follows", pa.actions.len(), pa.follows.len());
+        self.paused_all = Some(pa);
+    }
+    /// Resume everything `pause_all()` paused.
+    ///
+    /// Everything is resumed with the same timestamp, so things stay in time with one another.
+    pub fn resume_all(&mut self, d: &mut CD) {
+        let pa = match self.paused_all.take() {
+            Some(pa) => pa,
+            None => return
+        };
+        let now = ::sqa_engine::Sender::<()>::precise_time_ns();
+        for uu in pa.actions {
+            /* Anything that's been stopped or resumed in the meantime is left alone. */
+            let paused = match self.actions.get(&uu).map(|a| a.state()) {
+                Some(&PlaybackState::Paused(_)) => true,
+                _ => false
+            };
+            if paused {
+                if let Err(e) = self.execute_action(d, uu, now) {
+                    warn!("failed to resume action {}: {}", uu, e);
+                }
+            }
+        }
+        for (from, next, left) in pa.follows {
+            self.schedule_follow(d, from, next, now + left);
+        }
+    }
+    pub fn on_playhead_changed(&mut self, d: &mut CD) {
+        let uuid = self.actions.playhead();
+        if let Err(e) = d.broadcast(Reply::UpdatePlayhead { uuid }) {
+            error!("fixme: error in on_playhead_changed: {:?}", e);
+        }
+    }
+    pub fn on_port_registered(&mut self, d: &mut CD, name: &str) {
+        match self.mixer.reconnect_patches(name) {
+            Ok(true) => {
+                info!("port {} reappeared; patches reconnected", name);
+                let conf = self.mixer.obtain_config();
+                if let Err(e) = d.broadcast(Reply::UpdateMixerConf { conf }) {
+                    error!("fixme: error in on_port_registered: {:?}", e);
+                }
+            },
+            Ok(false) => {},
+            Err(e) => warn!("failed to reconnect patches to port {}: {}", name, e)
+        }
+    }
+    pub fn on_engine_shutdown(&mut self, d: &mut CD, reason: &str) {
+        self.mixer.on_engine_shutdown(reason);
+        for (uu, mut act) in self.actions.remove_all_for_editing().into_iter() {
+            act.on_engine_shutdown(self, &d.int_sender);
+            self.on_action_changed(d, &mut act);
+            self.actions.insert_after_editing(uu, act);
+        }
+        self.on_engine_state_changed(d);
+        self.schedule_reconnect();
+    }
+    fn schedule_reconnect(&mut self) {
+        let timeout = Timeout::new(Duration::from_millis(RECONNECT_INTERVAL_MS), self.handle.as_ref().unwrap())
+            .unwrap();
+        self.reconnect_timeout = AsyncResult::Waiting(Box::new(timeout));
+        let _ = self.reconnect_timeout.poll();
+    }
+    fn poll_reconnect(&mut self, d: &mut CD) {
+        let _ = self.reconnect_timeout.poll();
+        if !self.reconnect_timeout.is_complete() {
+            return;
+        }
+        self.reconnect_timeout = AsyncResult::Empty;
+        match self.mixer.reconnect() {
+            Ok(_) => {
+                info!("audio engine reconnected");
+                self.on_engine_state_changed(d);
+                let conf = self.mixer.obtain_config();
+                if let Err(e) = d.broadcast(Reply::UpdateMixerConf { conf }) {
+                    error!("fixme: error in poll_reconnect: {:?}", e);
+                }
+                self.on_all_actions_changed(d);
+            },
+            Err(e) => {
+                debug!("failed to reconnect to JACK: {}", e);
+                self.schedule_reconnect();
+            }
+        }
+    }
+    fn schedule_stats(&mut self) {
+        let timeout = Timeout::new(Duration::from_millis(STATS_INTERVAL_MS), self.handle.as_ref().unwrap())
+            .unwrap();
+        self.stats_timeout = AsyncResult::Waiting(Box::new(timeout));
+        let _ = self.stats_timeout.poll();
+    }
+    fn poll_stats(&mut self, d: &mut CD) {
+        if self.stats_timeout.is_empty() {
+            /* init() isn't run inside a task, so we start the timer on the first wakeup. */
+            self.schedule_stats();
+            return;
+        }
+        let _ = self.stats_timeout.poll();
+        if !self.stats_timeout.is_complete() {
+            return;
+        }
+        /* No point broadcasting stats for an engine that isn't there. */
+        if let Ok(stats) = self.mixer.stats() {
+            if let Err(e) = d.broadcast(Reply::UpdateEngineStats { stats }) {
+                error!("fixme: error in poll_stats: {:?}", e);
+            }
+        }
+        self.schedule_stats();
+    }
+    pub fn on_engine_state_changed(&mut self, d: &mut CD) {
+        let state = self.mixer.state();
+        if let Err(e) = d.broadcast(Reply::UpdateEngineState { state }) {
+            error!("fixme: error in on_engine_state_changed: {:?}", e);
+        }
+    }
     pub fn on_undo_changed(&mut self, d: &mut CD) {
         let ctx = self.undo.state();
         if let Err(e) = d.broadcast(Reply::ReplyUndoState { ctx }) {
@@ -160,6 +570,15 @@ impl Context {
         let mut act = match &*ty {
             "audio" => Action::audio(),
             "fade" => Action::fade(),
+            "group" => Action::group(),
+            "control" => Action::control(),
+            "wait" => Action::wait(),
+            "osc" => Action::osc(),
+            "midi" => Action::midi(),
+            "timecode" => Action::timecode(),
+            "process" => Action::process(),
+            "live" => Action::live(),
+            "record" => Action::record(),
             x => bail!("Unknown action type: {}", x)
         };
         if let Some(uu) = old_uu {
@@ -170,8 +589,9 @@ impl Context {
         if let Some(uu) = old_uu {
             act.set_uuid(uu);
         }
-        if let Some(ref met) = met {
-            act.set_meta(met.clone());
+        if let Some(mut met) = met {
+            self.actions.check_meta(act.uuid(), &mut met)?;
+            act.set_meta(met);
         }
         let uu = act.uuid();
         if let Some(ref pars) = pars {
@@ -0,0 +1,444 @@
+//! Chasing incoming timecode (MIDI Time Code or LTC), so that things can happen at given
+//! timecode positions.
+
+use sqa_engine::midi::MidiEvent;
+use sqa_engine::ltc::LtcFrame;
+use sqa_engine::ONE_SECOND_IN_NANOSECONDS;
+use std::time::Duration;
+use std::fmt;
+
+/// How many frames' worth of time can go by without any timecode arriving before we
+/// consider it to have dropped out.
+const DROPOUT_FRAMES: f64 = 4.0;
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FrameRate {
+    Fps24,
+    Fps25,
+    /// 29.97 frames per second, drop frame.
+    Fps2997Drop,
+    Fps30
+}
+impl Default for FrameRate {
+    fn default() -> Self {
+        FrameRate::Fps25
+    }
+}
+impl FrameRate {
+    /// The number of frames labelled in each second of timecode.
+    pub fn nominal(&self) -> u64 {
+        use self::FrameRate::*;
+        match *self {
+            Fps24 => 24,
+            Fps25 => 25,
+            Fps2997Drop | Fps30 => 30
+        }
+    }
+    /// The actual number of frames per second.
+    pub fn fps(&self) -> f64 {
+        use self::FrameRate::*;
+        match *self {
+            Fps24 => 24.0,
+            Fps25 => 25.0,
+            Fps2997Drop => 30000.0 / 1001.0,
+            Fps30 => 30.0
+        }
+    }
+    /// The length of one frame, in nanoseconds.
+    pub fn frame_nanos(&self) -> f64 {
+        ONE_SECOND_IN_NANOSECONDS as f64 / self.fps()
+    }
+    /// The rate code used in MIDI Time Code.
+    pub fn mtc_code(&self) -> u8 {
+        use self::FrameRate::*;
+        match *self {
+            Fps24 => 0,
+            Fps25 => 1,
+            Fps2997Drop => 2,
+            Fps30 => 3
+        }
+    }
+}
+/// A SMPTE timecode position.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub struct Timecode {
+    pub hours: u8,
+    pub minutes: u8,
+    pub seconds: u8,
+    pub frames: u8
+}
+impl fmt::Display for Timecode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:02}:{:02}:{:02}:{:02}", self.hours, self.minutes, self.seconds, self.frames)
+    }
+}
+impl Timecode {
+    pub fn new(hours: u8, minutes: u8, seconds: u8, frames: u8) -> Self {
+        Timecode { hours, minutes, seconds, frames }
+    }
+    /// Whether this is a timecode that can actually occur at the given frame rate.
+    pub fn is_valid(&self, rate: FrameRate) -> bool {
+        if self.hours >= 24 || self.minutes >= 60 || self.seconds >= 60 {
+            return false;
+        }
+        if self.frames as u64 >= rate.nominal() {
+            return false;
+        }
+        if rate == FrameRate::Fps2997Drop {
+            /* Frames 0 and 1 are dropped at the start of every minute, except every tenth. */
+            return !(self.seconds == 0 && self.frames < 2 && self.minutes % 10 != 0);
+        }
+        true
+    }
+    /// Convert to a number of frames since 00:00:00:00.
+    pub fn to_frames(&self, rate: FrameRate) -> u64 {
+        let mins = self.hours as u64 * 60 + self.minutes as u64;
+        let mut ret = (mins * 60 + self.seconds as u64) * rate.nominal() + self.frames as u64;
+        if rate == FrameRate::Fps2997Drop {
+            ret -= 2 * (mins - mins / 10);
+        }
+        ret
+    }
+    /// Convert from a number of frames since 00:00:00:00, wrapping around after 24 hours.
+    pub fn from_frames(mut frames: u64, rate: FrameRate) -> Self {
+        if rate == FrameRate::Fps2997Drop {
+            /* There are 17982 frames in every ten minutes, and 1798 in every minute
+             * apart from the first. */
+            let (tens, rem) = (frames / 17982, frames % 17982);
+            frames += 18 * tens;
+            if rem > 1 {
+                frames += 2 * ((rem - 2) / 1798);
+            }
+        }
+        let n = rate.nominal();
+        Timecode {
+            hours: ((frames / (n * 3600)) % 24) as u8,
+            minutes: ((frames / (n * 60)) % 60) as u8,
+            seconds: ((frames / n) % 60) as u8,
+            frames: (frames % n) as u8
+        }
+    }
+}
+/// Where to get timecode from.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TimecodeSource {
+    /// Don't chase timecode at all.
+    Off,
+    /// MIDI Time Code, on the engine's MIDI input.
+    Mtc,
+    /// Linear timecode, on the engine's LTC audio input.
+    Ltc
+}
+impl Default for TimecodeSource {
+    fn default() -> Self {
+        TimecodeSource::Off
+    }
+}
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+pub struct TimecodeConf {
+    pub source: TimecodeSource,
+    /// The frame rate incoming timecode (and timecode triggers) are interpreted at.
+    pub rate: FrameRate,
+    /// How long to carry on running (freewheel) after incoming timecode drops out.
+    ///
+    /// If this is zero, we stop chasing as soon as timecode stops arriving.
+    pub freewheel: Duration
+}
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TimecodeState {
+    /// No timecode is being received.
+    Stopped,
+    /// Timecode is being received, and we're following it.
+    Locked,
+    /// Timecode dropped out recently, and we're carrying on without it.
+    Freewheeling
+}
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct TimecodeStatus {
+    pub state: TimecodeState,
+    pub position: Option<Timecode>
+}
+/// The last position we got from incoming timecode.
+struct Lock {
+    /// The position, in frames...
+    frames: u64,
+    /// ...and the time it was at that position.
+    time: u64
+}
+pub struct TimecodeChaser {
+    conf: TimecodeConf,
+    lock: Option<Lock>,
+    /// Quarter frame MTC messages received so far.
+    qf: [u8; 8],
+    /// Which of the quarter frames in `qf` we've got (one bit per piece).
+    qf_seen: u8
+}
+impl TimecodeChaser {
+    pub fn new() -> Self {
+        TimecodeChaser {
+            conf: Default::default(),
+            lock: None,
+            qf: [0; 8],
+            qf_seen: 0
+        }
+    }
+    pub fn conf(&self) -> &TimecodeConf {
+        &self.conf
+    }
+    pub fn set_conf(&mut self, conf: TimecodeConf) {
+        if conf != self.conf {
+            self.lock = None;
+            self.qf_seen = 0;
+        }
+        self.conf = conf;
+    }
+    fn update(&mut self, tc: Timecode, ahead: u64, time: u64) -> bool {
+        if !tc.is_valid(self.conf.rate) {
+            debug!("ignoring invalid timecode {}", tc);
+            return false;
+        }
+        let frames = tc.to_frames(self.conf.rate) + ahead;
+        self.lock = Some(Lock { frames, time });
+        true
+    }
+    /// Handle a MIDI message received on the engine's MIDI input, returning whether our
+    /// position changed.
+    pub fn on_midi(&mut self, ev: &MidiEvent) -> bool {
+        if self.conf.source != TimecodeSource::Mtc {
+            return false;
+        }
+        let data = ev.data();
+        if data.len() == 2 && data[0] == 0xF1 {
+            let (piece, val) = ((data[1] >> 4) & 0x07, data[1] & 0x0F);
+            if piece == 0 {
+                self.qf_seen = 0;
+            }
+            self.qf[piece as usize] = val;
+            self.qf_seen |= 1 << piece;
+            if piece == 7 && self.qf_seen == 0xFF {
+                let qf = self.qf;
+                let tc = Timecode {
+                    hours: qf[6] | ((qf[7] & 0x01) << 4),
+                    minutes: qf[4] | ((qf[5] & 0x03) << 4),
+                    seconds: qf[2] | ((qf[3] & 0x03) << 4),
+                    frames: qf[0] | ((qf[1] & 0x01) << 4)
+                };
+                /* The quarter frames describe the frame at which the first of them was
+                 * sent; it's taken eight of them (two frames) to get to now. */
+                return self.update(tc, 2, ev.time);
+            }
+        }
+        else if data.len() == 10 && data[0] == 0xF0 && data[1] == 0x7F && data[3] == 0x01 && data[4] == 0x01 {
+            /* A full frame message means the sender has located somewhere (and isn't
+             * running), so throw away anything we'd half-assembled. */
+            self.qf_seen = 0;
+            debug!("MTC full frame: {:02}:{:02}:{:02}:{:02}", data[5] & 0x1F, data[6], data[7], data[8]);
+        }
+        false
+    }
+    /// Handle a frame of LTC, returning whether our position changed.
+    pub fn on_ltc(&mut self, fr: &LtcFrame) -> bool {
+        if self.conf.source != TimecodeSource::Ltc {
+            return false;
+        }
+        let (h, m, s, f) = fr.timecode();
+        /* By the time a frame has been received in full, the next one is starting. */
+        self.update(Timecode::new(h, m, s, f), 1, fr.time)
+    }
+    /// Work out what state we're in at the given time, forgetting our position if
+    /// we've stopped.
+    pub fn state(&mut self, now: u64) -> TimecodeState {
+        let since = match self.lock {
+            Some(ref l) => now.saturating_sub(l.time) as f64,
+            None => return TimecodeState::Stopped
+        };
+        let dropout = DROPOUT_FRAMES * self.conf.rate.frame_nanos();
+        let freewheel = self.conf.freewheel.as_secs() as f64 * ONE_SECOND_IN_NANOSECONDS as f64
+            + self.conf.freewheel.subsec_nanos() as f64;
+        if since <= dropout {
+            TimecodeState::Locked
+        }
+        else if since <= dropout + freewheel {
+            TimecodeState::Freewheeling
+        }
+        else {
+            self.lock = None;
+            TimecodeState::Stopped
+        }
+    }
+    /// Our position at the given time, in (fractional) frames.
+    pub fn position(&self, now: u64) -> Option<f64> {
+        self.lock.as_ref().map(|l| {
+            let delta = now as f64 - l.time as f64;
+            l.frames as f64 + delta / self.conf.rate.frame_nanos()
+        })
+    }
+    /// The time at which we'll reach (or reached) the given position, in frames.
+    pub fn time_of(&self, frames: u64) -> Option<u64> {
+        self.lock.as_ref().map(|l| {
+            let delta = (frames as f64 - l.frames as f64) * self.conf.rate.frame_nanos();
+            (l.time as f64 + delta).max(0.0) as u64
+        })
+    }
+    pub fn status(&mut self, now: u64) -> TimecodeStatus {
+        let state = self.state(now);
+        let rate = self.conf.rate;
+        let position = self.position(now)
+            .map(|p| Timecode::from_frames(p.max(0.0) as u64, rate));
+        TimecodeStatus { state, position }
+    }
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use uuid::Uuid;
+
+    fn tc(hours: u8, minutes: u8, seconds: u8, frames: u8) -> Timecode {
+        Timecode::new(hours, minutes, seconds, frames)
+    }
+    fn chaser(source: TimecodeSource, rate: FrameRate, freewheel: Duration) -> TimecodeChaser {
+        let mut ret = TimecodeChaser::new();
+        ret.set_conf(TimecodeConf { source, rate, freewheel });
+        ret
+    }
+    /// The eight quarter frame messages describing `tc`, in the order they're sent.
+    fn quarter_frames(tc: Timecode, rate: FrameRate) -> Vec<[u8; 2]> {
+        let vals = [
+            tc.frames & 0x0F, tc.frames >> 4,
+            tc.seconds & 0x0F, tc.seconds >> 4,
+            tc.minutes & 0x0F, tc.minutes >> 4,
+            tc.hours & 0x0F, (tc.hours >> 4) | (rate.mtc_code() << 1)
+        ];
+        vals.iter().enumerate()
+            .map(|(piece, val)| [0xF1, ((piece as u8) << 4) | val])
+            .collect()
+    }
+    #[test]
+    fn drop_frame_minute_boundaries() {
+        let rate = FrameRate::Fps2997Drop;
+        /* Every tenth minute keeps frames 0 and 1... */
+        assert!(tc(0, 10, 0, 0).is_valid(rate));
+        assert_eq!(tc(0, 10, 0, 0).to_frames(rate), 17982);
+        assert_eq!(Timecode::from_frames(17982, rate), tc(0, 10, 0, 0));
+        assert_eq!(Timecode::from_frames(17983, rate), tc(0, 10, 0, 1));
+        assert_eq!(Timecode::from_frames(17984, rate), tc(0, 10, 0, 2));
+        /* ...but the others go straight from frame 29 to frame 2. */
+        assert!(!tc(0, 11, 0, 0).is_valid(rate));
+        assert!(!tc(0, 11, 0, 1).is_valid(rate));
+        let last = tc(0, 10, 59, 29).to_frames(rate);
+        assert_eq!(tc(0, 11, 0, 2).to_frames(rate), last + 1);
+        assert_eq!(Timecode::from_frames(last, rate), tc(0, 10, 59, 29));
+        assert_eq!(Timecode::from_frames(last + 1, rate), tc(0, 11, 0, 2));
+        assert_eq!(tc(0, 1, 0, 2).to_frames(rate), 1800);
+        assert_eq!(tc(1, 0, 0, 0).to_frames(rate), 107892);
+    }
+    #[test]
+    fn frames_round_trip() {
+        for &rate in [FrameRate::Fps24, FrameRate::Fps25, FrameRate::Fps2997Drop, FrameRate::Fps30].iter() {
+            /* The first 21 minutes (so two lots of ten minutes for drop frame), and a bit
+             * either side of the hour. */
+            let hour = tc(1, 0, 0, 0).to_frames(rate);
+            let ranges = [0..(rate.nominal() * 60 * 21), (hour - 5000)..(hour + 5000)];
+            for range in ranges.iter().cloned() {
+                for frames in range {
+                    let t = Timecode::from_frames(frames, rate);
+                    assert!(t.is_valid(rate), "{} isn't valid at {:?}", t, rate);
+                    assert_eq!(t.to_frames(rate), frames, "{} at {:?}", t, rate);
+                }
+            }
+        }
+    }
+    #[test]
+    fn mtc_quarter_frames() {
+        let rate = FrameRate::Fps25;
+        let mut ch = chaser(TimecodeSource::Mtc, rate, Duration::new(0, 0));
+        let pos = tc(1, 2, 3, 4);
+        let qf_nanos = rate.frame_nanos() as u64 / 4;
+        let start = 1_000_000_000;
+        let msgs = quarter_frames(pos, rate);
+        for (i, msg) in msgs.iter().enumerate() {
+            let ev = MidiEvent::new(start + i as u64 * qf_nanos, Uuid::nil(), msg).unwrap();
+            let changed = ch.on_midi(&ev);
+            assert_eq!(changed, i == 7, "quarter frame {}", i);
+        }
+        /* The position is two frames on from the one described, as of the last message. */
+        let last = start + 7 * qf_nanos;
+        assert_eq!(ch.position(last), Some((pos.to_frames(rate) + 2) as f64));
+        assert_eq!(ch.status(last).position, Some(tc(1, 2, 3, 6)));
+        assert_eq!(ch.state(last), TimecodeState::Locked);
+    }
+    #[test]
+    fn mtc_needs_a_whole_sequence() {
+        let rate = FrameRate::Fps30;
+        let mut ch = chaser(TimecodeSource::Mtc, rate, Duration::new(0, 0));
+        let msgs = quarter_frames(tc(10, 20, 30, 15), rate);
+        /* Starting halfway through doesn't give us a position... */
+        for msg in msgs[4..].iter() {
+            assert!(!ch.on_midi(&MidiEvent::new(1, Uuid::nil(), msg).unwrap()));
+        }
+        assert_eq!(ch.position(1), None);
+        /* ...and nor does getting interrupted by a full frame message. */
+        for msg in msgs[..4].iter() {
+            assert!(!ch.on_midi(&MidiEvent::new(1, Uuid::nil(), msg).unwrap()));
+        }
+        let full = [0xF0, 0x7F, 0x7F, 0x01, 0x01, 0x00, 0x00, 0x00, 0x00, 0xF7];
+        assert!(!ch.on_midi(&MidiEvent::new(1, Uuid::nil(), &full).unwrap()));
+        for msg in msgs[4..].iter() {
+            assert!(!ch.on_midi(&MidiEvent::new(1, Uuid::nil(), msg).unwrap()));
+        }
+        assert_eq!(ch.position(1), None);
+        for (i, msg) in msgs.iter().enumerate() {
+            assert_eq!(ch.on_midi(&MidiEvent::new(1, Uuid::nil(), msg).unwrap()), i == 7);
+        }
+        assert_eq!(ch.status(1).position, Some(tc(10, 20, 30, 17)));
+    }
+    #[test]
+    fn ignores_other_sources() {
+        let rate = FrameRate::Fps25;
+        let mut ch = chaser(TimecodeSource::Ltc, rate, Duration::new(0, 0));
+        for msg in quarter_frames(tc(0, 0, 1, 0), rate).iter() {
+            assert!(!ch.on_midi(&MidiEvent::new(1, Uuid::nil(), msg).unwrap()));
+        }
+        assert_eq!(ch.position(1), None);
+        let mut ch = chaser(TimecodeSource::Mtc, rate, Duration::new(0, 0));
+        let fr = LtcFrame { time: 1, bits: LtcFrame::bits_for(0, 0, 1, 0, false) };
+        assert!(!ch.on_ltc(&fr));
+        assert_eq!(ch.position(1), None);
+    }
+    #[test]
+    fn ltc_chasing() {
+        let rate = FrameRate::Fps25;
+        let mut ch = chaser(TimecodeSource::Ltc, rate, Duration::new(0, 0));
+        let fr = LtcFrame { time: 5_000_000_000, bits: LtcFrame::bits_for(0, 59, 59, 24, false) };
+        assert!(ch.on_ltc(&fr));
+        assert_eq!(ch.status(fr.time).position, Some(tc(1, 0, 0, 0)));
+        /* Half a second later, we've moved on by 12.5 frames. */
+        assert_eq!(ch.position(fr.time + 500_000_000), Some((tc(1, 0, 0, 0).to_frames(rate)) as f64 + 12.5));
+        let target = tc(1, 0, 1, 0).to_frames(rate);
+        assert_eq!(ch.time_of(target), Some(fr.time + 1_000_000_000));
+        /* Invalid timecode is ignored. */
+        let bad = LtcFrame { time: 6_000_000_000, bits: LtcFrame::bits_for(0, 0, 0, 26, false) };
+        assert!(!ch.on_ltc(&bad));
+    }
+    #[test]
+    fn dropout_and_freewheel() {
+        let rate = FrameRate::Fps25;
+        let frame = rate.frame_nanos() as u64;
+        let mut ch = chaser(TimecodeSource::Ltc, rate, Duration::new(1, 0));
+        let start = 10_000_000_000;
+        assert_eq!(ch.state(start), TimecodeState::Stopped);
+        assert!(ch.on_ltc(&LtcFrame { time: start, bits: LtcFrame::bits_for(0, 0, 10, 0, false) }));
+        assert_eq!(ch.state(start + 3 * frame), TimecodeState::Locked);
+        /* It's dropped out, but we keep going for the freewheel time... */
+        assert_eq!(ch.state(start + 5 * frame), TimecodeState::Freewheeling);
+        assert_eq!(ch.status(start + 25 * frame).position, Some(tc(0, 0, 11, 1)));
+        /* ...and then stop, forgetting where we were. */
+        assert_eq!(ch.state(start + 4 * frame + 1_000_000_001), TimecodeState::Stopped);
+        assert_eq!(ch.position(start), None);
+        /* Without freewheel, we stop as soon as it drops out. */
+        let mut ch = chaser(TimecodeSource::Ltc, rate, Duration::new(0, 0));
+        assert!(ch.on_ltc(&LtcFrame { time: start, bits: LtcFrame::bits_for(0, 0, 10, 0, false) }));
+        assert_eq!(ch.state(start + 4 * frame), TimecodeState::Locked);
+        assert_eq!(ch.state(start + 5 * frame), TimecodeState::Stopped);
+    }
+}
@@ -0,0 +1,406 @@
+//! Firing commands from incoming MIDI, OSC and timecode.
+
+use uuid::Uuid;
+use state::{Context, CD};
+use errors::*;
+use rosc::{decoder, OscPacket};
+use tokio_core::net::UdpSocket;
+use tokio_core::reactor::Handle;
+use sqa_engine::midi::MidiEvent;
+use sqa_engine::ltc::LtcFrame;
+use sqa_engine::Sender;
+use timecode::{FrameRate, Timecode, TimecodeConf, TimecodeChaser, TimecodeState, TimecodeStatus};
+use codec::Reply;
+use async::AsyncResult;
+use tokio_core::reactor::Timeout;
+use futures::Future;
+use std::time::Duration;
+use std::io;
+
+/// How many frames before the position timecode first locks on at (or jumps to) timecode
+/// triggers still fire for, since it takes a frame or two of timecode to lock on.
+const LOCK_WINDOW_FRAMES: u64 = 4;
+
+/// Something that can happen to fire a trigger.
+///
+/// MIDI channels are numbered from 1 to 16; `None` matches any channel.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub enum TriggerEvent {
+    /// A note on message (with non-zero velocity) on the engine's MIDI input.
+    MidiNote { channel: Option<u8>, note: u8 },
+    /// A control change message on the engine's MIDI input. If `value` is `None`, any value
+    /// matches.
+    MidiCc { channel: Option<u8>, controller: u8, value: Option<u8> },
+    /// An OSC message with the given address, received on the trigger port.
+    Osc { addr: String },
+    /// The timecode being chased (see `TimecodeConf`) reaching the given position.
+    Timecode { tc: Timecode }
+}
+/// What to do when a trigger fires.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub enum TriggerCommand {
+    /// Execute the action standing by, as with `/go`.
+    Go,
+    Execute(Uuid),
+    Stop(Uuid),
+    StopAll,
+    PlayheadNext,
+    PlayheadPrevious
+}
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Trigger {
+    pub event: TriggerEvent,
+    pub command: TriggerCommand
+}
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct TriggerConf {
+    /// The UDP port to listen for trigger OSC messages on, if any.
+    ///
+    /// This is separate from the port SQA's own commands are received on, so trigger
+    /// addresses can be anything.
+    pub osc_port: Option<u16>,
+    pub triggers: Vec<Trigger>,
+    #[serde(default)]
+    pub timecode: TimecodeConf
+}
+pub struct TriggerContext {
+    conf: TriggerConf,
+    sock: Option<UdpSocket>,
+    buf: Vec<u8>,
+    tc: TimecodeChaser,
+    /// The last timecode position (in frames) we fired triggers up to.
+    tc_last: Option<u64>,
+    /// The timecode state we last told everyone about.
+    tc_state: TimecodeState,
+    /// Fires once a frame whilst we're chasing, so that we keep going without timecode.
+    tc_timeout: AsyncResult<(), io::Error>
+}
+impl TriggerEvent {
+    fn matches_midi(&self, data: &[u8]) -> bool {
+        use self::TriggerEvent::*;
+        if data.len() < 3 {
+            return false;
+        }
+        let (status, chan) = (data[0] & 0xF0, (data[0] & 0x0F) + 1);
+        match *self {
+            MidiNote { channel, note } => {
+                status == 0x90 && data[2] > 0 && data[1] == note
+                    && channel.map(|c| c == chan).unwrap_or(true)
+            },
+            MidiCc { channel, controller, value } => {
+                status == 0xB0 && data[1] == controller
+                    && value.map(|v| v == data[2]).unwrap_or(true)
+                    && channel.map(|c| c == chan).unwrap_or(true)
+            },
+            Osc { .. } | Timecode { .. } => false
+        }
+    }
+}
+impl TriggerContext {
+    pub fn new() -> Self {
+        Self {
+            conf: Default::default(),
+            sock: None,
+            buf: vec![0; 65536],
+            tc: TimecodeChaser::new(),
+            tc_last: None,
+            tc_state: TimecodeState::Stopped,
+            tc_timeout: AsyncResult::Empty
+        }
+    }
+    pub fn obtain_config(&self) -> TriggerConf {
+        self.conf.clone()
+    }
+    pub fn process_config(&mut self, conf: TriggerConf, hdl: Option<&Handle>) -> BackendResult<()> {
+        if conf.osc_port != self.conf.osc_port || self.sock.is_none() {
+            /* Only get rid of the old socket once we've got the new one, so that a port
+             * that's already in use doesn't leave us with no trigger input at all. */
+            let sock = match conf.osc_port {
+                Some(port) => {
+                    let hdl = match hdl {
+                        Some(h) => h,
+                        None => bail!("The server isn't running yet.")
+                    };
+                    let addr = format!("0.0.0.0:{}", port).parse().unwrap();
+                    let sock = UdpSocket::bind(&addr, hdl)?;
+                    info!("listening for trigger OSC messages on port {}", port);
+                    Some(sock)
+                },
+                None => None
+            };
+            self.sock = sock;
+        }
+        self.tc.set_conf(conf.timecode.clone());
+        self.conf = conf;
+        Ok(())
+    }
+    pub fn timecode_status(&mut self) -> TimecodeStatus {
+        self.tc.status(Sender::<()>::precise_time_ns())
+    }
+    fn commands_for_midi(&self, data: &[u8]) -> Vec<TriggerCommand> {
+        self.conf.triggers.iter()
+            .filter(|t| t.event.matches_midi(data))
+            .map(|t| t.command.clone())
+            .collect()
+    }
+    /// Move our idea of the timecode position on to `pos` (in frames), returning the
+    /// commands of any timecode triggers passed on the way, and the frames they're at.
+    fn commands_for_timecode(&mut self, pos: u64, rate: FrameRate) -> Vec<(TriggerCommand, u64)> {
+        /* Look one frame ahead, so that things can be scheduled to happen on time. */
+        let upto = pos + 1;
+        let from = match self.tc_last {
+            Some(last) if upto >= last && upto - last <= rate.nominal() => last + 1,
+            Some(last) if upto < last && last - upto <= rate.nominal() => {
+                /* Going back a little is just jitter; don't fire anything twice. */
+                return vec![];
+            },
+            last => {
+                if let Some(last) = last {
+                    debug!("timecode jumped from {} to {}", Timecode::from_frames(last, rate), Timecode::from_frames(upto, rate));
+                }
+                pos.saturating_sub(LOCK_WINDOW_FRAMES)
+            }
+        };
+        self.tc_last = Some(upto);
+        self.conf.triggers.iter()
+            .filter_map(|trig| match trig.event {
+                TriggerEvent::Timecode { tc } => Some((trig.command.clone(), tc.to_frames(rate))),
+                _ => None
+            })
+            .filter(|&(_, frames)| frames >= from && frames <= upto)
+            .collect()
+    }
+    fn commands_for_osc(&self, pkt: &OscPacket, out: &mut Vec<TriggerCommand>) {
+        match *pkt {
+            OscPacket::Message(ref msg) => {
+                for trig in self.conf.triggers.iter() {
+                    if let TriggerEvent::Osc { ref addr } = trig.event {
+                        if *addr == msg.addr {
+                            out.push(trig.command.clone());
+                        }
+                    }
+                }
+            },
+            OscPacket::Bundle(ref bundle) => {
+                for pkt in bundle.content.iter() {
+                    self.commands_for_osc(pkt, out);
+                }
+            }
+        }
+    }
+    /// Receive anything that's come in on the trigger port. Call this on every wakeup.
+    pub fn on_wakeup(ctx: &mut Context, d: &mut CD) {
+        let _ = ctx.triggers.tc_timeout.poll();
+        if ctx.triggers.tc_timeout.is_complete() {
+            ctx.triggers.tc_timeout = AsyncResult::Empty;
+            Self::poll_timecode(ctx, d);
+        }
+        let mut cmds = vec![];
+        loop {
+            let res = {
+                let TriggerContext { ref mut sock, ref mut buf, .. } = ctx.triggers;
+                match *sock {
+                    Some(ref mut s) => s.recv_from(buf),
+                    None => break
+                }
+            };
+            match res {
+                Ok((len, addr)) => {
+                    match decoder::decode(&ctx.triggers.buf[..len]) {
+                        Ok(pkt) => ctx.triggers.commands_for_osc(&pkt, &mut cmds),
+                        Err(e) => debug!("invalid trigger OSC packet from {}: {:?}", addr, e)
+                    }
+                },
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(e) => {
+                    warn!("error receiving trigger OSC: {}", e);
+                    break;
+                }
+            }
+        }
+        let now = Sender::<()>::precise_time_ns();
+        for cmd in cmds {
+            Self::fire(ctx, d, cmd, now);
+        }
+    }
+    /// Handle a MIDI message received on the engine's MIDI input.
+    pub fn on_midi(ctx: &mut Context, d: &mut CD, ev: &MidiEvent) {
+        let cmds = ctx.triggers.commands_for_midi(ev.data());
+        for cmd in cmds {
+            Self::fire(ctx, d, cmd, ev.time);
+        }
+        if ctx.triggers.tc.on_midi(ev) {
+            Self::poll_timecode(ctx, d);
+        }
+    }
+    /// Handle a frame of LTC received on the engine's LTC input.
+    pub fn on_ltc(ctx: &mut Context, d: &mut CD, fr: &LtcFrame) {
+        if ctx.triggers.tc.on_ltc(fr) {
+            Self::poll_timecode(ctx, d);
+        }
+    }
+    /// Bring our idea of the timecode position up to date, firing any timecode triggers
+    /// we've reached.
+    fn poll_timecode(ctx: &mut Context, d: &mut CD) {
+        let now = Sender::<()>::precise_time_ns();
+        let state = ctx.triggers.tc.state(now);
+        if state != ctx.triggers.tc_state {
+            ctx.triggers.tc_state = state;
+            let status = ctx.triggers.tc.status(now);
+            info!("timecode {:?}", status);
+            if let Err(e) = d.broadcast(Reply::UpdateTimecodeStatus { status }) {
+                error!("fixme: error in poll_timecode: {:?}", e);
+            }
+        }
+        let pos = match ctx.triggers.tc.position(now) {
+            Some(p) if p >= 0.0 => p as u64,
+            _ => {
+                ctx.triggers.tc_last = None;
+                ctx.triggers.tc_timeout = AsyncResult::Empty;
+                return;
+            }
+        };
+        let rate = ctx.triggers.tc.conf().rate;
+        let cmds = ctx.triggers.commands_for_timecode(pos, rate).into_iter()
+            .map(|(cmd, frames)| (cmd, ctx.triggers.tc.time_of(frames).unwrap_or(now)))
+            .collect::<Vec<_>>();
+        if !ctx.triggers.tc_timeout.is_waiting() {
+            let dur = Duration::new(0, rate.frame_nanos() as u32);
+            match Timeout::new(dur, ctx.handle.as_ref().unwrap()) {
+                Ok(t) => {
+                    ctx.triggers.tc_timeout = AsyncResult::Waiting(Box::new(t));
+                    let _ = ctx.triggers.tc_timeout.poll();
+                },
+                Err(e) => error!("failed to make timecode timeout: {}", e)
+            }
+        }
+        for (cmd, time) in cmds {
+            Self::fire(ctx, d, cmd, time);
+        }
+    }
+    fn fire(ctx: &mut Context, d: &mut CD, cmd: TriggerCommand, time: u64) {
+        use self::TriggerCommand::*;
+        debug!("trigger fired: {:?}", cmd);
+        let res = match cmd {
+            Go => ctx.go(d, time).map(|_| ()),
+            Execute(uu) => ctx.execute_action(d, uu, time),
+            Stop(uu) => ctx.stop_action(d, uu),
+            StopAll => {
+                ctx.stop_all(d);
+                Ok(())
+            },
+            PlayheadNext => {
+                ctx.actions.playhead_next();
+                Ok(())
+            },
+            PlayheadPrevious => {
+                ctx.actions.playhead_previous();
+                Ok(())
+            }
+        };
+        if let Err(e) = res {
+            warn!("trigger {:?} failed: {}", cmd, e);
+        }
+    }
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rosc::{OscMessage, OscBundle, OscType};
+
+    fn ctx_with(triggers: Vec<(TriggerEvent, TriggerCommand)>) -> TriggerContext {
+        let mut ret = TriggerContext::new();
+        ret.conf.triggers = triggers.into_iter()
+            .map(|(event, command)| Trigger { event, command })
+            .collect();
+        ret
+    }
+    fn osc(addr: &str) -> OscPacket {
+        OscPacket::Message(OscMessage { addr: addr.into(), args: None })
+    }
+    #[test]
+    fn midi_notes() {
+        let uu = Uuid::new_v4();
+        let ctx = ctx_with(vec![
+            (TriggerEvent::MidiNote { channel: Some(2), note: 60 }, TriggerCommand::Execute(uu)),
+            (TriggerEvent::MidiNote { channel: None, note: 61 }, TriggerCommand::Go)
+        ]);
+        assert_eq!(ctx.commands_for_midi(&[0x91, 60, 100]), vec![TriggerCommand::Execute(uu)]);
+        /* wrong channel */
+        assert!(ctx.commands_for_midi(&[0x90, 60, 100]).is_empty());
+        /* note on with zero velocity is really a note off */
+        assert!(ctx.commands_for_midi(&[0x91, 60, 0]).is_empty());
+        /* note off */
+        assert!(ctx.commands_for_midi(&[0x81, 60, 100]).is_empty());
+        assert_eq!(ctx.commands_for_midi(&[0x9F, 61, 1]), vec![TriggerCommand::Go]);
+        /* too short */
+        assert!(ctx.commands_for_midi(&[0x91, 60]).is_empty());
+    }
+    #[test]
+    fn midi_ccs() {
+        let ctx = ctx_with(vec![
+            (TriggerEvent::MidiCc { channel: Some(1), controller: 7, value: Some(127) }, TriggerCommand::StopAll),
+            (TriggerEvent::MidiCc { channel: None, controller: 8, value: None }, TriggerCommand::PlayheadNext)
+        ]);
+        assert_eq!(ctx.commands_for_midi(&[0xB0, 7, 127]), vec![TriggerCommand::StopAll]);
+        assert!(ctx.commands_for_midi(&[0xB0, 7, 126]).is_empty());
+        assert!(ctx.commands_for_midi(&[0xB1, 7, 127]).is_empty());
+        assert_eq!(ctx.commands_for_midi(&[0xB5, 8, 0]), vec![TriggerCommand::PlayheadNext]);
+        assert_eq!(ctx.commands_for_midi(&[0xB5, 8, 64]), vec![TriggerCommand::PlayheadNext]);
+        /* a note with the same numbers isn't a CC */
+        assert!(ctx.commands_for_midi(&[0x90, 8, 64]).is_empty());
+    }
+    #[test]
+    fn timecode_positions() {
+        let rate = FrameRate::Fps25;
+        let uus = (0..5).map(|_| Uuid::new_v4()).collect::<Vec<_>>();
+        let at = |s, f| Timecode::new(1, 0, s, f).to_frames(rate);
+        let mut ctx = ctx_with(vec![
+            (TriggerEvent::Timecode { tc: Timecode::new(1, 0, 0, 0) }, TriggerCommand::Execute(uus[0])),
+            (TriggerEvent::Timecode { tc: Timecode::new(1, 0, 0, 2) }, TriggerCommand::Execute(uus[1])),
+            (TriggerEvent::Timecode { tc: Timecode::new(1, 0, 0, 3) }, TriggerCommand::Execute(uus[2])),
+            (TriggerEvent::Timecode { tc: Timecode::new(1, 0, 10, 0) }, TriggerCommand::Execute(uus[3])),
+            (TriggerEvent::Timecode { tc: Timecode::new(1, 0, 20, 0) }, TriggerCommand::Execute(uus[4])),
+            (TriggerEvent::MidiNote { channel: None, note: 60 }, TriggerCommand::StopAll)
+        ]);
+        /* Locking on a frame late still fires the triggers on the first frames, as well as
+         * looking a frame ahead. */
+        assert_eq!(ctx.commands_for_timecode(at(0, 1), rate), vec![
+            (TriggerCommand::Execute(uus[0]), at(0, 0)),
+            (TriggerCommand::Execute(uus[1]), at(0, 2))
+        ]);
+        assert_eq!(ctx.commands_for_timecode(at(0, 2), rate), vec![(TriggerCommand::Execute(uus[2]), at(0, 3))]);
+        /* Nothing fires twice, whether timecode stays put or goes back a little. */
+        assert!(ctx.commands_for_timecode(at(0, 2), rate).is_empty());
+        assert!(ctx.commands_for_timecode(at(0, 0), rate).is_empty());
+        assert!(ctx.commands_for_timecode(at(0, 10), rate).is_empty());
+        /* Jumping somewhere else works like locking on afresh. */
+        assert!(ctx.commands_for_timecode(at(9, 0), rate).is_empty());
+        assert_eq!(ctx.commands_for_timecode(at(10, 3), rate), vec![(TriggerCommand::Execute(uus[3]), at(10, 0))]);
+        assert!(ctx.commands_for_timecode(at(10, 4), rate).is_empty());
+        /* As does losing lock, but only within a few frames. */
+        ctx.tc_last = None;
+        assert!(ctx.commands_for_timecode(at(20, LOCK_WINDOW_FRAMES as u8 + 1), rate).is_empty());
+    }
+    #[test]
+    fn osc_addresses() {
+        let ctx = ctx_with(vec![
+            (TriggerEvent::Osc { addr: "/go".into() }, TriggerCommand::Go),
+            (TriggerEvent::Osc { addr: "/back".into() }, TriggerCommand::PlayheadPrevious),
+            (TriggerEvent::MidiNote { channel: None, note: 60 }, TriggerCommand::StopAll)
+        ]);
+        let mut out = vec![];
+        ctx.commands_for_osc(&osc("/go"), &mut out);
+        assert_eq!(out, vec![TriggerCommand::Go]);
+        let mut out = vec![];
+        ctx.commands_for_osc(&osc("/go/now"), &mut out);
+        assert!(out.is_empty());
+        let bundle = OscPacket::Bundle(OscBundle {
+            timetag: OscType::Time(0, 1),
+            content: vec![osc("/back"), osc("/nothing"), osc("/go")]
+        });
+        let mut out = vec![];
+        ctx.commands_for_osc(&bundle, &mut out);
+        assert_eq!(out, vec![TriggerCommand::PlayheadPrevious, TriggerCommand::Go]);
+    }
+}
@@ -121,6 +121,9 @@ pub fn cmd_as_undoable(ctx: &mut Context, cmd: &Command) -> Option<UndoableChang
             })
         },
         UpdateActionMetadata { uuid, ref meta } => {
+            if ctx.actions.check_meta(uuid, &mut meta.clone()).is_err() {
+                return None;
+            }
             let res = do_with_ctx!(ctx, uuid, |a: &mut Action| {
                 a.get_data(ctx).map_err(|e| e.to_string())
             });
@@ -154,6 +157,29 @@ pub fn cmd_as_undoable(ctx: &mut Context, cmd: &Command) -> Option<UndoableChang
                 desc: "modify mixer configuration".into()
             })
         },
+        SetTriggerConf { ref conf } => {
+            let old = ctx.triggers.obtain_config();
+            Some(UndoableChange {
+                undo: SetTriggerConf { conf: old },
+                redo: SetTriggerConf { conf: conf.clone() },
+                desc: "modify trigger configuration".into()
+            })
+        },
+        SetCueNumbers { ref numbers } => {
+            if ctx.actions.check_numbers(numbers).is_err() {
+                return None;
+            }
+            let old = numbers.iter()
+                .filter_map(|&(uu, _)| {
+                    ctx.actions.get(&uu).map(|a| (uu, a.meta().number.clone()))
+                })
+                .collect();
+            Some(UndoableChange {
+                undo: SetCueNumbers { numbers: old },
+                redo: SetCueNumbers { numbers: numbers.clone() },
+                desc: "change cue numbers".into()
+            })
+        },
         ReorderAction { uuid, new_pos } => {
             ctx.actions.position_of(uuid).map(|pos| {
                 UndoableChange {
@@ -6,7 +6,7 @@ use std::thread;
 use std::io::{self, Read};
 use sqa_engine::{EngineContext, jack, Sender};
 fn main() {
-    let mut ec = EngineContext::new(None).unwrap();
+    let mut ec = EngineContext::new(None, Default::default()).unwrap();
     let mut reader = hound::WavReader::open("test.wav").unwrap();
     let mut chans = vec![];
     let mut ctls = vec![];
@@ -14,5 +14,14 @@ error_chain! {
         NoSuchChannel {
             display("No such channel.")
         }
+        NoSuchInput {
+            display("No such input.")
+        }
+        MidiEventTooLong {
+            display("MIDI event is too long.")
+        }
+        MidiQueueFull {
+            display("Too many MIDI events are waiting to be sent.")
+        }
     }
 }
@@ -12,7 +12,6 @@
 pub extern crate sqa_jack;
 extern crate bounded_spsc_queue;
 extern crate time;
-extern crate arrayvec;
 #[macro_use]
 extern crate error_chain;
 extern crate parking_lot;
@@ -21,41 +20,77 @@ extern crate uuid;
 pub mod errors;
 pub mod sync;
 pub mod param;
+pub mod midi;
+pub mod ltc;
+pub mod record;
 mod thread;
 
 use std::sync::atomic::{AtomicBool, AtomicUsize, AtomicU64, AtomicPtr};
 use std::sync::atomic::Ordering::*;
 use bounded_spsc_queue::Producer;
-use arrayvec::ArrayVec;
 use std::sync::Arc;
 use time::Duration;
 use sqa_jack::*;
 pub use errors::EngineResult;
 use errors::{ErrorKind};
 use param::Parameter;
+use midi::MidiEvent;
 pub use uuid::Uuid;
 pub use sqa_jack as jack;
-/// The maximum amount of streams that can play concurrently.
-///
-/// Can be increased to 512 with the `players-512` feature.
-#[cfg(not(feature = "players-512"))]
-pub const MAX_PLAYERS: usize = 256;
-#[cfg(feature = "players-512")]
-pub const MAX_PLAYERS: usize = 512;
-/// The maximum amount of channels that can be created.
-///
-/// Can be increased to 128 with the `channels-128` feature.
-#[cfg(not(feature = "channels-128"))]
-pub const MAX_CHANS: usize = 64;
-#[cfg(feature = "channels-128")]
-pub const MAX_CHANS: usize = 128;
-/// The size of a stream's buffer, in samples.
-pub const STREAM_BUFFER_SIZE: usize = 100_000;
-/// The size of the communication buffer between audio thread and main thread, in messages.
-pub const CONTROL_BUFFER_SIZE: usize = MAX_PLAYERS * 2;
+pub use record::{Recorder, BufferRecorder, PlainRecorder};
+/// The default maximum amount of streams that can play concurrently.
+pub const DEFAULT_MAX_PLAYERS: usize = 256;
+/// The default maximum amount of channels that can be created.
+pub const DEFAULT_MAX_CHANS: usize = 64;
+/// The default size of a stream's buffer, in samples.
+pub const DEFAULT_STREAM_BUFFER_SIZE: usize = 100_000;
+/// The default maximum amount of MIDI events that can be waiting to be sent.
+pub const DEFAULT_MAX_MIDI_EVENTS: usize = 1024;
+/// The default amount of audio input ports.
+pub const DEFAULT_INPUTS: usize = 8;
+/// The default maximum amount of recordings that can be made concurrently.
+pub const DEFAULT_MAX_CAPTURES: usize = 16;
 /// One second, in nanoseconds.
 pub const ONE_SECOND_IN_NANOSECONDS: u64 = 1_000_000_000;
 
+/// Capacity limits for an `EngineContext`, fixed when it is created.
+///
+/// All storage in the audio thread is allocated up front according to these limits, so
+/// that it never has to allocate whilst processing audio. Bigger numbers therefore cost
+/// memory, even if you never use them.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct EngineConfig {
+    /// The maximum amount of streams (`Sender`s) that can play concurrently.
+    pub max_players: usize,
+    /// The maximum amount of channels that can be created.
+    pub max_chans: usize,
+    /// The size of each stream's buffer, in samples.
+    pub stream_buffer_size: usize,
+    /// The maximum amount of MIDI events that can be waiting to be sent.
+    pub max_midi_events: usize,
+    /// How many audio input ports to register.
+    pub inputs: usize,
+    /// The maximum amount of recordings (`Recorder`s) that can be made concurrently.
+    pub max_captures: usize
+}
+impl EngineConfig {
+    /// The size of the communication buffer between audio thread and main thread, in messages.
+    pub fn control_buffer_size(&self) -> usize {
+        (self.max_players + self.max_captures) * 2
+    }
+}
+impl Default for EngineConfig {
+    fn default() -> Self {
+        EngineConfig {
+            max_players: DEFAULT_MAX_PLAYERS,
+            max_chans: DEFAULT_MAX_CHANS,
+            stream_buffer_size: DEFAULT_STREAM_BUFFER_SIZE,
+            max_midi_events: DEFAULT_MAX_MIDI_EVENTS,
+            inputs: DEFAULT_INPUTS,
+            max_captures: DEFAULT_MAX_CAPTURES
+        }
+    }
+}
 /// Corresponds to, and controls, a `Player` in the audio thread.
 pub struct Sender<T> {
     /// Current position, in samples from the start of the buffer (read only)
@@ -70,6 +105,10 @@ pub struct Sender<T> {
     start_time: Arc<AtomicU64>,
     /// Which channel number this stream is patched to (rw)
     output_patch: Arc<AtomicUsize>,
+    /// How many samples are in the stream's buffer, as of the last audio callback (read only)
+    buffer_fill: Arc<AtomicUsize>,
+    /// The capacity of the stream's buffer, in samples.
+    buffer_capacity: usize,
     /// The playback volume (rw)
     volume: Arc<AtomicPtr<Parameter<f32>>>,
     /// The master playback volume (rw)
@@ -183,6 +222,15 @@ impl<T> Sender<T> {
     pub fn output_patch(&self) -> usize {
         self.output_patch.load(Relaxed)
     }
+    /// Get how many samples were in this stream's buffer, as of the last time the audio
+    /// thread looked at it.
+    pub fn buffer_fill(&self) -> usize {
+        self.buffer_fill.load(Relaxed)
+    }
+    /// Get the capacity of this stream's buffer, in samples.
+    pub fn buffer_capacity(&self) -> usize {
+        self.buffer_capacity
+    }
     /// Set this stream's output patch (which channel number this stream is patched to)
     ///
     /// An invalid output patch will cause the stream to deactivate (`active` will be set to false).
@@ -204,6 +252,8 @@ impl<T> Sender<T> {
             alive: self.alive.clone(),
             start_time: self.start_time.clone(),
             output_patch: self.output_patch.clone(),
+            buffer_fill: self.buffer_fill.clone(),
+            buffer_capacity: self.buffer_capacity,
             volume: self.volume.clone(),
             master_vol: self.master_vol.clone(),
             kill_when_empty: self.kill_when_empty.clone(),
@@ -235,29 +285,62 @@ impl<T> Drop for Sender<T> {
 /// Main engine context, containing a connection to JACK.
 pub struct EngineContext {
     pub conn: JackConnection<Activated>,
-    pub chans: ArrayVec<[Option<JackPort>; MAX_CHANS]>,
-    pub holes: ArrayVec<[usize; MAX_CHANS]>,
+    pub chans: Vec<Option<JackPort>>,
+    pub holes: Vec<usize>,
+    /// The engine's MIDI output port.
+    pub midi_out: JackPort,
+    /// The engine's MIDI input port.
+    pub midi_in: JackPort,
+    /// The engine's audio input port for linear timecode (see the `ltc` module).
+    pub ltc_in: JackPort,
+    /// The engine's audio input ports, which can be played through to channels (see
+    /// `new_input_sender()`).
+    pub inputs: Vec<JackPort>,
+    config: EngineConfig,
     length: Arc<AtomicUsize>,
+    xruns: Arc<AtomicUsize>,
     control: Producer<thread::AudioThreadCommand>,
+    midi: Producer<MidiEvent>,
     rx: Option<sync::AudioThreadHandle>
 }
 impl EngineContext {
     /// Initialise the SQA Engine, opening a connection to JACK and starting the audio thread.
     ///
     /// The connection is made under a given name if provided, otherwise under "SQA Engine".
-    pub fn new(name: Option<&str>) -> EngineResult<Self> {
+    /// The engine's capacity limits are taken from `config`.
+    pub fn new(name: Option<&str>, config: EngineConfig) -> EngineResult<Self> {
         let len = Arc::new(AtomicUsize::new(0));
-        let (p, c) = bounded_spsc_queue::make(CONTROL_BUFFER_SIZE);
-        let (rc, rp) = unsafe { sync::AudioThreadHandle::make() };
+        let xruns = Arc::new(AtomicUsize::new(0));
+        let (p, c) = bounded_spsc_queue::make(config.control_buffer_size());
+        let (rc, rp, rn) = unsafe { sync::AudioThreadHandle::make(config.control_buffer_size()) };
+        let (mp, mc) = bounded_spsc_queue::make(config.max_midi_events);
         let mut conn = JackConnection::connect(name.unwrap_or("SQA Engine"), Some(OPEN_NO_START_SERVER))?;
+        let midi_out = conn.register_midi_port("midi_out", PORT_IS_OUTPUT | PORT_IS_TERMINAL)?;
+        let midi_in = conn.register_midi_port("midi_in", PORT_IS_INPUT | PORT_IS_TERMINAL)?;
+        let ltc_in = conn.register_port("ltc_in", PORT_IS_INPUT | PORT_IS_TERMINAL)?;
+        let mut inputs = Vec::with_capacity(config.inputs);
+        for i in 0..config.inputs {
+            inputs.push(conn.register_port(&format!("in_{}", i + 1), PORT_IS_INPUT | PORT_IS_TERMINAL)?);
+        }
         let dctx = thread::DeviceContext {
-            players: ArrayVec::new(),
-            chans: ArrayVec::new(),
-            holes: ArrayVec::new(),
+            players: Vec::with_capacity(config.max_players),
+            max_players: config.max_players,
+            captures: Vec::with_capacity(config.max_captures),
+            max_captures: config.max_captures,
+            chans: Vec::with_capacity(config.max_chans),
+            holes: Vec::with_capacity(config.max_chans),
             control: c,
+            midi_out: midi_out.clone(),
+            midi_in: midi_in.clone(),
+            midi_queue: mc,
+            midi_pending: Vec::with_capacity(config.max_midi_events),
+            ltc_in: ltc_in.clone(),
+            ltc: ltc::LtcDecoder::new(conn.sample_rate() as u64),
             length: len.clone(),
+            xruns: xruns.clone(),
             sample_rate: conn.sample_rate() as u64,
-            sender: rp
+            sender: rp,
+            notifier: rn
         };
         conn.set_handler(dctx)?;
         let conn = match conn.activate() {
@@ -266,10 +349,17 @@ impl EngineContext {
         };
         Ok(EngineContext {
             conn: conn,
-            chans: ArrayVec::new(),
-            holes: ArrayVec::new(),
+            chans: Vec::with_capacity(config.max_chans),
+            holes: Vec::with_capacity(config.max_chans),
+            midi_out: midi_out,
+            midi_in: midi_in,
+            ltc_in: ltc_in,
+            inputs: inputs,
+            config: config,
             length: len,
+            xruns: xruns,
             control: p,
+            midi: mp,
             rx: Some(rc)
         })
     }
@@ -286,17 +376,62 @@ impl EngineContext {
     pub fn get_handle(&mut self) -> Option<sync::AudioThreadHandle> {
         self.rx.take()
     }
+    /// Get the capacity limits this context was created with.
+    pub fn config(&self) -> &EngineConfig {
+        &self.config
+    }
     pub fn num_senders(&self) -> usize {
         self.length.load(Relaxed)
     }
+    /// Get the number of xruns (buffer under- or overruns) JACK has reported since this
+    /// context was created.
+    pub fn xrun_count(&self) -> usize {
+        self.xruns.load(Relaxed)
+    }
+    /// Get the playback latency of a channel, in nanoseconds.
+    ///
+    /// This is the worst-case latency reported by JACK between audio being written to the
+    /// channel and it reaching whatever the channel is connected to. It changes whenever the
+    /// channel is (dis)connected, so you SHOULD NOT cache it for long.
+    pub fn channel_latency(&self, idx: usize) -> EngineResult<u64> {
+        let port = match self.chans.get(idx) {
+            Some(&Some(ref p)) => p,
+            _ => Err(ErrorKind::NoSuchChannel)?
+        };
+        let (_, max) = port.get_latency_range(JackLatencyMode::Playback);
+        Ok(max as u64 * ONE_SECOND_IN_NANOSECONDS / self.conn.sample_rate() as u64)
+    }
+    /// Get the highest playback latency of any of this context's channels, in nanoseconds.
+    pub fn max_channel_latency(&self) -> u64 {
+        (0..self.chans.len())
+            .filter_map(|idx| self.channel_latency(idx).ok())
+            .max()
+            .unwrap_or(0)
+    }
+    /// Convert the time at which a cue was fired into the time a `Sender` patched to a given
+    /// channel should start at.
+    ///
+    /// Senders started with start times obtained from this function will be heard at the
+    /// same time, regardless of differences in the latency of their channels: channels with
+    /// less latency than the worst one are delayed by the difference. (Nothing is ever started
+    /// in the past, as that would just skip the start of the audio.) If the channel doesn't
+    /// exist, the time is returned unchanged.
+    pub fn compensated_start_time(&self, fired_at: u64, idx: usize) -> u64 {
+        let latency = match self.channel_latency(idx) {
+            Ok(l) => l,
+            Err(_) => return fired_at
+        };
+        fired_at + (self.max_channel_latency() - latency)
+    }
     pub fn new_channel(&mut self, name: &str) -> EngineResult<usize> {
         /* NOTE: This code must mirror the code in thread.rs */
-        let port = self.conn.register_port(name, PORT_IS_OUTPUT | PORT_IS_TERMINAL)?;
-        if (self.chans.len() - self.holes.len()) == self.chans.capacity() - 1 {
+        if (self.chans.len() - self.holes.len()) >= self.config.max_chans {
             Err(ErrorKind::LimitExceeded)?
         }
+        let port = self.conn.register_port(name, PORT_IS_OUTPUT | PORT_IS_TERMINAL)?;
         let ret;
-        if let Some(ix) = self.holes.remove(0) {
+        if self.holes.len() > 0 {
+            let ix = self.holes.remove(0);
             self.chans[ix] = Some(port);
             ret = ix;
         }
@@ -312,21 +447,74 @@ impl EngineContext {
         if idx >= self.chans.len() || self.holes.contains(&idx) {
             Err(ErrorKind::NoSuchChannel)?
         }
-        self.chans.push(None);
         self.holes.push(idx);
         self.control.push(thread::AudioThreadCommand::RemoveChannel(idx));
-        self.conn.unregister_port(self.chans.swap_remove(idx).unwrap().unwrap())?;
+        self.conn.unregister_port(self.chans[idx].take().unwrap())?;
+        Ok(())
+    }
+    /// Queue a MIDI event to be sent out of the engine's MIDI port (`midi_out`).
+    ///
+    /// The event will be sent at the sample corresponding to its `time`, or as soon as
+    /// possible if that's in the past.
+    pub fn send_midi(&mut self, ev: MidiEvent) -> EngineResult<()> {
+        if self.midi.try_push(ev).is_some() {
+            Err(ErrorKind::MidiQueueFull)?
+        }
         Ok(())
     }
+    /// Cancel all MIDI events with a given `owner` that haven't been sent yet.
+    pub fn cancel_midi(&mut self, owner: Uuid) {
+        self.control.push(thread::AudioThreadCommand::CancelMidi(owner));
+    }
     pub fn new_sender(&mut self, sample_rate: u64) -> BufferSender {
-        self.new_sender_ext(sample_rate, None)
+        self.new_sender_ext(sample_rate, None, None)
     }
     pub fn new_sender_with_master<T>(&mut self, master: &Sender<T>) -> BufferSender {
         let master_vol = master.master_vol.clone();
-        self.new_sender_ext(master.sample_rate, Some(master_vol))
+        self.new_sender_ext(master.sample_rate, Some(master_vol), None)
+    }
+    /// Make a sender that plays whatever's coming in on input port number `input`, instead
+    /// of what's written to its buffer.
+    ///
+    /// Everything else (volume, output patch, start time, and so on) works as usual. The
+    /// sender's position is the number of samples it has played through.
+    ///
+    /// # Errors
+    ///
+    /// - `NoSuchInput`: if there's no input port with the given number.
+    pub fn new_input_sender(&mut self, input: usize) -> EngineResult<BufferSender> {
+        let port = *self.inputs.get(input).ok_or(ErrorKind::NoSuchInput)?;
+        let sample_rate = self.conn.sample_rate() as u64;
+        Ok(self.new_sender_ext(sample_rate, None, Some(port)))
+    }
+    /// Like `new_input_sender()`, but sharing the master volume of another sender.
+    pub fn new_input_sender_with_master<T>(&mut self, input: usize, master: &Sender<T>) -> EngineResult<BufferSender> {
+        let port = *self.inputs.get(input).ok_or(ErrorKind::NoSuchInput)?;
+        let master_vol = master.master_vol.clone();
+        Ok(self.new_sender_ext(master.sample_rate, Some(master_vol), Some(port)))
+    }
+    /// Make a recorder that records whatever's coming in on the given input ports, one
+    /// buffer per port (in the order given).
+    ///
+    /// The recorder starts off inactive; make it active to start recording.
+    ///
+    /// # Errors
+    ///
+    /// - `NoSuchInput`: if any of the input port numbers doesn't exist.
+    pub fn new_recorder(&mut self, inputs: &[usize]) -> EngineResult<BufferRecorder> {
+        let mut ports = Vec::with_capacity(inputs.len());
+        for &i in inputs {
+            ports.push(*self.inputs.get(i).ok_or(ErrorKind::NoSuchInput)?);
+        }
+        let sample_rate = self.conn.sample_rate() as u64;
+        let (cap, rec) = record::make_recorder(ports, sample_rate, self.config.stream_buffer_size);
+        self.control.push(thread::AudioThreadCommand::AddCapture(cap));
+        Ok(rec)
     }
-    fn new_sender_ext(&mut self, sample_rate: u64, master_vol: Option<Arc<AtomicPtr<Parameter<f32>>>>) -> BufferSender {
-        let (p, c) = bounded_spsc_queue::make(STREAM_BUFFER_SIZE);
+    fn new_sender_ext(&mut self, sample_rate: u64, master_vol: Option<Arc<AtomicPtr<Parameter<f32>>>>, input: Option<JackPort>) -> BufferSender {
+        /* Input senders don't use their buffers, so don't waste memory on them. */
+        let size = if input.is_some() { 1 } else { self.config.stream_buffer_size };
+        let (p, c) = bounded_spsc_queue::make(size);
         let active = Arc::new(AtomicBool::new(false));
         let alive = Arc::new(AtomicBool::new(false));
         let kill_when_empty = Arc::new(AtomicBool::new(false));
@@ -338,6 +526,7 @@ impl EngineContext {
         let master_vol = master_vol.unwrap_or(
             Arc::new(AtomicPtr::new(Box::into_raw(default_master_vol))));
         let output_patch = Arc::new(AtomicUsize::new(::std::usize::MAX));
+        let buffer_fill = Arc::new(AtomicUsize::new(0));
         let uu = Uuid::new_v4();
 
         self.control.push(thread::AudioThreadCommand::AddPlayer(thread::Player {
@@ -348,9 +537,11 @@ impl EngineContext {
             active: active.clone(),
             alive: alive.clone(),
             output_patch: output_patch.clone(),
+            buffer_fill: buffer_fill.clone(),
             volume: volume.clone(),
             master_vol: master_vol.clone(),
             kill_when_empty: kill_when_empty.clone(),
+            input: input,
             uuid: uu,
             half_sent: false,
             empty_sent: false
@@ -362,6 +553,8 @@ impl EngineContext {
             active: active,
             alive: alive,
             output_patch: output_patch,
+            buffer_fill: buffer_fill,
+            buffer_capacity: size,
             start_time: start_time,
             sample_rate: sample_rate,
             volume: volume.clone(),
@@ -0,0 +1,207 @@
+//! Encoding and decoding SMPTE linear timecode (LTC) as audio.
+//!
+//! The decoder runs in the audio thread on the engine's `ltc_in` port, so it never allocates.
+//! The encoder runs outside it, producing samples to be played back through a `Sender`.
+
+/// The sync word that ends every LTC frame, as it appears in bits 64 to 79.
+const LTC_SYNC_WORD: u16 = 0xBFFC;
+/// How far (in either direction) the signal has to swing before we count it as a transition.
+const LTC_THRESHOLD: f32 = 0.05;
+/// The amplitude of encoded LTC.
+const LTC_LEVEL: f32 = 0.5;
+
+/// One complete LTC frame, as received on the engine's `ltc_in` port.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct LtcFrame {
+    /// When (from the system's monotonic clock) the last bit of the frame was received.
+    pub time: u64,
+    /// The 64 data bits of the frame (i.e. excluding the sync word), bit 0 first.
+    pub bits: u64
+}
+impl LtcFrame {
+    fn field(&self, start: u32, len: u32) -> u8 {
+        ((self.bits >> start) & ((1 << len) - 1)) as u8
+    }
+    /// Make the data bits of a frame with the given timecode.
+    ///
+    /// The user bits, and the flags other than drop frame, are left as zero.
+    pub fn bits_for(hours: u8, minutes: u8, seconds: u8, frames: u8, drop_frame: bool) -> u64 {
+        let field = |val: u8, start: u32| (val as u64) << start;
+        field(frames % 10, 0) | field(frames / 10, 8) | field(drop_frame as u8, 10)
+            | field(seconds % 10, 16) | field(seconds / 10, 24)
+            | field(minutes % 10, 32) | field(minutes / 10, 40)
+            | field(hours % 10, 48) | field(hours / 10, 56)
+    }
+    /// The frame's timecode, as (hours, minutes, seconds, frames).
+    pub fn timecode(&self) -> (u8, u8, u8, u8) {
+        let hours = self.field(56, 2) * 10 + self.field(48, 4);
+        let minutes = self.field(40, 3) * 10 + self.field(32, 4);
+        let seconds = self.field(24, 3) * 10 + self.field(16, 4);
+        let frames = self.field(8, 2) * 10 + self.field(0, 4);
+        (hours, minutes, seconds, frames)
+    }
+    /// Whether the drop frame flag is set.
+    pub fn drop_frame(&self) -> bool {
+        self.field(10, 1) == 1
+    }
+}
+/// A biphase mark decoder for LTC.
+///
+/// Bit periods are tracked adaptively, so any of the common frame rates (and some amount
+/// of varispeed) can be decoded without telling the decoder which one to expect.
+pub struct LtcDecoder {
+    /// Whether the signal is currently above the threshold.
+    high: bool,
+    /// Samples since the last transition.
+    count: u32,
+    /// Our current estimate of the length of one bit, in samples.
+    period: f32,
+    /// Whether we've seen the first half of a one bit.
+    half: bool,
+    /// The last 80 bits received: bits 0 to 63 in `lo`, 64 to 79 in `hi`.
+    lo: u64,
+    hi: u16
+}
+impl LtcDecoder {
+    /// Make a new decoder for audio at the given sample rate.
+    pub fn new(sample_rate: u64) -> Self {
+        LtcDecoder {
+            high: false,
+            count: 0,
+            /* Start off expecting 25fps; we'll adapt if it's anything else. */
+            period: sample_rate as f32 / (25.0 * 80.0),
+            half: false,
+            lo: 0,
+            hi: 0
+        }
+    }
+    fn push_bit(&mut self, bit: bool) -> Option<u64> {
+        self.lo = (self.lo >> 1) | ((self.hi as u64 & 1) << 63);
+        self.hi = (self.hi >> 1) | ((bit as u16) << 15);
+        if self.hi == LTC_SYNC_WORD {
+            Some(self.lo)
+        }
+        else {
+            None
+        }
+    }
+    /// Feed one sample into the decoder, returning the data bits of a frame if this sample
+    /// completed one.
+    #[inline(always)]
+    pub fn feed(&mut self, sample: f32) -> Option<u64> {
+        self.count = self.count.saturating_add(1);
+        let transition = if self.high { sample < -LTC_THRESHOLD } else { sample > LTC_THRESHOLD };
+        if !transition {
+            return None;
+        }
+        self.high = !self.high;
+        let interval = self.count as f32;
+        self.count = 0;
+        if interval > self.period * 4.0 {
+            /* We lost the signal for a while; start afresh. */
+            self.half = false;
+            return None;
+        }
+        if interval > self.period * 0.75 {
+            /* A whole bit period without a transition in the middle: a zero. */
+            self.period = (self.period * 3.0 + interval) / 4.0;
+            self.half = false;
+            self.push_bit(false)
+        }
+        else if self.half {
+            /* The second half of a one. */
+            self.half = false;
+            self.push_bit(true)
+        }
+        else {
+            self.half = true;
+            None
+        }
+    }
+}
+/// A biphase mark encoder for LTC.
+pub struct LtcEncoder {
+    /// The current output level.
+    high: bool,
+    /// The length of one bit, in samples.
+    samples_per_bit: f64,
+    /// Fractional samples left over from the last bit.
+    phase: f64
+}
+impl LtcEncoder {
+    /// Make a new encoder, producing audio at the given sample rate and frame rate.
+    pub fn new(sample_rate: u64, fps: f64) -> Self {
+        LtcEncoder {
+            high: false,
+            samples_per_bit: sample_rate as f64 / (fps * 80.0),
+            phase: 0.0
+        }
+    }
+    /// Encode one frame, given its data bits (see `LtcFrame::bits_for`), appending the
+    /// resulting samples to `out`.
+    pub fn encode(&mut self, bits: u64, out: &mut Vec<f32>) {
+        for i in 0..80 {
+            let bit = if i < 64 {
+                (bits >> i) & 1 == 1
+            }
+            else {
+                (LTC_SYNC_WORD >> (i - 64)) & 1 == 1
+            };
+            self.phase += self.samples_per_bit;
+            let len = self.phase as usize;
+            self.phase -= len as f64;
+            /* Every bit starts with a transition; ones have another one halfway through. */
+            self.high = !self.high;
+            for j in 0..len {
+                if bit && j == len / 2 {
+                    self.high = !self.high;
+                }
+                out.push(if self.high { LTC_LEVEL } else { -LTC_LEVEL });
+            }
+        }
+    }
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Encode 50 consecutive frames starting at 01:02:03:04, and decode them again, returning
+    /// what was sent and what was received.
+    fn round_trip(sample_rate: u64, fps: u8) -> (Vec<u64>, Vec<u64>) {
+        let mut enc = LtcEncoder::new(sample_rate, fps as f64);
+        let mut dec = LtcDecoder::new(sample_rate);
+        let mut sent = vec![];
+        let mut samples = vec![];
+        for i in 0..50u8 {
+            let frames = (4 + i) % fps;
+            let seconds = 3 + (4 + i) / fps;
+            let bits = LtcFrame::bits_for(1, 2, seconds, frames, false);
+            sent.push(bits);
+            enc.encode(bits, &mut samples);
+        }
+        let received = samples.into_iter()
+            .filter_map(|s| dec.feed(s))
+            .collect();
+        (sent, received)
+    }
+    #[test]
+    fn frame_bits() {
+        let fr = LtcFrame { time: 0, bits: LtcFrame::bits_for(23, 59, 58, 29, true) };
+        assert_eq!(fr.timecode(), (23, 59, 58, 29));
+        assert!(fr.drop_frame());
+        let fr = LtcFrame { time: 0, bits: LtcFrame::bits_for(1, 2, 3, 4, false) };
+        assert_eq!(fr.timecode(), (1, 2, 3, 4));
+        assert!(!fr.drop_frame());
+    }
+    #[test]
+    fn decodes_encoded_frames() {
+        for &(rate, fps) in [(48000, 25), (44100, 25), (48000, 24), (48000, 30), (96000, 30)].iter() {
+            let (sent, received) = round_trip(rate, fps);
+            /* The decoder might take a frame to lock on, and a frame only completes once the
+             * next one starts, but it should get every frame in between. */
+            assert!(received.len() >= sent.len() - 2, "{} Hz, {} fps: only decoded {} frames", rate, fps, received.len());
+            let start = sent.iter().position(|&b| b == received[0]).unwrap();
+            assert_eq!(&sent[start..start + received.len()], &received[..], "{} Hz, {} fps", rate, fps);
+        }
+    }
+}
@@ -0,0 +1,46 @@
+//! MIDI events, for sending out of the engine's MIDI port.
+
+use uuid::Uuid;
+use errors::{EngineResult, ErrorKind};
+
+/// The maximum size of one MIDI event (including sysex messages), in bytes.
+pub const MAX_MIDI_EVENT_SIZE: usize = 64;
+
+/// One MIDI message, to be sent at a given time.
+///
+/// This is a fixed-size type, so that it can be passed to the audio thread without
+/// allocating.
+#[derive(Copy, Clone)]
+pub struct MidiEvent {
+    /// When (from the system's monotonic clock) the event should be sent.
+    pub time: u64,
+    /// An identifier for whoever sent the event, used to cancel it (see
+    /// `EngineContext::cancel_midi`).
+    pub owner: Uuid,
+    len: usize,
+    data: [u8; MAX_MIDI_EVENT_SIZE]
+}
+impl MidiEvent {
+    /// Make a new event, containing one complete MIDI message.
+    ///
+    /// # Errors
+    ///
+    /// - `MidiEventTooLong`: if `msg` is longer than `MAX_MIDI_EVENT_SIZE`.
+    pub fn new(time: u64, owner: Uuid, msg: &[u8]) -> EngineResult<Self> {
+        if msg.len() > MAX_MIDI_EVENT_SIZE {
+            Err(ErrorKind::MidiEventTooLong)?
+        }
+        let mut data = [0; MAX_MIDI_EVENT_SIZE];
+        data[..msg.len()].copy_from_slice(msg);
+        Ok(MidiEvent { time, owner, len: msg.len(), data })
+    }
+    /// Get the MIDI message this event contains.
+    pub fn data(&self) -> &[u8] {
+        &self.data[..self.len]
+    }
+}
+impl ::std::fmt::Debug for MidiEvent {
+    fn fmt(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
+        write!(f, "MidiEvent {{ time: {}, owner: {}, data: {:?} }}", self.time, self.owner, self.data())
+    }
+}
@@ -16,11 +16,54 @@ pub struct FadeDetails<T> where T: Mul<f32, Output=T> + Sub<T, Output=T> + Add<T
     active: Arc<AtomicBool>,
     id_ptr: Arc<()>
 }
+/// The shape of a fade, describing how far through the change in value a fade is, given how
+/// far through its duration it is.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum FadeCurve {
+    /// A straight line.
+    Linear,
+    /// Slow at first, then fast (a quadratic curve).
+    Exponential,
+    /// Fast at first, then slow (the mirror image of `Exponential`).
+    Logarithmic,
+    /// Slow at either end, and fast in the middle.
+    SCurve
+}
+impl Default for FadeCurve {
+    fn default() -> Self {
+        FadeCurve::Linear
+    }
+}
+impl FadeCurve {
+    /// Map a proportion of the fade's duration (0.0 to 1.0) to a proportion of its change in value.
+    pub fn apply(&self, x: f32) -> f32 {
+        use self::FadeCurve::*;
+        match *self {
+            Linear => x,
+            Exponential => x * x,
+            Logarithmic => 1.0 - (1.0 - x) * (1.0 - x),
+            SCurve => x * x * (3.0 - 2.0 * x)
+        }
+    }
+    /// Get the curve that, going the other way, is the mirror image of this one.
+    ///
+    /// For example, a fade in along an `Exponential` curve sounds like the reverse of a fade
+    /// out along a `Logarithmic` one.
+    pub fn mirror(&self) -> Self {
+        use self::FadeCurve::*;
+        match *self {
+            Exponential => Logarithmic,
+            Logarithmic => Exponential,
+            x => x
+        }
+    }
+}
 #[derive(Clone, Debug)]
 pub enum Parameter<T> where T: Mul<f32, Output=T> + Sub<T, Output=T> + Add<T, Output=T> + Copy + Display {
     Raw(T),
     TimedRaw(T, u64, T),
-    LinearFade(FadeDetails<T>)
+    LinearFade(FadeDetails<T>),
+    CurvedFade(FadeDetails<T>, FadeCurve)
 }
 impl<T> Parameter<T> where T: Mul<f32, Output=T> + Sub<T, Output=T> + Add<T, Output=T> + Copy + Display {
     pub fn handle_linear(fd: &FadeDetails<T>, time: u64) -> T {
@@ -34,7 +77,8 @@ impl<T> Parameter<T> where T: Mul<f32, Output=T> + Sub<T, Output=T> + Add<T, Out
                 if time >= thresh { now }
                 else { before }
             },
-            LinearFade(ref fd) => Self::handle_linear(fd, time)
+            LinearFade(ref fd) => Self::handle_linear(fd, time),
+            CurvedFade(ref fd, curve) => fd.from() + (fd.delta() * curve.apply(fd.percentage_complete(time)))
         }
     }
 }
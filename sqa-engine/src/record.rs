@@ -0,0 +1,129 @@
+//! Recording audio from the engine's input ports.
+
+use sqa_jack::JackPort;
+use bounded_spsc_queue::{Producer, Consumer};
+use std::sync::atomic::{AtomicBool, AtomicUsize, AtomicU64};
+use std::sync::atomic::Ordering::*;
+use std::sync::Arc;
+use uuid::Uuid;
+
+/// Holds data about a recording, to be made on the audio thread.
+pub struct Capture {
+    /// The input ports being recorded, and the buffer each one's samples are pushed to.
+    pub chans: Vec<(JackPort, Producer<f32>)>,
+    pub start_time: Arc<AtomicU64>,
+    pub active: Arc<AtomicBool>,
+    pub alive: Arc<AtomicBool>,
+    pub overruns: Arc<AtomicUsize>,
+    pub uuid: Uuid
+}
+impl Drop for Capture {
+    fn drop(&mut self) {
+        self.active.store(false, Relaxed);
+        self.alive.store(false, Relaxed);
+    }
+}
+/// Corresponds to, and controls, a `Capture` in the audio thread.
+///
+/// Whilst active, the `Capture` pushes one sample per frame into each of the recorder's
+/// buffers (one per input), always the same amount into each, so reading the same number of
+/// samples from each buffer gives you aligned audio.
+pub struct Recorder<T> {
+    /// Whether this recorder is recording (rw)
+    active: Arc<AtomicBool>,
+    /// Whether this recorder is dead (rw)
+    alive: Arc<AtomicBool>,
+    /// When (from the system's monotonic clock) recording should begin (rw)
+    start_time: Arc<AtomicU64>,
+    /// How many times audio was thrown away because the buffers were full (read only)
+    overruns: Arc<AtomicUsize>,
+    /// The buffers to read from (or not) - will be a `Vec<bounded_spsc_queue::Consumer<f32>>` or `()`.
+    pub bufs: T,
+    /// The sample rate of the recorded audio.
+    pub sample_rate: u64,
+    /// Whether this recorder was the original, or a clone.
+    original: bool,
+    uuid: Uuid
+}
+/// A `Recorder` which can read the recorded audio.
+pub type BufferRecorder = Recorder<Vec<Consumer<f32>>>;
+/// A `Recorder` which cannot read the recorded audio.
+pub type PlainRecorder = Recorder<()>;
+impl<T> Recorder<T> {
+    /// Set whether this recorder is recording.
+    pub fn set_active(&mut self, active: bool) {
+        self.active.store(active, Relaxed);
+    }
+    pub fn active(&self) -> bool {
+        self.active.load(Relaxed)
+    }
+    /// Query whether this recorder is alive. If this function returns `false`, the audio thread
+    /// isn't processing it (either because it was dropped, or because the engine's
+    /// `max_captures` was exceeded).
+    pub fn alive(&self) -> bool {
+        self.alive.load(Relaxed)
+    }
+    /// Set the time (from the system's monotonic clock) before which nothing is recorded.
+    pub fn set_start_time(&mut self, st: u64) {
+        self.start_time.store(st, Relaxed);
+    }
+    /// Get the number of times the audio thread had to throw audio away because the
+    /// buffers were full, i.e. they weren't read from quickly enough.
+    pub fn overruns(&self) -> usize {
+        self.overruns.load(Relaxed)
+    }
+    /// Make a `PlainRecorder` from this recorder.
+    pub fn make_plain(&self) -> PlainRecorder {
+        Recorder {
+            active: self.active.clone(),
+            alive: self.alive.clone(),
+            start_time: self.start_time.clone(),
+            overruns: self.overruns.clone(),
+            bufs: (),
+            sample_rate: self.sample_rate,
+            original: false,
+            uuid: self.uuid
+        }
+    }
+    /// Get this recorder's UUID.
+    pub fn uuid(&self) -> Uuid {
+        self.uuid
+    }
+}
+impl<T> Drop for Recorder<T> {
+    /// If this recorder was the original: stops recording, setting `alive` to false.
+    fn drop(&mut self) {
+        if self.original {
+            self.active.store(false, Relaxed);
+            self.alive.store(false, Relaxed);
+        }
+    }
+}
+pub(crate) fn make_recorder(ports: Vec<JackPort>, sample_rate: u64, buffer_size: usize) -> (Capture, BufferRecorder) {
+    let active = Arc::new(AtomicBool::new(false));
+    let alive = Arc::new(AtomicBool::new(false));
+    let start_time = Arc::new(AtomicU64::new(0));
+    let overruns = Arc::new(AtomicUsize::new(0));
+    let uu = Uuid::new_v4();
+    let mut chans = Vec::with_capacity(ports.len());
+    let mut bufs = Vec::with_capacity(ports.len());
+    for port in ports {
+        let (p, c) = ::bounded_spsc_queue::make(buffer_size);
+        chans.push((port, p));
+        bufs.push(c);
+    }
+    let cap = Capture {
+        chans,
+        start_time: start_time.clone(),
+        active: active.clone(),
+        alive: alive.clone(),
+        overruns: overruns.clone(),
+        uuid: uu
+    };
+    let rec = Recorder {
+        active, alive, start_time, overruns, bufs, sample_rate,
+        original: true,
+        uuid: uu
+    };
+    (cap, rec)
+}
@@ -4,17 +4,19 @@ use parking_lot::{Mutex, Condvar};
 use std::sync::Arc;
 use std::time::{Duration, Instant};
 use bounded_spsc_queue::{Producer, Consumer};
-use super::CONTROL_BUFFER_SIZE;
 use bounded_spsc_queue;
 use uuid::Uuid;
+use midi::MidiEvent;
+use ltc::LtcFrame;
 
 pub use thread::Player;
+pub use record::Capture;
 
 /// A message from the audio thread.
 pub enum AudioThreadMessage {
     /// The player with a given `Uuid` was successfully added.
     PlayerAdded(Uuid),
-    /// This player was rejected due to you exceeding `MAX_PLAYERS`.
+    /// This player was rejected due to you exceeding the engine's `max_players`.
     PlayerRejected(Player),
     /// This player was removed on account of not being `alive`.
     PlayerRemoved(Player),
@@ -34,47 +36,85 @@ pub enum AudioThreadMessage {
     /// refill the buffer and your audio has now stopped. In the latter case, you OUGHT TO refill the
     /// buffer.
     PlayerBufEmpty(Uuid),
+    /// The capture with a given `Uuid` was successfully added.
+    CaptureAdded(Uuid),
+    /// This capture was rejected due to you exceeding the engine's `max_captures`.
+    CaptureRejected(Capture),
+    /// This capture was removed on account of not being `alive`.
+    CaptureRemoved(Capture),
     /// The audio thread has experienced an under- or over- run.
     ///
     /// This REALLY SHOULD NOT happen under normal circumstances. If your sample rate and buffer size
     /// are set to reasonable values, [file a bug!](https://github.com/eeeeeta/sqa-engine).
     /// You MAY WISH TO inform the user that their audio just glitched, and that they should adjust
     /// the sample rate and buffer size to remedy the problem.
-    Xrun
+    Xrun,
+    /// A port with the given full name was registered (`true`) or unregistered (`false`) in the
+    /// JACK graph.
+    ///
+    /// This includes ports belonging to other clients, e.g. those of an audio interface that was
+    /// just plugged in.
+    PortRegistered(String, bool),
+    /// The two ports with the given full names were connected (`true`) or disconnected (`false`).
+    PortConnected(String, String, bool),
+    /// A JACK client with the given name was registered (`true`) or unregistered (`false`).
+    ClientRegistered(String, bool),
+    /// A MIDI message was received on the engine's MIDI input port (`midi_in`).
+    ///
+    /// The event's `time` is when it was received, and its `owner` is the nil UUID.
+    MidiReceived(MidiEvent),
+    /// A complete frame of linear timecode was decoded from the engine's LTC input port
+    /// (`ltc_in`).
+    LtcReceived(LtcFrame),
+    /// The JACK server has shut down, for the given reason.
+    ///
+    /// The engine is now useless: no further audio will be processed, and you MUST create a new
+    /// `EngineContext` if you want to carry on.
+    Shutdown(String)
 }
 
 /// A commmunication channel to receive messages from the audio thread.
+///
+/// Messages come from two places: the realtime audio thread itself, and the JACK notification
+/// thread (which delivers things like xruns and graph changes). Each has its own queue, but
+/// this handle receives from both.
 pub struct AudioThreadHandle {
     inner: Arc<(Mutex<()>, Condvar)>,
-    rx: Consumer<AudioThreadMessage>
+    rx: Consumer<AudioThreadMessage>,
+    notif_rx: Consumer<AudioThreadMessage>
 }
 impl AudioThreadHandle {
-    pub(crate) unsafe fn make() -> (AudioThreadHandle, AudioThreadSender) {
-        let (p, c) = bounded_spsc_queue::make(CONTROL_BUFFER_SIZE);
+    pub(crate) unsafe fn make(size: usize) -> (AudioThreadHandle, AudioThreadSender, AudioThreadNotifier) {
+        let (p, c) = bounded_spsc_queue::make(size);
+        let (np, nc) = bounded_spsc_queue::make(size);
         let arc = Arc::new((Mutex::new(()), Condvar::new()));
         (AudioThreadHandle {
             inner: arc.clone(),
-            rx: c
+            rx: c,
+            notif_rx: nc
         }, AudioThreadSender {
-            inner: arc,
+            inner: arc.clone(),
             tx: p,
             written_t: 0,
             cur_t: 1
+        }, AudioThreadNotifier {
+            inner: arc,
+            tx: np
         })
     }
     /// Attempt to receive a message from the audio thread, returning `None` if none is available.
     pub fn try_recv(&mut self) -> Option<AudioThreadMessage> {
-        self.rx.try_pop()
+        self.rx.try_pop().or_else(|| self.notif_rx.try_pop())
     }
     /// Wait (forever, if necessary) until a message is available, and return it.
     ///
     /// This blocks the thread on a condition variable, consuming no CPU time whilst blocked.
     pub fn recv(&mut self) -> AudioThreadMessage {
-        if let Some(x) = self.rx.try_pop() { return x; }
+        if let Some(x) = self.try_recv() { return x; }
         let mut lock = self.inner.0.lock();
         loop {
             self.inner.1.wait(&mut lock);
-            if let Some(x) = self.rx.try_pop() { return x; }
+            if let Some(x) = self.try_recv() { return x; }
         }
     }
     /// Wait until a message is available, timing out after the specified time instant. Return a
@@ -88,10 +128,10 @@ impl AudioThreadHandle {
     /// Note that the best effort is made to ensure that the time waited is measured with a monotonic
     /// clock, and not affected by the changes made to the system time.
     pub fn wait_until(&mut self, timeout: Instant) -> Option<AudioThreadMessage> {
-        if let Some(x) = self.rx.try_pop() { return Some(x); }
+        if let Some(x) = self.try_recv() { return Some(x); }
         let mut lock = self.inner.0.lock();
         self.inner.1.wait_until(&mut lock, timeout);
-        self.rx.try_pop()
+        self.try_recv()
     }
     /// Wait until a message is available, timing out after a specified duration. Return a
     /// message if obtained in the time period, otherwise `None`.
@@ -104,10 +144,10 @@ impl AudioThreadHandle {
     /// Note that the best effort is made to ensure that the time waited is measured with a monotonic
     /// clock, and not affected by the changes made to the system time.
     pub fn wait_for(&mut self, timeout: Duration) -> Option<AudioThreadMessage> {
-        if let Some(x) = self.rx.try_pop() { return Some(x); }
+        if let Some(x) = self.try_recv() { return Some(x); }
         let mut lock = self.inner.0.lock();
         self.inner.1.wait_for(&mut lock, timeout);
-        self.rx.try_pop()
+        self.try_recv()
     }
 }
 pub(crate) struct AudioThreadSender {
@@ -137,3 +177,17 @@ impl AudioThreadSender {
         }
     }
 }
+/// Sends messages from the JACK notification thread (as opposed to the realtime audio thread).
+pub(crate) struct AudioThreadNotifier {
+    inner: Arc<(Mutex<()>, Condvar)>,
+    tx: Producer<AudioThreadMessage>
+}
+impl AudioThreadNotifier {
+    pub(crate) fn send(&mut self, data: AudioThreadMessage) {
+        if let Some(remnant) = self.tx.try_push(data) {
+            // Same reasoning as in AudioThreadSender::send().
+            ::std::mem::forget(remnant);
+        }
+        self.inner.1.notify_one();
+    }
+}
@@ -1,17 +1,19 @@
 //! Types used in the realtime audio thread.
 
 use sqa_jack::*;
-use arrayvec::ArrayVec;
-use super::{MAX_PLAYERS, MAX_CHANS, ONE_SECOND_IN_NANOSECONDS};
+use super::ONE_SECOND_IN_NANOSECONDS;
 use bounded_spsc_queue::Consumer;
 use std::sync::atomic::{AtomicBool, AtomicUsize, AtomicU64, AtomicPtr};
 use std::sync::atomic::Ordering::*;
 use std::sync::Arc;
 use uuid::Uuid;
 use time;
-use sync::AudioThreadSender;
+use sync::{AudioThreadSender, AudioThreadNotifier};
 use sync::AudioThreadMessage::*;
 use param::Parameter;
+use midi::MidiEvent;
+use ltc::{LtcDecoder, LtcFrame};
+use record::Capture;
 
 /// Holds data about one mono channel of audio, to be played back on the audio thread.
 pub struct Player {
@@ -23,8 +25,11 @@ pub struct Player {
     pub alive: Arc<AtomicBool>,
     pub kill_when_empty: Arc<AtomicBool>,
     pub output_patch: Arc<AtomicUsize>,
+    pub buffer_fill: Arc<AtomicUsize>,
     pub volume: Arc<AtomicPtr<Parameter<f32>>>,
     pub master_vol: Arc<AtomicPtr<Parameter<f32>>>,
+    /// If set, the player plays what's coming in on this port, rather than its buffer.
+    pub input: Option<JackPort>,
     pub uuid: Uuid,
     pub half_sent: bool,
     pub empty_sent: bool
@@ -38,8 +43,10 @@ impl Drop for Player {
 
 pub enum AudioThreadCommand {
     AddPlayer(Player),
+    AddCapture(Capture),
     AddChannel(JackPort),
-    RemoveChannel(usize)
+    RemoveChannel(usize),
+    CancelMidi(Uuid)
 }
 
 /// A channel in the device context.
@@ -54,13 +61,28 @@ pub struct DeviceChannel {
 }
 
 /// Audio thread handler.
+///
+/// The `Vec`s in here are preallocated to the engine's configured limits, and MUST NOT be
+/// grown past them (that would allocate in the audio thread).
 pub struct DeviceContext {
-    pub players: ArrayVec<[Player; MAX_PLAYERS]>,
-    pub chans: ArrayVec<[Option<DeviceChannel>; MAX_CHANS]>,
-    pub holes: ArrayVec<[usize; MAX_CHANS]>,
+    pub players: Vec<Player>,
+    pub max_players: usize,
+    pub captures: Vec<Capture>,
+    pub max_captures: usize,
+    pub chans: Vec<Option<DeviceChannel>>,
+    pub holes: Vec<usize>,
     pub control: Consumer<AudioThreadCommand>,
+    pub midi_out: JackPort,
+    pub midi_in: JackPort,
+    pub midi_queue: Consumer<MidiEvent>,
+    /// MIDI events waiting to be sent, in order of time.
+    pub midi_pending: Vec<MidiEvent>,
+    pub ltc_in: JackPort,
+    pub ltc: LtcDecoder,
     pub length: Arc<AtomicUsize>,
+    pub xruns: Arc<AtomicUsize>,
     pub(crate) sender: AudioThreadSender,
+    pub(crate) notifier: AudioThreadNotifier,
     pub sample_rate: u64
 }
 impl DeviceContext {
@@ -69,20 +91,33 @@ impl DeviceContext {
         match cmd {
             AudioThreadCommand::AddPlayer(p) => {
                 let uu = p.uuid;
-                if let Some(p) = self.players.push(p) {
+                if self.players.len() >= self.max_players {
                     self.sender.send(PlayerRejected(p));
                 }
                 else {
+                    self.players.push(p);
                     let len = self.length.load(Acquire);
                     self.length.store(len + 1, Release);
                     self.players[self.players.len()-1].alive.store(true, Release);
                     self.sender.send(PlayerAdded(uu));
                 }
             },
+            AudioThreadCommand::AddCapture(c) => {
+                let uu = c.uuid;
+                if self.captures.len() >= self.max_captures {
+                    self.sender.send(CaptureRejected(c));
+                }
+                else {
+                    self.captures.push(c);
+                    self.captures[self.captures.len()-1].alive.store(true, Release);
+                    self.sender.send(CaptureAdded(uu));
+                }
+            },
             AudioThreadCommand::AddChannel(p) => {
                 /* NOTE: This code must mirror the code in lib.rs */
                 let ch = DeviceChannel { port: p, written_t: 0, zeroed_t: 0 };
-                if let Some(ix) = self.holes.remove(0) {
+                if self.holes.len() > 0 {
+                    let ix = self.holes.remove(0);
                     self.chans[ix] = Some(ch);
                 }
                 else {
@@ -91,28 +126,147 @@ impl DeviceContext {
             },
             AudioThreadCommand::RemoveChannel(ch) => {
                 /* NOTE: This code must mirror the code in lib.rs */
-                self.chans.push(None);
-                self.chans.swap_remove(ch);
+                self.chans[ch] = None;
                 self.holes.push(ch);
             },
+            AudioThreadCommand::CancelMidi(owner) => {
+                /* Events still in the queue could belong to `owner` too, so get them out
+                 * first (making room as we go, if `midi_pending` is full). */
+                loop {
+                    self.pull_midi_queue();
+                    let len = self.midi_pending.len();
+                    self.midi_pending.retain(|ev| ev.owner != owner);
+                    if self.midi_pending.len() == len || self.midi_queue.size() == 0 {
+                        break;
+                    }
+                }
+            }
+        }
+    }
+    /// Move as many events as will fit from `midi_queue` into `midi_pending`, keeping the
+    /// latter sorted by time.
+    #[inline(always)]
+    fn pull_midi_queue(&mut self) {
+        while self.midi_pending.len() < self.midi_pending.capacity() {
+            match self.midi_queue.try_pop() {
+                Some(ev) => {
+                    let idx = self.midi_pending.iter()
+                        .position(|x| x.time > ev.time)
+                        .unwrap_or(self.midi_pending.len());
+                    self.midi_pending.insert(idx, ev);
+                },
+                None => break
+            }
+        }
+    }
+    #[inline(always)]
+    fn process_midi(&mut self, out: &JackCallbackContext, time: u64) {
+        self.pull_midi_queue();
+        if let Some(mut buf) = out.get_midi_output_buffer(&self.midi_out) {
+            let nframes = out.nframes() as u64;
+            let mut sent = 0;
+            for ev in self.midi_pending.iter() {
+                let frame = if ev.time > time {
+                    (ev.time - time) * self.sample_rate / ONE_SECOND_IN_NANOSECONDS
+                } else { 0 };
+                if frame >= nframes {
+                    break;
+                }
+                /* If this fails, the buffer's full; there isn't much we can do about it. */
+                let _ = buf.write(frame as JackNFrames, ev.data());
+                sent += 1;
+            }
+            self.midi_pending.drain(..sent);
+        }
+        if let Some(buf) = out.get_midi_input_buffer(&self.midi_in) {
+            for ev in buf {
+                let ev_time = time + ev.time as u64 * ONE_SECOND_IN_NANOSECONDS / self.sample_rate;
+                /* Events too long to fit are sysex we don't care about anyway. */
+                if let Ok(ev) = MidiEvent::new(ev_time, Uuid::nil(), ev.data) {
+                    self.sender.send(MidiReceived(ev));
+                }
+            }
+        }
+    }
+    #[inline(always)]
+    fn process_captures(&mut self, out: &JackCallbackContext, time: u64) {
+        let nframes = out.nframes() as usize;
+        let mut to_remove = None;
+        for (idx, cap) in self.captures.iter_mut().enumerate() {
+            if !cap.alive.load(Relaxed) {
+                if to_remove.is_none() {
+                    to_remove = Some(idx);
+                }
+                continue;
+            }
+            if !cap.active.load(Relaxed) || cap.start_time.load(Relaxed) > time {
+                continue;
+            }
+            /* Keep the channels aligned: either everything fits, or nothing gets written. */
+            if cap.chans.iter().any(|&(_, ref p)| p.capacity() - p.size() < nframes) {
+                cap.overruns.fetch_add(1, Relaxed);
+                continue;
+            }
+            for &mut (ref port, ref mut p) in cap.chans.iter_mut() {
+                match out.get_port_buffer(port) {
+                    Some(buf) => for x in buf.iter() {
+                        p.try_push(*x);
+                    },
+                    None => for _ in 0..nframes {
+                        p.try_push(0.0);
+                    }
+                }
+            }
+        }
+        if let Some(x) = to_remove {
+            let c = self.captures.swap_remove(x);
+            self.sender.send(CaptureRemoved(c));
+        }
+    }
+    #[inline(always)]
+    fn process_ltc(&mut self, out: &JackCallbackContext, time: u64) {
+        if let Some(buf) = out.get_port_buffer(&self.ltc_in) {
+            for (i, x) in buf.iter().enumerate() {
+                if let Some(bits) = self.ltc.feed(*x) {
+                    let time = time + i as u64 * ONE_SECOND_IN_NANOSECONDS / self.sample_rate;
+                    self.sender.send(LtcReceived(LtcFrame { time, bits }));
+                }
+            }
         }
     }
 }
 impl JackHandler for DeviceContext {
-    #[inline(always)]
     fn xrun(&mut self) -> JackControl {
-        self.sender.init(0);
-        self.sender.send(Xrun);
-        self.sender.notify();
+        self.xruns.fetch_add(1, Relaxed);
+        self.notifier.send(Xrun);
         JackControl::Continue
     }
+    fn port_registered(&mut self, port: JackPort, registered: bool) {
+        if let Ok(name) = port.get_name(false) {
+            self.notifier.send(PortRegistered(name.into_owned(), registered));
+        }
+    }
+    fn port_connected(&mut self, from: JackPort, to: JackPort, connected: bool) {
+        if let (Ok(from), Ok(to)) = (from.get_name(false), to.get_name(false)) {
+            self.notifier.send(PortConnected(from.into_owned(), to.into_owned(), connected));
+        }
+    }
+    fn client_registered(&mut self, name: &str, registered: bool) {
+        self.notifier.send(ClientRegistered(name.into(), registered));
+    }
+    fn shutdown(&mut self, _status: JackStatus, reason: &str) {
+        self.notifier.send(Shutdown(reason.into()));
+    }
     #[inline(always)]
     fn process(&mut self, out: &JackCallbackContext) -> JackControl {
         let time = time::precise_time_ns();
         self.sender.init(time);
-        if let Some(cmd) = self.control.try_pop() {
+        while let Some(cmd) = self.control.try_pop() {
             self.handle(cmd);
         }
+        self.process_midi(out, time);
+        self.process_ltc(out, time);
+        self.process_captures(out, time);
         let mut to_remove = None;
         'outer: for (idx, player) in self.players.iter_mut().enumerate() {
             if !player.alive.load(Relaxed) {
@@ -121,6 +275,7 @@ impl JackHandler for DeviceContext {
                 }
                 continue;
             }
+            player.buffer_fill.store(player.buf.size(), Relaxed);
             if !player.active.load(Relaxed) {
                 continue;
             }
@@ -130,6 +285,44 @@ impl JackHandler for DeviceContext {
                 player.position.store(0, Relaxed);
                 continue;
             }
+            if let Some(input) = player.input {
+                if outpatch >= self.chans.len() || self.chans[outpatch].is_none() {
+                    self.sender.send(PlayerInvalidOutpatch(player.uuid));
+                    player.active.store(false, Relaxed);
+                    continue;
+                }
+                let volp = player.volume.load(Acquire);
+                let master_volp = player.master_vol.load(Acquire);
+                let (vol, master_vol) = unsafe {
+                    ((*volp).clone(), (*master_volp).clone())
+                };
+                player.volume.store(volp, Release);
+                player.master_vol.store(master_volp, Release);
+                let sample_rate = self.sample_rate;
+                let ch = self.chans[outpatch].as_mut().unwrap();
+                if let (Some(ibuf), Some(obuf)) = (out.get_port_buffer(&input), out.get_port_buffer(&ch.port)) {
+                    let written = time == ch.written_t;
+                    if !written {
+                        ch.written_t = time;
+                    }
+                    for (i, (x, data)) in obuf.iter_mut().zip(ibuf.iter()).enumerate() {
+                        /* Evaluate the volume for every sample, so fades don't step once per period. */
+                        let t = time + (i as u64 * ONE_SECOND_IN_NANOSECONDS) / sample_rate;
+                        let vol = vol.get(t) * master_vol.get(t);
+                        if written {
+                            *x += *data * vol;
+                        }
+                        else {
+                            *x = *data * vol;
+                        }
+                        if *x > 1.0 { *x = 1.0; }
+                        if *x < -1.0 { *x = -1.0; }
+                    }
+                }
+                let pos = player.position.load(Relaxed);
+                player.position.store(pos + out.nframes() as u64, Relaxed);
+                continue;
+            }
             let sample_delta = (time - start_time) * self.sample_rate / ONE_SECOND_IN_NANOSECONDS;
             let mut pos = player.position.load(Relaxed);
             if pos < sample_delta {
@@ -160,27 +353,27 @@ impl JackHandler for DeviceContext {
             }
             let volp = player.volume.load(Acquire);
             let master_volp = player.master_vol.load(Acquire);
-            let vol = unsafe {
-                (*volp).get(time)
-            };
-            let master_vol = unsafe {
-                (*master_volp).get(time)
+            let (vol, master_vol) = unsafe {
+                ((*volp).clone(), (*master_volp).clone())
             };
             player.volume.store(volp, Release);
             player.master_vol.store(master_volp, Release);
+            let sample_rate = self.sample_rate;
             let ch = self.chans[outpatch].as_mut().unwrap();
             if let Some(buf) = out.get_port_buffer(&ch.port) {
                 let written = time == ch.written_t;
                 if !written {
                     ch.written_t = time;
                 }
-                for x in buf.iter_mut() {
+                for (i, x) in buf.iter_mut().enumerate() {
                     if let Some(data) = player.buf.try_pop() {
+                        let t = time + (i as u64 * ONE_SECOND_IN_NANOSECONDS) / sample_rate;
+                        let vol = vol.get(t) * master_vol.get(t);
                         if written {
-                            *x += data * vol * master_vol;
+                            *x += data * vol;
                         }
                         else {
-                            *x = data * vol * master_vol;
+                            *x = data * vol;
                         }
                         if *x > 1.0 { *x = 1.0; }
                         if *x < -1.0 { *x = -1.0; }
@@ -191,9 +384,8 @@ impl JackHandler for DeviceContext {
             player.position.store(pos, Relaxed);
         }
         if let Some(x) = to_remove {
-            if let Some(p) = self.players.swap_remove(x) {
-                self.sender.send(PlayerRemoved(p));
-            }
+            let p = self.players.swap_remove(x);
+            self.sender.send(PlayerRemoved(p));
             self.length.store(self.length.load(Relaxed) - 1, Relaxed);
         }
         for ch in self.chans.iter_mut() {
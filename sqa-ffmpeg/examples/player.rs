@@ -18,7 +18,7 @@ fn main() {
     let mut buffer = String::new();
     stdin.read_line(&mut buffer).unwrap();
     let mut file = MediaFile::new(&mut mctx, &buffer.trim()).unwrap();
-    let mut ec = EngineContext::new(None).unwrap();
+    let mut ec = EngineContext::new(None, Default::default()).unwrap();
     let mut chans = vec![];
     let mut ctls = vec![];
     for ch in 0..file.channels() {
@@ -40,5 +40,11 @@ error_chain! {
         NotPowerOfTwo {
             description("The new buffer size was not a power of two.")
         }
+        MidiBufferFull {
+            description("There was no space left in the MIDI buffer for the event.")
+        }
+        MidiEventOutOfOrder {
+            description("MIDI events must be written in order of time, and within the current buffer.")
+        }
     }
 }
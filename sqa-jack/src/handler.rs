@@ -1,10 +1,11 @@
 //! Callback-based JACK API functions (logging, processing).
 
-use super::{JackNFrames, JackPort, JackStatus, JackConnection, Deactivated};
+use super::{JackNFrames, JackPort, JackStatus, JackConnection, JackLatencyMode, Deactivated};
 use jack_sys::*;
 use std::panic::{AssertUnwindSafe, catch_unwind};
 use std::sync::atomic::{AtomicPtr, Ordering};
 use std::ffi::CStr;
+use std::marker::PhantomData;
 use libc;
 use errors::*;
 
@@ -76,6 +77,125 @@ impl JackCallbackContext {
             }
         }
     }
+    /// Gets the buffer of a MIDI output port, if the port is valid.
+    ///
+    /// The buffer is cleared of any events from the last callback. You MUST call this for
+    /// every MIDI output port in every callback, even if you have nothing to write,
+    /// otherwise whatever was in the buffer last time gets sent again.
+    pub fn get_midi_output_buffer(&self, port: &JackPort) -> Option<JackMidiOutputBuffer> {
+        unsafe {
+            let buf = jack_port_get_buffer(port.as_ptr(), self.nframes);
+            if buf.is_null() {
+                None
+            }
+            else {
+                jack_midi_clear_buffer(buf);
+                Some(JackMidiOutputBuffer {
+                    buf: buf,
+                    nframes: self.nframes,
+                    _ctx: PhantomData
+                })
+            }
+        }
+    }
+    /// Gets the buffer of a MIDI input port, if the port is valid.
+    pub fn get_midi_input_buffer(&self, port: &JackPort) -> Option<JackMidiInputBuffer> {
+        unsafe {
+            let buf = jack_port_get_buffer(port.as_ptr(), self.nframes);
+            if buf.is_null() {
+                None
+            }
+            else {
+                Some(JackMidiInputBuffer {
+                    buf: buf,
+                    len: jack_midi_get_event_count(buf),
+                    idx: 0,
+                    _ctx: PhantomData
+                })
+            }
+        }
+    }
+}
+/// A MIDI event received on a MIDI input port.
+#[derive(Copy, Clone, Debug)]
+pub struct JackMidiEvent<'a> {
+    /// The offset of the event, in frames, from the start of the buffer.
+    pub time: JackNFrames,
+    /// The raw MIDI data of the event.
+    pub data: &'a [u8]
+}
+/// The buffer of a MIDI input port, for one callback.
+///
+/// This is an `Iterator` over the events in the buffer, in order of time.
+pub struct JackMidiInputBuffer<'a> {
+    buf: *mut libc::c_void,
+    len: u32,
+    idx: u32,
+    _ctx: PhantomData<&'a JackCallbackContext>
+}
+impl<'a> JackMidiInputBuffer<'a> {
+    /// Get the number of events in the buffer.
+    pub fn len(&self) -> u32 {
+        self.len
+    }
+}
+impl<'a> Iterator for JackMidiInputBuffer<'a> {
+    type Item = JackMidiEvent<'a>;
+
+    fn next(&mut self) -> Option<JackMidiEvent<'a>> {
+        while self.idx < self.len {
+            let idx = self.idx;
+            self.idx += 1;
+            unsafe {
+                let mut ev: jack_midi_event_t = ::std::mem::zeroed();
+                if jack_midi_event_get(&mut ev, self.buf, idx) == 0 {
+                    return Some(JackMidiEvent {
+                        time: ev.time,
+                        data: ::std::slice::from_raw_parts(ev.buffer as *const u8, ev.size as usize)
+                    });
+                }
+            }
+        }
+        None
+    }
+}
+/// The buffer of a MIDI output port, for one callback.
+pub struct JackMidiOutputBuffer<'a> {
+    buf: *mut libc::c_void,
+    nframes: JackNFrames,
+    _ctx: PhantomData<&'a JackCallbackContext>
+}
+impl<'a> JackMidiOutputBuffer<'a> {
+    /// Write a MIDI event (one complete MIDI message, or a sysex message) to the buffer.
+    ///
+    /// `time` is the offset of the event, in frames, from the start of the buffer. Events
+    /// MUST be written in order of time.
+    ///
+    /// # Errors
+    ///
+    /// - `MidiBufferFull`: if the event won't fit in the buffer
+    /// - `MidiEventOutOfOrder`: if `time` is before the last event, or after the buffer
+    pub fn write(&mut self, time: JackNFrames, data: &[u8]) -> JackResult<()> {
+        if time >= self.nframes {
+            Err(ErrorKind::MidiEventOutOfOrder)?
+        }
+        let code = unsafe {
+            jack_midi_event_write(self.buf, time, data.as_ptr(), data.len() as _)
+        };
+        match code {
+            0 => Ok(()),
+            x if x == -libc::ENOBUFS => Err(ErrorKind::MidiBufferFull)?,
+            x if x == -libc::EINVAL => Err(ErrorKind::MidiEventOutOfOrder)?,
+            x @ _ => Err(ErrorKind::UnknownErrorCode("midi_event_write()", x))?
+        }
+    }
+    /// Get the number of events that couldn't be written to this buffer since the last
+    /// callback, because it was full.
+    pub fn lost_events(&self) -> u32 {
+        unsafe {
+            jack_midi_get_lost_event_count(self.buf)
+        }
+    }
 }
 
 /// Return type of callback functions.
@@ -131,18 +251,27 @@ pub trait JackHandler: Send {
     fn client_registered(&mut self, _name: &str, _registered: bool) { }
     /// Called when an XRUN (over- or under- run) occurs.
     fn xrun(&mut self) -> JackControl { JackControl::Continue }
-}
-/*
+    /// Called whenever the latency of the processing graph changes, once for each
+    /// `JackLatencyMode`.
+    ///
+    /// Clients that only have terminal ports (i.e. no signal path between their inputs and
+    /// outputs) don't need to do anything here, but can use this to find out that the
+    /// value returned by `JackPort::get_latency_range()` may have changed.
+    fn latency(&mut self, _mode: JackLatencyMode) { }
     /// Called whenever a port is registered or unregistered.
     ///
-    /// Use the `registered` argument to determine which it is.
+    /// Use the `registered` argument to determine which it is. When a port is
+    /// unregistered, it is still valid for the duration of this call (so you can get its
+    /// name), but not afterwards.
     fn port_registered(&mut self, _port: JackPort, _registered: bool) { }
-    /// Called whenever a port is renamed.
-    fn port_renamed(&mut self, _port: JackPort, _old_name: &str, _new_name: &str) { }
     /// Called whenever ports are connected or disconnected.
     ///
     /// Use the `connected` argument to determine which it is.
     fn port_connected(&mut self, _from: JackPort, _to: JackPort, _connected: bool) { }
+}
+/*
+    /// Called whenever a port is renamed.
+    fn port_renamed(&mut self, _port: JackPort, _old_name: &str, _new_name: &str) { }
     /// Called whenever the processing graph is reordered.
     fn graph_reorder(&mut self) -> JackControl { JackControl::Continue }
     /// Called when the JACK server starts or stops freewheeling.
@@ -151,25 +280,30 @@ pub trait JackHandler: Send {
     fn freewheel(&mut self, _freewheel: bool) { }
 */
 
+/// The data passed to JACK as the `user` argument of every callback.
+struct HandlerContext<T> {
+    handler: T,
+    client: *mut jack_client_t
+}
 impl<F> JackHandler for F where F: FnMut(&JackCallbackContext) -> JackControl + Send + 'static {
     fn process(&mut self, ctx: &JackCallbackContext) -> JackControl {
         self(ctx)
     }
 }
 unsafe extern "C" fn buffer_size_callback<T>(frames: JackNFrames, user: *mut libc::c_void) -> libc::c_int where T: JackHandler {
-    let callbacks = &mut *(user as *mut T);
+    let callbacks = &mut (*(user as *mut HandlerContext<T>)).handler;
     catch_unwind(AssertUnwindSafe(|| {
         callbacks.buffer_size(frames) as _
     })).unwrap_or(-1)
 }
 unsafe extern "C" fn sample_rate_callback<T>(frames: JackNFrames, user: *mut libc::c_void) -> libc::c_int where T: JackHandler {
-    let callbacks = &mut *(user as *mut T);
+    let callbacks = &mut (*(user as *mut HandlerContext<T>)).handler;
     catch_unwind(AssertUnwindSafe(|| {
         callbacks.sample_rate(frames) as _
     })).unwrap_or(-1)
 }
 unsafe extern "C" fn client_registration_callback<T>(name: *const libc::c_char, register: libc::c_int, user: *mut libc::c_void) where T: JackHandler {
-    let callbacks = &mut *(user as *mut T);
+    let callbacks = &mut (*(user as *mut HandlerContext<T>)).handler;
     let name = CStr::from_ptr(name);
     let _ = catch_unwind(AssertUnwindSafe(|| {
         callbacks.client_registered(&name.to_string_lossy(), register != 0)
@@ -177,7 +311,7 @@ unsafe extern "C" fn client_registration_callback<T>(name: *const libc::c_char,
 
 }
 unsafe extern "C" fn info_shutdown_callback<T>(code: jack_status_t, reason: *const libc::c_char, user: *mut libc::c_void) where T: JackHandler {
-    let callbacks = &mut *(user as *mut T);
+    let callbacks = &mut (*(user as *mut HandlerContext<T>)).handler;
     let code = JackStatus::from_bits_truncate(code);
     let reason = CStr::from_ptr(reason);
     let _ = catch_unwind(AssertUnwindSafe(|| {
@@ -186,13 +320,13 @@ unsafe extern "C" fn info_shutdown_callback<T>(code: jack_status_t, reason: *con
 
 }
 unsafe extern "C" fn thread_init_callback<T>(user: *mut libc::c_void) where T: JackHandler {
-    let callbacks = &mut *(user as *mut T);
+    let callbacks = &mut (*(user as *mut HandlerContext<T>)).handler;
     let _ = catch_unwind(AssertUnwindSafe(|| {
         callbacks.thread_init()
     }));
 }
 unsafe extern "C" fn process_callback<T>(nframes: JackNFrames, user: *mut libc::c_void) -> libc::c_int where T: JackHandler {
-    let callbacks = &mut *(user as *mut T);
+    let callbacks = &mut (*(user as *mut HandlerContext<T>)).handler;
     let ctx = JackCallbackContext {
         nframes: nframes
     };
@@ -201,13 +335,46 @@ unsafe extern "C" fn process_callback<T>(nframes: JackNFrames, user: *mut libc::
     })).unwrap_or(-1)
 }
 unsafe extern "C" fn xrun_callback<T>(user: *mut libc::c_void) -> libc::c_int where T: JackHandler {
-    let callbacks = &mut *(user as *mut T);
+    let callbacks = &mut (*(user as *mut HandlerContext<T>)).handler;
     catch_unwind(AssertUnwindSafe(|| {
         callbacks.xrun() as _
     })).unwrap_or(-1)
 }
+unsafe extern "C" fn latency_callback<T>(mode: jack_latency_callback_mode_t, user: *mut libc::c_void) where T: JackHandler {
+    let callbacks = &mut (*(user as *mut HandlerContext<T>)).handler;
+    let _ = catch_unwind(AssertUnwindSafe(|| {
+        callbacks.latency(JackLatencyMode::from_raw(mode))
+    }));
+}
+unsafe extern "C" fn port_registration_callback<T>(port: jack_port_id_t, register: libc::c_int, user: *mut libc::c_void) where T: JackHandler {
+    let ctx = &mut *(user as *mut HandlerContext<T>);
+    let ptr = jack_port_by_id(ctx.client, port);
+    if ptr.is_null() {
+        return;
+    }
+    let port = JackPort::from_ptr(ptr);
+    let callbacks = &mut ctx.handler;
+    let _ = catch_unwind(AssertUnwindSafe(|| {
+        callbacks.port_registered(port, register != 0)
+    }));
+}
+unsafe extern "C" fn port_connect_callback<T>(a: jack_port_id_t, b: jack_port_id_t, connect: libc::c_int, user: *mut libc::c_void) where T: JackHandler {
+    let ctx = &mut *(user as *mut HandlerContext<T>);
+    let (a, b) = (jack_port_by_id(ctx.client, a), jack_port_by_id(ctx.client, b));
+    if a.is_null() || b.is_null() {
+        return;
+    }
+    let (a, b) = (JackPort::from_ptr(a), JackPort::from_ptr(b));
+    let callbacks = &mut ctx.handler;
+    let _ = catch_unwind(AssertUnwindSafe(|| {
+        callbacks.port_connected(a, b, connect != 0)
+    }));
+}
 pub fn set_handler<F>(conn: &mut JackConnection<Deactivated>, handler: F) -> JackResult<()> where F: JackHandler {
-    let user_ptr = Box::into_raw(Box::new(handler));
+    let user_ptr = Box::into_raw(Box::new(HandlerContext {
+        handler: handler,
+        client: conn.handle
+    }));
     let user_ptr = user_ptr as *mut libc::c_void;
     unsafe {
         let code = jack_set_process_callback(conn.handle, Some(process_callback::<F>), user_ptr);
@@ -222,6 +389,12 @@ pub fn set_handler<F>(conn: &mut JackConnection<Deactivated>, handler: F) -> Jac
         if code != 0 { Err(ErrorKind::UnknownErrorCode("set_process_callback() - xrun", code))? }
         let code = jack_set_client_registration_callback(conn.handle, Some(client_registration_callback::<F>), user_ptr);
         if code != 0 { Err(ErrorKind::UnknownErrorCode("set_process_callback() - client_registration", code))? }
+        let code = jack_set_port_registration_callback(conn.handle, Some(port_registration_callback::<F>), user_ptr);
+        if code != 0 { Err(ErrorKind::UnknownErrorCode("set_process_callback() - port_registration", code))? }
+        let code = jack_set_port_connect_callback(conn.handle, Some(port_connect_callback::<F>), user_ptr);
+        if code != 0 { Err(ErrorKind::UnknownErrorCode("set_process_callback() - port_connect", code))? }
+        let code = jack_set_latency_callback(conn.handle, Some(latency_callback::<F>), user_ptr);
+        if code != 0 { Err(ErrorKind::UnknownErrorCode("set_process_callback() - latency", code))? }
         jack_on_info_shutdown(conn.handle, Some(info_shutdown_callback::<F>), user_ptr);
     }
     Ok(())
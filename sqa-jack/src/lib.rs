@@ -8,6 +8,7 @@ extern crate error_chain;
 extern crate lazy_static;
 
 static JACK_DEFAULT_AUDIO_TYPE: &'static [u8] = b"32 bit float mono audio\0";
+static JACK_DEFAULT_MIDI_TYPE: &'static [u8] = b"8 bit raw midi\0";
 pub mod errors;
 pub mod handler;
 pub mod port;
@@ -19,7 +20,7 @@ use std::ffi::{CString, CStr};
 use std::marker::PhantomData;
 use errors::{ErrorKind, ChainErr};
 pub use errors::JackResult;
-pub use handler::{JackCallbackContext, JackControl, JackHandler, JackLoggingHandler, set_logging_handler};
+pub use handler::{JackCallbackContext, JackControl, JackHandler, JackLoggingHandler, JackMidiOutputBuffer, JackMidiInputBuffer, JackMidiEvent, set_logging_handler};
 pub use port::JackPort;
 pub use jack_sys::*;
 
@@ -107,6 +108,32 @@ bitflags! {
         const OPEN_USE_EXACT_NAME = JackUseExactName,
     }
 }
+/// Which direction of latency a latency query or callback refers to.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum JackLatencyMode {
+    /// The time between data arriving at a physical input connector and it being
+    /// available to read from a port.
+    Capture,
+    /// The time between data being written to a port and it arriving at a physical
+    /// output connector.
+    Playback
+}
+impl JackLatencyMode {
+    fn from_raw(mode: jack_latency_callback_mode_t) -> Self {
+        if mode == JackCaptureLatency {
+            JackLatencyMode::Capture
+        }
+        else {
+            JackLatencyMode::Playback
+        }
+    }
+    fn to_raw(&self) -> jack_latency_callback_mode_t {
+        match *self {
+            JackLatencyMode::Capture => JackCaptureLatency,
+            JackLatencyMode::Playback => JackPlaybackLatency
+        }
+    }
+}
 /// Type argument for deactivated connections.
 pub struct Deactivated;
 /// Type argument for activated connections.
@@ -175,6 +202,27 @@ impl<T> JackConnection<T> {
             Ok(())
         }
     }
+    /// Request a complete recomputation of all port latencies.
+    ///
+    /// This can be called by a client that has just changed the internal latency of its
+    /// port using `JackPort::set_latency_range()` and wants to ensure that all signal
+    /// pathways in the graph are updated with respect to the values that will be
+    /// returned by `JackPort::get_latency_range()`.
+    ///
+    /// # Errors
+    ///
+    /// - `UnknownErrorCode`
+    pub fn recompute_total_latencies(&mut self) -> JackResult<()> {
+        let code = unsafe {
+            jack_recompute_total_latencies(self.handle)
+        };
+        if code != 0 {
+            Err(ErrorKind::UnknownErrorCode("recompute_total_latencies()", code))?
+        }
+        else {
+            Ok(())
+        }
+    }
     unsafe fn activate_or_deactivate<X>(self, activate: bool) -> Result<JackConnection<X>, (Self, errors::Error)> {
         let code = {
             if activate {
@@ -343,17 +391,28 @@ impl<T> JackConnection<T> {
     ///
     /// All ports have a type, which may be any non-NULL and non-zero length string,
     /// passed as an argument. Some port types are built into the JACK API, like
-    /// JACK_DEFAULT_AUDIO_TYPE or JACK_DEFAULT_MIDI_TYPE. *[By default, sqa-jack makes a
-    /// JACK_DEFAULT_AUDIO_TYPE port - this will be changeable in later releases.]*
+    /// JACK_DEFAULT_AUDIO_TYPE or JACK_DEFAULT_MIDI_TYPE. This function makes a
+    /// JACK_DEFAULT_AUDIO_TYPE port; use `register_midi_port()` for a MIDI one.
     ///
     /// # Errors
     ///
     /// - `NulError`: if any `&str` argument contains a NUL byte (`\0`).
     /// - `PortRegistrationFailed`: if port registration failed (TODO: why could this happen?)
     pub fn register_port(&mut self, name: &str, ty: JackPortFlags) -> JackResult<JackPort> {
+        self.register_port_of_type(name, ty, JACK_DEFAULT_AUDIO_TYPE)
+    }
+    /// Register a new JACK_DEFAULT_MIDI_TYPE port with the JACK server.
+    ///
+    /// See `register_port()` for details; the buffers of MIDI ports are accessed through
+    /// `JackCallbackContext::get_midi_output_buffer()` or `get_midi_input_buffer()`, not
+    /// `get_port_buffer()`.
+    pub fn register_midi_port(&mut self, name: &str, ty: JackPortFlags) -> JackResult<JackPort> {
+        self.register_port_of_type(name, ty, JACK_DEFAULT_MIDI_TYPE)
+    }
+    fn register_port_of_type(&mut self, name: &str, ty: JackPortFlags, port_type: &'static [u8]) -> JackResult<JackPort> {
         let ptr = unsafe {
             let name = str_to_cstr(name)?;
-            jack_port_register(self.handle, name.as_ptr(), JACK_DEFAULT_AUDIO_TYPE.as_ptr() as *const i8, ty.bits(), 0)
+            jack_port_register(self.handle, name.as_ptr(), port_type.as_ptr() as *const i8, ty.bits(), 0)
         };
         if ptr.is_null() {
             Err(ErrorKind::PortRegistrationFailed)?
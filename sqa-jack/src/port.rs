@@ -1,10 +1,11 @@
 use libc;
 use errors::{ErrorKind, JackResult};
-use super::{JackPortFlags, JackPortPtr, str_to_cstr};
+use super::{JackPortFlags, JackPortPtr, JackNFrames, JackLatencyMode, str_to_cstr};
 use std::borrow::Cow;
 use std::ffi::CStr;
 use jack_sys::{jack_port_set_name, jack_port_type, jack_port_flags,
-jack_port_short_name, jack_port_name};
+jack_port_short_name, jack_port_name, jack_port_get_latency_range,
+jack_port_set_latency_range, jack_latency_range_t};
 /// An object used for moving data of any type in or out of the client.
 ///
 /// Ports may be connected in various ways.
@@ -86,4 +87,28 @@ impl JackPort {
         let flags = unsafe { jack_port_flags(self.ptr) };
         JackPortFlags::from_bits_truncate(flags as u64)
     }
+    /// Get the minimum and maximum latency of the port, in frames, for a given mode.
+    ///
+    /// For an output port, `Playback` latency is the time between data being written to
+    /// the port and it reaching the outside world; for an input port, `Capture` latency is
+    /// the time between data arriving from the outside world and it being readable from
+    /// the port. The values are only updated by JACK when the graph changes, so callers
+    /// interested in changes should also implement `JackHandler::latency()`.
+    pub fn get_latency_range(&self, mode: JackLatencyMode) -> (JackNFrames, JackNFrames) {
+        let mut range = jack_latency_range_t { min: 0, max: 0 };
+        unsafe {
+            jack_port_get_latency_range(self.ptr, mode.to_raw(), &mut range);
+        }
+        (range.min, range.max)
+    }
+    /// Set the minimum and maximum latency of the port, in frames, for a given mode.
+    ///
+    /// This should only be called from within `JackHandler::latency()`, by clients that
+    /// introduce latency of their own between their input and output ports.
+    pub fn set_latency_range(&mut self, mode: JackLatencyMode, min: JackNFrames, max: JackNFrames) {
+        let mut range = jack_latency_range_t { min, max };
+        unsafe {
+            jack_port_set_latency_range(self.ptr, mode.to_raw(), &mut range);
+        }
+    }
 }
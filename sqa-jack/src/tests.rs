@@ -162,3 +162,71 @@ fn sawtooth() {
     run(atomic.clone()).unwrap();
     assert_eq!(atomic.load(Relaxed), false);
 }
+#[test]
+fn latency() {
+    let atomic = Arc::new(AtomicBool::new(false));
+    struct Special(Arc<AtomicBool>);
+    impl JackHandler for Special {
+        fn process(&mut self, _: &JackCallbackContext) -> JackControl {
+            JackControl::Continue
+        }
+        fn latency(&mut self, mode: JackLatencyMode) {
+            if mode == JackLatencyMode::Playback {
+                self.0.store(true, Relaxed)
+            }
+        }
+    }
+    fn run(atomic: Arc<AtomicBool>) -> JackResult<()> {
+        let mut conn = JackConnection::connect("Testing", None)?;
+        let out = conn.register_port("output", PORT_IS_OUTPUT)?;
+        conn.set_handler(Special(atomic))?;
+        let mut conn = match conn.activate() {
+            Ok(nc) => nc,
+            Err((_, err)) => return Err(err)
+        };
+        let ports = conn.get_ports(None, None, Some(PORT_IS_INPUT | PORT_IS_PHYSICAL))?;
+        if ports.len() >= 1 {
+            conn.connect_ports(&out, &ports[0])?;
+        }
+        thread::sleep(::std::time::Duration::new(2, 0));
+        let (min, max) = out.get_latency_range(JackLatencyMode::Playback);
+        assert!(min <= max);
+        Ok(())
+    }
+    run(atomic.clone()).unwrap();
+    assert_eq!(atomic.load(Relaxed), true);
+}
+#[test]
+fn midi_output() {
+    struct Midi {
+        out: JackPort,
+        written: Arc<AtomicBool>
+    }
+    impl JackHandler for Midi {
+        fn process(&mut self, ctx: &JackCallbackContext) -> JackControl {
+            let mut buf = ctx.get_midi_output_buffer(&self.out).unwrap();
+            if !self.written.load(Relaxed) {
+                buf.write(0, &[0x90, 60, 100]).unwrap();
+                buf.write(ctx.nframes() - 1, &[0x80, 60, 0]).unwrap();
+                assert!(buf.write(0, &[0x90, 62, 100]).is_err());
+                self.written.store(true, Relaxed);
+            }
+            JackControl::Continue
+        }
+    }
+    fn run(atomic: Arc<AtomicBool>) -> JackResult<()> {
+        let mut conn = JackConnection::connect("Testing", None)?;
+        let out = conn.register_midi_port("midi_out", PORT_IS_OUTPUT)?;
+        assert_eq!(out.get_type()?, "8 bit raw midi");
+        conn.set_handler(Midi { out: out, written: atomic })?;
+        let _conn = match conn.activate() {
+            Ok(nc) => nc,
+            Err((_, err)) => return Err(err)
+        };
+        thread::sleep(::std::time::Duration::new(1, 0));
+        Ok(())
+    }
+    let atomic = Arc::new(AtomicBool::new(false));
+    run(atomic.clone()).unwrap();
+    assert_eq!(atomic.load(Relaxed), true);
+}
@@ -1,5 +1,6 @@
 use gtk::prelude::*;
-use gtk::{Button, Widget, Image, Align};
+use gtk::{Button, Widget, Image, Align, CheckButton, Label};
+use glib::signal;
 use super::{ActionMessageInner, ActionInternalMessage, ActionMessage, OpaqueAction, UISender, ActionUI, UITemplate};
 use std::time::Duration;
 use widgets::{SliderBox, Faded, DurationEntry, DurationEntryMessage, SliderMessage, FadedSliderMessage};
@@ -14,7 +15,11 @@ use sqa_backend::actions::fade::FadeParams;
 #[derive(Clone)]
 pub enum FadeMessage {
     Slider(usize, FadedSliderMessage),
-    DurationModified(Duration)
+    DurationModified(Duration),
+    StopTargetToggled(bool),
+    RelativeToggled(bool),
+    AllActiveToggled(bool),
+    ClearTargets
 }
 impl SliderMessage<Faded> for FadeMessage {
     type Message = ActionMessage;
@@ -41,8 +46,19 @@ pub struct FadeUI {
     sel: Button,
     params: FadeParams,
     dur: DurationEntry,
+    stop_tgt: CheckButton,
+    stop_tgt_handler: u64,
+    relative: CheckButton,
+    relative_handler: u64,
+    all_active: CheckButton,
+    all_active_handler: u64,
+    add_tgt: Button,
+    clear_tgts: Button,
+    tgts_label: Label,
     sb: SliderBox<Faded, FadeMessage>,
     selecting: Rc<Cell<bool>>,
+    /// Whether the current selection adds to `targets`, rather than replacing `target`.
+    adding: Rc<Cell<bool>>,
     actionlist: HashMap<Uuid, OpaqueAction>,
     tx: UISender
 }
@@ -52,18 +68,38 @@ impl FadeUI {
         let sb = SliderBox::new(0, 0, &tx, uu);
         let params = Default::default();
         let dur = DurationEntry::new();
+        let stop_tgt = CheckButton::new_with_label("Stop target when done");
+        let relative = CheckButton::new_with_label("Levels are relative to current levels");
+        let all_active = CheckButton::new_with_label("Also fade all active audio");
         let sel = Button::new_with_label("[choose...]");
+        let add_tgt = Button::new_with_label("Add...");
+        let clear_tgts = Button::new_with_label("Clear");
+        let tgts_label = Label::new(Some("None"));
         let selecting = Rc::new(Cell::new(false));
+        let adding = Rc::new(Cell::new(false));
         let actionlist = HashMap::new();
         let patch = temp.add_tab("Levels &amp; Patch");
         let fade = temp.add_tab("Fade Properties");
         temp.get_tab("Basics").append_property("Target", &sel);
+        temp.get_tab("Basics").append_property("Other targets", &tgts_label);
+        temp.get_tab("Basics").append_property("", &add_tgt);
+        temp.get_tab("Basics").append_property("", &clear_tgts);
+        temp.get_tab("Basics").append_property("", &all_active);
         fade.append_property("Duration", &*dur);
+        fade.append_property("", &stop_tgt);
+        fade.append_property("", &relative);
         patch.container.pack_start(&sb.grid, false, true, 5);
         sel.set_halign(Align::Start);
         sel.set_always_show_image(true);
         sel.set_image(&Image::new_from_stock("gtk-find", 4));
-        let mut ctx = FadeUI { temp, params, sb, sel, tx, selecting, actionlist, dur };
+        add_tgt.set_halign(Align::Start);
+        clear_tgts.set_halign(Align::Start);
+        tgts_label.set_halign(Align::Start);
+        tgts_label.set_line_wrap(true);
+        let stop_tgt_handler = 0;
+        let relative_handler = 0;
+        let all_active_handler = 0;
+        let mut ctx = FadeUI { temp, params, sb, sel, tx, selecting, adding, actionlist, dur, stop_tgt, stop_tgt_handler, relative, relative_handler, all_active, all_active_handler, add_tgt, clear_tgts, tgts_label };
         ctx.bind();
         ctx
     }
@@ -71,9 +107,30 @@ impl FadeUI {
         self.temp.bind();
         let ref tx = self.tx;
         let ref selecting = self.selecting;
+        let ref adding = self.adding;
         let uu = self.temp.uu;
         self.dur.bind::<FadeMessage>(tx, uu);
-        self.sel.connect_clicked(clone!(tx, selecting; |slf| {
+        self.stop_tgt_handler = self.stop_tgt.connect_toggled(clone!(tx; |slf| {
+            tx.send_internal((uu, ActionMessageInner::Fade(FadeMessage::StopTargetToggled(slf.get_active()))));
+        }));
+        self.relative_handler = self.relative.connect_toggled(clone!(tx; |slf| {
+            tx.send_internal((uu, ActionMessageInner::Fade(FadeMessage::RelativeToggled(slf.get_active()))));
+        }));
+        self.all_active_handler = self.all_active.connect_toggled(clone!(tx; |slf| {
+            tx.send_internal((uu, ActionMessageInner::Fade(FadeMessage::AllActiveToggled(slf.get_active()))));
+        }));
+        self.sel.connect_clicked(clone!(tx, selecting, adding; |slf| {
+            if selecting.get() {
+                tx.send_internal(ActionInternalMessage::CancelSelection);
+            }
+            else {
+                slf.set_label("Choose an action above [click here to cancel]");
+                tx.send_internal(ActionInternalMessage::BeginSelection(uu));
+                selecting.set(true);
+                adding.set(false);
+            }
+        }));
+        self.add_tgt.connect_clicked(clone!(tx, selecting, adding; |slf| {
             if selecting.get() {
                 tx.send_internal(ActionInternalMessage::CancelSelection);
             }
@@ -81,8 +138,12 @@ impl FadeUI {
                 slf.set_label("Choose an action above [click here to cancel]");
                 tx.send_internal(ActionInternalMessage::BeginSelection(uu));
                 selecting.set(true);
+                adding.set(true);
             }
         }));
+        self.clear_tgts.connect_clicked(clone!(tx; |_| {
+            tx.send_internal((uu, ActionMessageInner::Fade(FadeMessage::ClearTargets)));
+        }));
     }
     fn on_new_parameters(&mut self, p: &FadeParams) {
         trace!("fade: new parameters {:?}", p);
@@ -98,7 +159,30 @@ impl FadeUI {
         else {
             self.sel.set_label("[choose...]");
         }
+        self.add_tgt.set_label("Add...");
+        if p.targets.len() == 0 {
+            self.tgts_label.set_text("None");
+        }
+        else {
+            let names = p.targets.iter()
+                .map(|uu| match self.actionlist.get(uu) {
+                    Some(opa) => opa.display_name(),
+                    None => format!("{}", uu)
+                })
+                .collect::<Vec<_>>();
+            self.tgts_label.set_text(&names.join(", "));
+        }
+        self.clear_tgts.set_sensitive(p.targets.len() > 0);
+        signal::signal_handler_block(&self.all_active, self.all_active_handler);
+        self.all_active.set_active(p.all_active);
+        signal::signal_handler_unblock(&self.all_active, self.all_active_handler);
         self.dur.set(p.dur);
+        signal::signal_handler_block(&self.stop_tgt, self.stop_tgt_handler);
+        self.stop_tgt.set_active(p.stop_target_when_done);
+        signal::signal_handler_unblock(&self.stop_tgt, self.stop_tgt_handler);
+        signal::signal_handler_block(&self.relative, self.relative_handler);
+        self.relative.set_active(p.relative);
+        signal::signal_handler_unblock(&self.relative, self.relative_handler);
         if p.fades.len() != self.sb.n_sliders() {
             self.sb.grid.destroy();
             self.sb = SliderBox::new(p.fades.len(), 0, &self.temp.tx, self.temp.uu);
@@ -145,6 +229,22 @@ impl ActionUI for FadeUI {
                     self.params.dur = dur;
                     trace!("dur cb: new dur {:?}", dur);
                     self.apply_changes("change fade duration");
+                },
+                StopTargetToggled(val) => {
+                    self.params.stop_target_when_done = val;
+                    self.apply_changes("change whether fade stops target");
+                },
+                RelativeToggled(val) => {
+                    self.params.relative = val;
+                    self.apply_changes("change whether fade is relative");
+                },
+                AllActiveToggled(val) => {
+                    self.params.all_active = val;
+                    self.apply_changes("change whether fade applies to all active audio");
+                },
+                ClearTargets => {
+                    self.params.targets.clear();
+                    self.apply_changes("clear other fade targets");
                 }
             }
         }
@@ -160,15 +260,25 @@ impl ActionUI for FadeUI {
     }
     fn on_selection_finished(&mut self, sel: Uuid) {
         trace!("selected {}", sel);
-        self.params.target = Some(sel);
-        self.apply_changes("change fade target");
+        if self.adding.get() {
+            if self.params.target != Some(sel) && !self.params.targets.contains(&sel) {
+                self.params.targets.push(sel);
+            }
+            self.apply_changes("add fade target");
+        }
+        else {
+            self.params.target = Some(sel);
+            self.apply_changes("change fade target");
+        }
         self.selecting.set(false);
+        self.adding.set(false);
     }
     fn on_selection_cancelled(&mut self) {
         trace!("selection cancelled");
         let p = self.params.clone();
         self.on_new_parameters(&p);
         self.selecting.set(false);
+        self.adding.set(false);
     }
     fn on_action_list(&mut self, l: &HashMap<Uuid, OpaqueAction>) {
         trace!("got new actionlist");
@@ -0,0 +1,38 @@
+use gtk::Widget;
+use super::{ActionMessageInner, OpaqueAction, UISender, ActionUI, UITemplate};
+use uuid::Uuid;
+
+/// UI for action types that don't have a dedicated editor yet.
+///
+/// This only provides the basics (name, pre-wait, playback controls & errors); the
+/// action's parameters have to be edited by other means.
+pub struct GenericUI {
+    temp: UITemplate
+}
+impl GenericUI {
+    pub fn new(uu: Uuid, tx: UISender) -> Self {
+        let temp = UITemplate::new(uu, tx);
+        let mut ctx = GenericUI { temp };
+        ctx.temp.bind();
+        ctx
+    }
+}
+impl ActionUI for GenericUI {
+    fn on_update(&mut self, p: &OpaqueAction) {
+        self.temp.on_update(p);
+    }
+    fn on_message(&mut self, _: ActionMessageInner) {
+    }
+    fn close_window(&mut self) {
+        self.temp.pwin.hide();
+    }
+    fn edit_separately(&mut self) {
+        self.temp.edit_separately();
+    }
+    fn get_container(&mut self) -> Option<Widget> {
+        self.temp.get_container()
+    }
+    fn change_cur_page(&mut self, cp: Option<u32>) {
+        self.temp.change_cur_page(cp)
+    }
+}
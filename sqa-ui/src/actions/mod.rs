@@ -23,10 +23,12 @@ use sqa_backend::waveform::WaveformReply;
 
 pub mod audio;
 pub mod fade;
+pub mod generic;
 pub mod template;
 pub use self::template::UITemplate;
 use self::audio::AudioUI;
 use self::fade::FadeUI;
+use self::generic::GenericUI;
 pub enum ActionInternalMessage {
     Create(&'static str),
     SelectionChanged,
@@ -330,7 +332,16 @@ impl ActionController {
             }
             let typ = match action.params {
                 ActionParameters::Audio(_) => "audio-x-generic",
-                ActionParameters::Fade(_) => "audio-volume-medium-symbolic"
+                ActionParameters::Fade(_) => "audio-volume-medium-symbolic",
+                ActionParameters::Group(_) => "folder",
+                ActionParameters::Control(_) => "media-playlist-consecutive-symbolic",
+                ActionParameters::Wait(_) => "alarm-symbolic",
+                ActionParameters::Osc(_) => "network-transmit-symbolic",
+                ActionParameters::Midi(_) => "audio-card-symbolic",
+                ActionParameters::Timecode(_) => "preferences-system-time-symbolic",
+                ActionParameters::Process(_) => "utilities-terminal-symbolic",
+                ActionParameters::Live(_) => "audio-input-microphone-symbolic",
+                ActionParameters::Record(_) => "media-record-symbolic"
             };
             use self::PlaybackState::*;
             let state = match action.state {
@@ -400,7 +411,25 @@ impl ActionController {
                 ActionParameters::Audio(..) =>
                     Box::new(AudioUI::new(data.uu, self.tx.as_ref().unwrap().clone())) as Box<ActionUI>,
                 ActionParameters::Fade(..) =>
-                    Box::new(FadeUI::new(data.uu, self.tx.as_ref().unwrap().clone())) as Box<ActionUI>
+                    Box::new(FadeUI::new(data.uu, self.tx.as_ref().unwrap().clone())) as Box<ActionUI>,
+                ActionParameters::Group(..) =>
+                    Box::new(GenericUI::new(data.uu, self.tx.as_ref().unwrap().clone())) as Box<ActionUI>,
+                ActionParameters::Control(..) =>
+                    Box::new(GenericUI::new(data.uu, self.tx.as_ref().unwrap().clone())) as Box<ActionUI>,
+                ActionParameters::Wait(..) =>
+                    Box::new(GenericUI::new(data.uu, self.tx.as_ref().unwrap().clone())) as Box<ActionUI>,
+                ActionParameters::Osc(..) =>
+                    Box::new(GenericUI::new(data.uu, self.tx.as_ref().unwrap().clone())) as Box<ActionUI>,
+                ActionParameters::Midi(..) =>
+                    Box::new(GenericUI::new(data.uu, self.tx.as_ref().unwrap().clone())) as Box<ActionUI>,
+                ActionParameters::Timecode(..) =>
+                    Box::new(GenericUI::new(data.uu, self.tx.as_ref().unwrap().clone())) as Box<ActionUI>,
+                ActionParameters::Process(..) =>
+                    Box::new(GenericUI::new(data.uu, self.tx.as_ref().unwrap().clone())) as Box<ActionUI>,
+                ActionParameters::Live(..) =>
+                    Box::new(GenericUI::new(data.uu, self.tx.as_ref().unwrap().clone())) as Box<ActionUI>,
+                ActionParameters::Record(..) =>
+                    Box::new(GenericUI::new(data.uu, self.tx.as_ref().unwrap().clone())) as Box<ActionUI>
             };
             aui.on_update(&data);
             aui.on_mixer(&self.mixer);